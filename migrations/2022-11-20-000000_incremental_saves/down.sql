@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX markovstates_b_seq;
+DROP INDEX exemaps_map_seq;
+DROP INDEX markovstates_a_seq_b_seq;
+DROP INDEX exemaps_seq_map_seq;
+DROP INDEX exes_seq;
+DROP INDEX maps_seq;
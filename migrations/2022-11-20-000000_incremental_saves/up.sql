@@ -0,0 +1,34 @@
+-- Older versions appended a full copy of the model on every save, and
+-- numbered maps and exes anew on every start, so the same seq may stand for
+-- different files in different copies. The latest copy of each file is kept,
+-- and then the latest file of each seq, since the rows of a later copy come
+-- after those of the copies before it.
+DELETE FROM maps WHERE id NOT IN (
+    SELECT MAX(id) FROM maps GROUP BY uri, offset, length
+);
+DELETE FROM maps WHERE id NOT IN (SELECT MAX(id) FROM maps GROUP BY seq);
+DELETE FROM exes WHERE id NOT IN (SELECT MAX(id) FROM exes GROUP BY uri);
+DELETE FROM exes WHERE id NOT IN (SELECT MAX(id) FROM exes GROUP BY seq);
+
+-- The rows that refer to the removed copies go with them, and the latest
+-- copy of the others is kept. Every Markov chain was also written twice, once
+-- from each of its exes.
+DELETE FROM exemaps
+WHERE seq NOT IN (SELECT seq FROM exes)
+    OR map_seq NOT IN (SELECT seq FROM maps)
+    OR id NOT IN (SELECT MAX(id) FROM exemaps GROUP BY seq, map_seq);
+DELETE FROM markovstates
+WHERE a_seq NOT IN (SELECT seq FROM exes)
+    OR b_seq NOT IN (SELECT seq FROM exes)
+    OR id NOT IN (
+        SELECT MAX(id) FROM markovstates GROUP BY a_seq, b_seq
+    );
+
+CREATE UNIQUE INDEX maps_seq ON maps (seq);
+CREATE UNIQUE INDEX exes_seq ON exes (seq);
+CREATE UNIQUE INDEX exemaps_seq_map_seq ON exemaps (seq, map_seq);
+CREATE UNIQUE INDEX markovstates_a_seq_b_seq ON markovstates (a_seq, b_seq);
+
+-- used when deleting the rows of removed maps and exes
+CREATE INDEX exemaps_map_seq ON exemaps (map_seq);
+CREATE INDEX markovstates_b_seq ON markovstates (b_seq);
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE exes DROP COLUMN mtime;
+ALTER TABLE exes DROP COLUMN size;
+ALTER TABLE exes DROP COLUMN ino;
+ALTER TABLE exes DROP COLUMN dev;
+
+ALTER TABLE maps DROP COLUMN mtime;
+ALTER TABLE maps DROP COLUMN size;
+ALTER TABLE maps DROP COLUMN ino;
+ALTER TABLE maps DROP COLUMN dev;
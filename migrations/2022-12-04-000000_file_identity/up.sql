@@ -0,0 +1,12 @@
+-- Identity of the file behind each map and exe, to tell when it has been
+-- replaced under the same path. Rows written by older versions have none,
+-- and get it on the next load.
+ALTER TABLE maps ADD COLUMN dev BIGINT;
+ALTER TABLE maps ADD COLUMN ino BIGINT;
+ALTER TABLE maps ADD COLUMN size BIGINT;
+ALTER TABLE maps ADD COLUMN mtime BIGINT;
+
+ALTER TABLE exes ADD COLUMN dev BIGINT;
+ALTER TABLE exes ADD COLUMN ino BIGINT;
+ALTER TABLE exes ADD COLUMN size BIGINT;
+ALTER TABLE exes ADD COLUMN mtime BIGINT;
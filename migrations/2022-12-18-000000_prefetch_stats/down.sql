@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX prefetchstats_day_exe_seq;
+DROP TABLE prefetchstats;
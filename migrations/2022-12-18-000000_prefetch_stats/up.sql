@@ -0,0 +1,12 @@
+-- How well prefetching worked for each exe, by day since the epoch. The
+-- counts of all exes together have an exe_seq of 0. See `stats.rs`.
+CREATE TABLE prefetchstats (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    day INTEGER NOT NULL,
+    exe_seq INTEGER NOT NULL,
+    hits INTEGER NOT NULL,
+    misses INTEGER NOT NULL,
+    waste INTEGER NOT NULL
+);
+
+CREATE UNIQUE INDEX prefetchstats_day_exe_seq ON prefetchstats (day, exe_seq);
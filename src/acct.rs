@@ -0,0 +1,402 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Short-lived processes from BSD process accounting.
+//!
+//! Processes that start and exit between two scans are never listed in
+//! `/proc`, so the model does not learn from them. When process accounting is
+//! turned on (see `accton(8)`), the kernel appends a record to the accounting
+//! file for every process that exits. These records are tailed, and the runs
+//! of known exes that no scan has seen are fed to the model.
+//!
+//! See `acct(5)` for the format of the version 3 records read here.
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+
+use crate::state::{Exe, State};
+
+/// Length of `struct acct_v3`.
+const RECORD_LEN: usize = 64;
+
+/// Format version of the records we understand. The high bit of the version
+/// byte tells the byte order, which is always the one of the running kernel.
+const ACCT_VERSION: u8 = 3;
+
+/// The process has forked but not executed any program.
+const AFORK: u8 = 0x01;
+
+/// Ticks per second of the elapsed time.
+const AHZ: f32 = 100.0;
+
+/// The command name is the executable name truncated to this length.
+const COMM_LEN: usize = 15;
+
+/// A process that has exited, as recorded by the kernel.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Record {
+    pub(crate) pid: u32,
+
+    /// Name of the last program executed, truncated to 15 bytes.
+    pub(crate) comm: OsString,
+
+    /// Time at which the process was created, in seconds since the epoch.
+    pub(crate) begin: u32,
+
+    /// Seconds the process has been running for.
+    pub(crate) elapsed: f32,
+
+    /// Whether the process has exited without executing any program, in
+    /// which case `comm` is the one of its parent.
+    pub(crate) forked: bool,
+}
+
+impl Record {
+    /// Parse a `struct acct_v3`. Records of other versions are skipped.
+    pub(crate) fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < RECORD_LEN || buf[1] & 0x7f != ACCT_VERSION {
+            return None;
+        }
+
+        let u32_at = |i: usize| {
+            u32::from_ne_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]])
+        };
+        let comm = &buf[48..RECORD_LEN];
+        let comm =
+            &comm[..comm.iter().position(|&c| c == 0).unwrap_or(comm.len())];
+
+        Some(Self {
+            pid: u32_at(16),
+            comm: OsStr::from_bytes(comm).to_owned(),
+            begin: u32_at(24),
+            elapsed: f32::from_bits(u32_at(28)) / AHZ,
+            forked: buf[0] & AFORK != 0,
+        })
+    }
+}
+
+/// Follows an accounting file, even when it is truncated or rotated.
+#[derive(Debug)]
+pub(crate) struct Tail {
+    path: PathBuf,
+    file: Option<File>,
+
+    /// Device and inode of the open file.
+    id: (u64, u64),
+
+    /// Offset of the first record not read yet.
+    offset: u64,
+}
+
+impl Tail {
+    /// Start following the file at `path`. The records already there are
+    /// skipped, as they are too old to tell anything about the model.
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: None,
+            id: (0, 0),
+            offset: 0,
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the records appended since the last call.
+    pub(crate) fn read(&mut self) -> Result<Vec<Record>> {
+        let metadata = match self.path.metadata() {
+            Ok(metadata) => metadata,
+            // being rotated; the new file is picked up on next call
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(vec![])
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to read {:?}", self.path)
+                })
+            }
+        };
+
+        let mut buf = vec![];
+        let id = (metadata.dev(), metadata.ino());
+        if self.file.is_none() || self.id != id {
+            let file = File::open(&self.path)
+                .with_context(|| format!("Failed to open {:?}", self.path))?;
+            let offset = match self.file.take() {
+                // drain what was appended to the old file before it was
+                // rotated, all of the new one is yet to be read
+                Some(old) => {
+                    self.read_from(old, &mut buf)?;
+                    0
+                }
+                None => metadata.len() / RECORD_LEN as u64 * RECORD_LEN as u64,
+            };
+            self.file = Some(file);
+            self.id = id;
+            self.offset = offset;
+        } else if metadata.len() < self.offset {
+            log::debug!("{:?} has been truncated", self.path);
+            self.offset = 0;
+        }
+
+        if let Some(file) = self.file.take() {
+            let file = self.read_from(file, &mut buf)?;
+            self.file = Some(file);
+        }
+
+        Ok(buf
+            .chunks_exact(RECORD_LEN)
+            .filter_map(Record::parse)
+            .collect())
+    }
+
+    /// Append the whole records of `file` past the offset to `buf`.
+    fn read_from(
+        &mut self,
+        mut file: File,
+        buf: &mut Vec<u8>,
+    ) -> Result<File> {
+        let start = buf.len();
+        file.seek(SeekFrom::Start(self.offset))?;
+        file.read_to_end(buf)
+            .with_context(|| format!("Failed to read {:?}", self.path))?;
+
+        // a record may be half written
+        let whole = (buf.len() - start) / RECORD_LEN * RECORD_LEN;
+        buf.truncate(start + whole);
+        self.offset += whole as u64;
+        Ok(file)
+    }
+}
+
+/// The exe whose name shows in records as `comm`.
+fn comm_of(exe: &Exe) -> &[u8] {
+    let name = exe.path.file_name().unwrap_or_default().as_bytes();
+    &name[..name.len().min(COMM_LEN)]
+}
+
+/// Feed the runs in `records` that no scan has seen to the model. `now` is
+/// the current time in seconds since the epoch.
+///
+/// Records are matched to exes by name, so runs of exes sharing the same
+/// truncated name are ignored, and so are the exes never seen running.
+pub(crate) fn feed(state: &State, records: &[Record], now: i64) {
+    let mut exes = HashMap::new();
+    for exe in state.exes.values() {
+        exes.entry(comm_of(&exe.borrow()).to_owned())
+            .and_modify(|e| *e = None)
+            .or_insert_with(|| Some(Rc::clone(exe)));
+    }
+
+    for record in records.iter().filter(|r| !r.forked) {
+        let exe = match exes.get(record.comm.as_bytes()) {
+            Some(Some(exe)) => exe,
+            _ => continue,
+        };
+
+        // map the run onto the model time, which follows the wall clock
+        let start = state.time - (now - record.begin as i64) as i32;
+        let end = start + record.elapsed.round() as i32;
+
+        // runs that overlap a scan, or another run of the exe, are already
+        // accounted for
+        if start <= state.last_running_timestamp
+            || exe.borrow().is_running(state)
+        {
+            continue;
+        }
+
+        log::debug!(
+            "{:?} ran for {}s between scans",
+            record.comm,
+            end - start
+        );
+        state.short_lived_callback(exe, start, end);
+    }
+}
+
+/// Read the records appended to the accounting file at `path`, and feed them
+/// to the model. `tail` follows the file from one call to the next.
+///
+/// Empty `path` means accounting is not read.
+pub(crate) fn account(
+    tail: &mut Option<Tail>,
+    path: &Path,
+    state: &State,
+) -> Result<()> {
+    if path == Path::new("") {
+        *tail = None;
+        return Ok(());
+    }
+
+    let tail = match tail {
+        Some(tail) if tail.path() == path => tail,
+        _ => tail.insert(Tail::new(path)),
+    };
+    let records = tail.read()?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64;
+    feed(state, &records, now);
+    Ok(())
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::*;
+    use crate::{
+        common::{RcCell, RcCellNew},
+        proc::FakeSource,
+        rules::PathRules,
+        spy,
+    };
+
+    /// `make` run by uid 1000 as pid 4242, created at 1700000000 and running
+    /// for 2.5 seconds, as written by a little-endian kernel.
+    const MAKE: [u8; RECORD_LEN] = [
+        0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, // flag, version, tty
+        0xe8, 0x03, 0x00, 0x00, 0xe8, 0x03, 0x00, 0x00, // uid, gid
+        0x92, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // pid, ppid
+        0x00, 0xf1, 0x53, 0x65, 0x00, 0x00, 0x7a, 0x43, // btime, etime
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // utime...
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ...swaps
+        b'm', b'a', b'k', b'e', 0x00, 0x00, 0x00, 0x00, // comm
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn record(comm: &str, begin: u32, elapsed: f32) -> [u8; RECORD_LEN] {
+        let mut buf = MAKE;
+        buf[24..28].copy_from_slice(&begin.to_ne_bytes());
+        buf[28..32].copy_from_slice(&(elapsed * AHZ).to_ne_bytes());
+        buf[48..RECORD_LEN].fill(0);
+        buf[48..48 + comm.len()].copy_from_slice(comm.as_bytes());
+        buf
+    }
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn records_are_parsed() {
+        assert_eq!(
+            Record::parse(&MAKE),
+            Some(Record {
+                pid: 4242,
+                comm: "make".into(),
+                begin: 1_700_000_000,
+                elapsed: 2.5,
+                forked: false,
+            })
+        );
+
+        let mut forked = MAKE;
+        forked[0] |= AFORK;
+        assert!(Record::parse(&forked).unwrap().forked);
+
+        // version 2 records have another layout
+        let mut v2 = MAKE;
+        v2[1] = 2;
+        assert_eq!(Record::parse(&v2), None);
+    }
+
+    #[test]
+    fn new_records_are_tailed() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join("pacct");
+        fs::write(&path, record("old", 1, 1.0)).unwrap();
+
+        // what is there before we start is skipped
+        let mut tail = Tail::new(&path);
+        assert!(tail.read().unwrap().is_empty());
+
+        // a half written record is read once complete
+        let mut file =
+            fs::OpenOptions::new().append(true).open(&path).unwrap();
+        let new = record("new", 2, 1.0);
+        file.write_all(&new[..10]).unwrap();
+        assert!(tail.read().unwrap().is_empty());
+        file.write_all(&new[10..]).unwrap();
+        let records = tail.read().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].comm, "new");
+
+        // once rotated, the new file is read from its start
+        file.write_all(&record("late", 3, 1.0)).unwrap();
+        fs::rename(&path, dir.join("pacct.0")).unwrap();
+        fs::write(&path, record("rotated", 4, 1.0)).unwrap();
+        let records = tail.read().unwrap();
+        let comms = records.iter().map(|r| r.comm.clone()).collect::<Vec<_>>();
+        assert_eq!(comms, ["late", "rotated"]);
+
+        // and from its start again once truncated
+        fs::write(&path, b"").unwrap();
+        assert!(tail.read().unwrap().is_empty());
+        fs::write(&path, record("truncated", 5, 1.0)).unwrap();
+        assert_eq!(tail.read().unwrap()[0].comm, "truncated");
+    }
+
+    #[test]
+    fn short_runs_are_learnt() {
+        let rules = PathRules::from_prefixes(&["/usr/"]);
+        let mut source = FakeSource::default();
+        source.spawn(10, "/usr/bin/a", &[("/usr/lib/liba.so", 0, 8192)]);
+        source.spawn(11, "/usr/bin/b", &[("/usr/lib/libb.so", 0, 8192)]);
+
+        let state = RcCell::new_cell(State::default());
+        let run_cycle = |source: &FakeSource| {
+            spy::scan(&mut state.borrow_mut(), &rules.exes, source).unwrap();
+            state.borrow_mut().time += 10;
+            spy::update_model(Rc::clone(&state), &rules, 0, 20, source)
+                .unwrap();
+            state.borrow_mut().time += 10;
+        };
+        run_cycle(&source);
+        source.kill(11);
+        run_cycle(&source);
+
+        // last scan was 20 seconds ago, at time 20
+        let now = 1_700_000_000;
+        let state = state.borrow();
+        let records = [
+            // `b` ran for 2 seconds since
+            record("b", now - 10, 2.0),
+            // `b` was already running at the scan
+            record("b", now - 25, 10.0),
+            // `a` is running anyway
+            record("a", now - 5, 1.0),
+            record("unknown", now - 5, 1.0),
+        ];
+        let records = records
+            .iter()
+            .filter_map(|r| Record::parse(r))
+            .collect::<Vec<_>>();
+        feed(&state, &records, now as i64);
+
+        let a = state.exes[Path::new("/usr/bin/a")].borrow();
+        let b = state.exes[Path::new("/usr/bin/b")].borrow();
+        assert_eq!(a.time, 30);
+        assert_eq!(b.time, 10 + 2);
+
+        // `a` ran alone, then with `b`, and alone again
+        let markov = a.markovs.iter().next().unwrap().borrow();
+        assert_eq!(markov.state, 1);
+        assert_eq!(markov.time, 10 + 2);
+        assert_eq!(markov.weight[1][3], 1);
+        assert_eq!(markov.weight[3][1], 2);
+    }
+}
+// 1}}} //
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
 
 use structopt::StructOpt;
 use terminal_size::{terminal_size, Width};
@@ -54,12 +54,26 @@ pub(crate) struct Opt {
     )]
     pub(crate) logfile: PathBuf,
 
+    /// Set control socket to listen on. Empty string means no control
+    /// socket.
+    #[structopt(
+        long,
+        default_value = "/run/rustload.sock",
+        parse(from_os_str)
+    )]
+    pub(crate) socket: PathBuf,
+
     /// Run in foreground, do not daemonize.
     ///
     /// This option conflicts with `--debug`.
     #[structopt(short, long)]
     pub(crate) foreground: bool,
 
+    /// Only record what would be prefetched, without touching the page
+    /// cache. Same as setting `dryrun` in the configuration file.
+    #[structopt(long)]
+    pub(crate) dry_run: bool,
+
     /// Nice level.
     #[structopt(short, long, default_value = "15")]
     pub(crate) _nice: i32,
@@ -94,6 +108,197 @@ pub(crate) struct Opt {
         conflicts_with = "logfile"
     )]
     pub(crate) debug: bool,
+
+    #[structopt(subcommand)]
+    pub(crate) cmd: Option<Command>,
+}
+
+/// Alternative modes of operation. The daemon is started if none is given.
+#[derive(Debug, StructOpt)]
+pub(crate) enum Command {
+    /// Print the contents of the state file without starting the daemon.
+    ///
+    /// The state file is opened read-only and is never migrated.
+    Inspect {
+        #[structopt(subcommand)]
+        view: View,
+    },
+
+    /// Print which rules of the configuration decide for a path, whether
+    /// it is an exe or a map, without starting the daemon.
+    ExplainPath {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+}
+
+/// What part of the state file to print.
+#[derive(Debug, StructOpt)]
+pub(crate) enum View {
+    /// Print the known exes.
+    Exes {
+        /// Sort by the given key.
+        #[structopt(
+            long,
+            default_value = "time",
+            possible_values = ExeSort::NAMES
+        )]
+        sort: ExeSort,
+
+        #[structopt(flatten)]
+        filter: Filter,
+    },
+
+    /// Print the known maps.
+    Maps {
+        /// Only print the maps used by the given exe.
+        #[structopt(long, parse(from_os_str))]
+        exe: Option<PathBuf>,
+
+        /// Sort by the given key.
+        #[structopt(
+            long,
+            default_value = "length",
+            possible_values = MapSort::NAMES
+        )]
+        sort: MapSort,
+
+        #[structopt(flatten)]
+        filter: Filter,
+    },
+
+    /// Print the maps used by each exe along with their probabilities.
+    Exemaps {
+        /// Only print the maps used by the given exe.
+        #[structopt(long, parse(from_os_str))]
+        exe: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        filter: Filter,
+    },
+
+    /// Print the exes that were deemed too small to be tracked.
+    BadExes {
+        #[structopt(flatten)]
+        filter: Filter,
+    },
+
+    /// Print the Markov chains along with their decoded matrices.
+    Markovs {
+        /// Only print the chains involving the given exe.
+        #[structopt(long, parse(from_os_str))]
+        exe: Option<PathBuf>,
+
+        /// Sort by the given key. `correlation` sorts by the absolute value
+        /// of the correlation coefficient, strongest pairs first.
+        #[structopt(
+            long,
+            default_value = "correlation",
+            possible_values = MarkovSort::NAMES
+        )]
+        sort: MarkovSort,
+
+        #[structopt(flatten)]
+        filter: Filter,
+    },
+}
+
+/// Sort keys of [`View::Exes`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExeSort {
+    /// Longest running first.
+    Time,
+
+    /// Largest first.
+    Size,
+
+    /// Most maps first.
+    Maps,
+
+    /// By path.
+    Path,
+}
+
+impl ExeSort {
+    const NAMES: &'static [&'static str] = &["time", "size", "maps", "path"];
+}
+
+impl FromStr for ExeSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "time" => Self::Time,
+            "size" => Self::Size,
+            "maps" => Self::Maps,
+            "path" => Self::Path,
+            _ => return Err(format!("Unknown sort key {:?}", s)),
+        })
+    }
+}
+
+/// Sort keys of [`View::Maps`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MapSort {
+    /// Longest first.
+    Length,
+
+    /// By path.
+    Path,
+}
+
+impl MapSort {
+    const NAMES: &'static [&'static str] = &["length", "path"];
+}
+
+impl FromStr for MapSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "length" => Self::Length,
+            "path" => Self::Path,
+            _ => return Err(format!("Unknown sort key {:?}", s)),
+        })
+    }
+}
+
+/// Sort keys of [`View::Markovs`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MarkovSort {
+    /// Strongest correlation first, either positive or negative.
+    Correlation,
+
+    /// Longest running together first.
+    Time,
+}
+
+impl MarkovSort {
+    const NAMES: &'static [&'static str] = &["correlation", "time"];
+}
+
+impl FromStr for MarkovSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "correlation" => Self::Correlation,
+            "time" => Self::Time,
+            _ => return Err(format!("Unknown sort key {:?}", s)),
+        })
+    }
+}
+
+/// Options shared by every [`View`].
+#[derive(Debug, StructOpt)]
+pub(crate) struct Filter {
+    /// Only print entries whose path contains the given string.
+    #[structopt(long)]
+    pub(crate) filter: Option<String>,
+
+    /// Print at most this many entries. 0 prints all of them.
+    #[structopt(short = "n", long, default_value = "0")]
+    pub(crate) count: usize,
 }
 
 impl Opt {
@@ -105,3 +310,38 @@ impl Opt {
         opt
     }
 }
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(args: &[&str]) -> Result<View, structopt::clap::Error> {
+        let args = ["rustload", "inspect"].iter().chain(args);
+        match Opt::from_iter_safe(args)?.cmd {
+            Some(Command::Inspect { view }) => Ok(view),
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sort_keys_are_parsed() {
+        match view(&["exes"]).unwrap() {
+            View::Exes { sort, .. } => assert_eq!(sort, ExeSort::Time),
+            other => panic!("unexpected view {:?}", other),
+        }
+        match view(&["maps", "--sort", "path"]).unwrap() {
+            View::Maps { sort, .. } => assert_eq!(sort, MapSort::Path),
+            other => panic!("unexpected view {:?}", other),
+        }
+        match view(&["markovs", "--sort", "time"]).unwrap() {
+            View::Markovs { sort, .. } => assert_eq!(sort, MarkovSort::Time),
+            other => panic!("unexpected view {:?}", other),
+        }
+        // the keys of one view are not accepted by another
+        assert!(view(&["maps", "--sort", "time"]).is_err());
+        assert_eq!("size".parse(), Ok(ExeSort::Size));
+        assert!("Size".parse::<ExeSort>().is_err());
+    }
+}
+// 1}}} //
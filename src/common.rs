@@ -1,11 +1,11 @@
 //! This module holds items common to everyone.
 
 use anyhow::Result;
-use derive_more::Deref;
 use std::cell::RefCell;
 use std::rc::{Rc, Weak};
 use std::{
     fmt::Display,
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
 };
 
@@ -15,30 +15,6 @@ pub(crate) type RcCell<T> = Rc<RefCell<T>>;
 /// A shorthand way to write `Weak<RefCell<T>>`.
 pub(crate) type WeakCell<T> = Weak<RefCell<T>>;
 
-/// A custom [`RcCell`] with custom drop function.
-#[derive(Deref, Derivative)]
-#[derivative(Debug = "transparent")]
-pub(crate) struct DropperCell<T, F: Fn(&RcCell<T>) = fn(&RcCell<T>)>(
-    Rc<RefCell<T>>,
-    #[deref(ignore)]
-    #[derivative(Debug = "ignore")]
-    Option<F>,
-);
-
-impl<T, F: Fn(&RcCell<T>)> DropperCell<T, F> {
-    pub fn new(value: T, dropper: Option<F>) -> Self {
-        Self(Rc::new(RefCell::new(value)), dropper)
-    }
-}
-
-impl<T, F: Fn(&RcCell<T>)> Drop for DropperCell<T, F> {
-    fn drop(&mut self) {
-        if let Some(func) = &self.1 {
-            func(&self.0)
-        }
-    }
-}
-
 /// Adds a `.new(...)` to [`RcCell<T>`] type.
 pub(crate) trait RcCellNew<T> {
     /// Create a [`RefCell<T>`] enclosed in a [`Rc<T>`].
@@ -112,3 +88,69 @@ impl<T, U: Display> LogResult<T, U> for Result<T, U> {
 pub(crate) const fn kb(v: u64) -> u64 {
     v / 1024
 }
+
+/// Identity of a file on disk. A file replaced under the same path, like a
+/// library upgraded by the package manager, has a different identity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct FileId {
+    /// Device the file lives on.
+    pub(crate) dev: u64,
+
+    /// Inode number of the file.
+    pub(crate) ino: u64,
+
+    /// Size of the file in bytes.
+    pub(crate) size: u64,
+
+    /// Last modification time, in seconds since the epoch.
+    pub(crate) mtime: i64,
+}
+
+impl FileId {
+    /// Returns the identity of the regular file at `path`, or [`None`] if
+    /// there is no such file.
+    pub(crate) fn of(path: impl AsRef<Path>) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        if !meta.is_file() {
+            return None;
+        }
+        Some(Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: meta.mtime(),
+        })
+    }
+
+    /// Builds the identity from the nullable database columns. Rows written
+    /// before identities were recorded have none.
+    pub(crate) fn from_columns(
+        dev: Option<i64>,
+        ino: Option<i64>,
+        size: Option<i64>,
+        mtime: Option<i64>,
+    ) -> Option<Self> {
+        Some(Self {
+            dev: dev? as u64,
+            ino: ino? as u64,
+            size: size? as u64,
+            mtime: mtime?,
+        })
+    }
+
+    /// Splits the identity into database columns. See
+    /// [`Self::from_columns`].
+    pub(crate) fn to_columns(
+        this: Option<Self>,
+    ) -> (Option<i64>, Option<i64>, Option<i64>, Option<i64>) {
+        match this {
+            Some(id) => (
+                Some(id.dev as i64),
+                Some(id.ino as i64),
+                Some(id.size as i64),
+                Some(id.mtime),
+            ),
+            None => (None, None, None, None),
+        }
+    }
+}
@@ -1,29 +1,114 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use confy::load_path;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::{convert::TryFrom, path::Path};
 
-use crate::model::{Model, System};
+use crate::{
+    model::{App, Model, PrefetchBackend, SortStrategy, System},
+    rules::PathRules,
+};
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub(crate) struct Config {
     pub(crate) model: Model,
     pub(crate) system: System,
+
+    /// Settings overridden for some apps.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) apps: Vec<App>,
+
+    /// The path rules of [`System`], compiled once loaded.
+    #[serde(skip)]
+    pub(crate) rules: PathRules,
 }
 
 pub(crate) fn load_config(path: impl AsRef<Path>) -> Result<Config> {
     let path = path.as_ref();
 
-    if path == Path::new("") {
+    let mut conf: Config = if path == Path::new("") {
         log::info!("No config file provided. Using default params.");
-        return Ok(Config::default());
+        Config::default()
+    } else {
+        if !path.exists() {
+            log::info!(
+                "File {:?} does not exist. Will try to create a new file.",
+                path
+            );
+        }
+        load_path(path)?
+    };
+
+    conf.rules = PathRules::compile(&conf.system, &conf.apps)?;
+    SortStrategy::try_from(conf.system.sortstrategy)
+        .context("Invalid sortstrategy")?;
+    PrefetchBackend::try_from(conf.system.prefetchbackend)
+        .context("Invalid prefetchbackend")?;
+    Ok(conf)
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_choices_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rustload.conf");
+
+        let mut conf = Config::default();
+        confy::store_path(&path, &conf).unwrap();
+        load_config(&path).unwrap();
+
+        conf.system.prefetchbackend = 42;
+        confy::store_path(&path, &conf).unwrap();
+        assert!(load_config(&path).is_err());
+
+        conf.system.prefetchbackend = PrefetchBackend::Readahead as u8;
+        conf.system.sortstrategy = 42;
+        confy::store_path(&path, &conf).unwrap();
+        assert!(load_config(&path).is_err());
     }
 
-    if !path.exists() {
-        log::info!(
-            "File {:?} does not exist. Will try to create a new file.",
-            path
-        );
+    #[test]
+    fn configs_of_older_versions_are_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rustload.conf");
+        std::fs::write(
+            &path,
+            r#"[model]
+cycle = 20
+usecorrelation = true
+minsize = 2000000
+memtotal = -10
+memfree = 50
+memcached = 0
+
+[system]
+doscan = true
+dopredict = true
+autosave = 3600
+mapprefix = ["/opt", "!/usr/sbin/", "!/usr/local/sbin/", "/usr/", "!/"]
+exeprefix = ["/opt", "!/usr/sbin/", "!/usr/local/sbin/", "/usr/", "!/"]
+processes = 30
+sortstrategy = 3
+"#,
+        )
+        .unwrap();
+
+        let conf = load_config(&path).unwrap();
+        let system = System::default();
+        assert_eq!(conf.system.sortstrategy, SortStrategy::Block as u8);
+        assert_eq!(conf.system.prefetchbackend, system.prefetchbackend);
+        assert_eq!(conf.system.dryrunfile, system.dryrunfile);
+        assert_eq!(conf.system.procevents, system.procevents);
+        assert_eq!(conf.system.acctfile, system.acctfile);
+        assert_eq!(conf.system.procfsroot, system.procfsroot);
+        assert_eq!(conf.system.sysfsroot, system.sysfsroot);
+        assert_eq!(conf.system.maprules, system.maprules);
+        assert_eq!(conf.system.pinned, system.pinned);
+        assert_eq!(conf.system.prefetchrate, system.prefetchrate);
+        assert!(conf.apps.is_empty());
     }
-    Ok(load_path(path)?)
 }
+// 1}}} //
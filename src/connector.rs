@@ -0,0 +1,475 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Event-driven process detection through the kernel's process connector.
+//!
+//! Instead of listing `/proc` on every scan, the kernel is asked to report
+//! every `fork`, `exec` and `exit` over a `NETLINK_CONNECTOR` socket. The
+//! running processes are tracked from these events, and exes that start are
+//! fed to the model right away, so that short-lived processes are not missed.
+//!
+//! See `linux/cn_proc.h` for the wire format.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    convert::TryInto,
+    io, mem,
+    os::unix::io::{AsRawFd, RawFd},
+    path::PathBuf,
+    rc::Rc,
+};
+
+use anyhow::{Context, Result};
+use calloop::{generic::Generic, Interest, LoopHandle, Mode, PostAction};
+use log::Level;
+
+use crate::{
+    common::LogResult,
+    event::SharedData,
+    proc::{MemInfo, ProcMap, ProcessSource, Procfs},
+    spy,
+};
+
+/// Multicast group and id of the process connector.
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+
+/// Operation asking the kernel to start sending us process events.
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+
+/// Types of the process events we care about.
+const PROC_EVENT_FORK: u32 = 0x0000_0001;
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+/// Length of `struct nlmsghdr`.
+const NLMSG_HDRLEN: usize = 16;
+
+/// Length of `struct cn_msg`, without its payload.
+const CN_MSG_LEN: usize = 20;
+
+/// Offset of `event_data` in `struct proc_event`.
+const EVENT_DATA_OFFSET: usize = 16;
+
+/// A process event reported by the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ProcEvent {
+    /// The process `parent` has forked the process `child`, which runs the
+    /// same program until it executes another one.
+    Fork {
+        parent: libc::pid_t,
+        child: libc::pid_t,
+    },
+
+    /// The process `pid` has executed a new program.
+    Exec(libc::pid_t),
+
+    /// The process `pid` has exited.
+    Exit(libc::pid_t),
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset + 2)?;
+    Some(u16::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+/// Parses a single `struct proc_event`. Events of threads other than the
+/// main one are ignored, as are the event types we do not care about.
+fn parse_proc_event(event: &[u8]) -> Option<ProcEvent> {
+    let what = read_u32(event, 0)?;
+    let field = |i: usize| {
+        read_u32(event, EVENT_DATA_OFFSET + 4 * i).map(|v| v as libc::pid_t)
+    };
+
+    // a fork reports the parent first, then the child
+    if what == PROC_EVENT_FORK {
+        let (child, tgid) = (field(2)?, field(3)?);
+        // a new thread is not a new process
+        if child != tgid {
+            return None;
+        }
+        return Some(ProcEvent::Fork {
+            parent: field(1)?,
+            child,
+        });
+    }
+
+    let (pid, tgid) = (field(0)?, field(1)?);
+    if pid != tgid {
+        return None;
+    }
+    match what {
+        PROC_EVENT_EXEC => Some(ProcEvent::Exec(pid)),
+        PROC_EVENT_EXIT => Some(ProcEvent::Exit(pid)),
+        _ => None,
+    }
+}
+
+/// Parses the process events out of a datagram read from the connector. A
+/// datagram may carry several netlink messages; malformed ones are skipped.
+pub(crate) fn parse(buf: &[u8]) -> Vec<ProcEvent> {
+    let mut events = vec![];
+    let mut offset = 0;
+
+    while let Some(len) = read_u32(buf, offset) {
+        let len = len as usize;
+        if len < NLMSG_HDRLEN || offset + len > buf.len() {
+            break;
+        }
+        let msg = &buf[offset + NLMSG_HDRLEN..offset + len];
+
+        let idx = read_u32(msg, 0);
+        let val = read_u32(msg, 4);
+        let data_len = read_u16(msg, 16).unwrap_or(0) as usize;
+        if idx == Some(CN_IDX_PROC) && val == Some(CN_VAL_PROC) {
+            if let Some(event) = msg
+                .get(CN_MSG_LEN..CN_MSG_LEN + data_len)
+                .and_then(parse_proc_event)
+            {
+                events.push(event);
+            }
+        }
+
+        // messages are aligned to 4 bytes
+        offset += (len + 3) & !3;
+    }
+
+    events
+}
+
+/// The running processes, as tracked from the process events.
+#[derive(Debug, Default)]
+pub(crate) struct Tracker {
+    procs: BTreeMap<libc::pid_t, PathBuf>,
+}
+
+impl Tracker {
+    /// Replaces the tracked processes with the ones listed by `source`. This
+    /// is needed at startup and whenever events have been lost.
+    pub(crate) fn sync(
+        &mut self,
+        source: &(impl ProcessSource + ?Sized),
+    ) -> Result<()> {
+        self.procs = source.processes()?.into_iter().collect();
+        Ok(())
+    }
+
+    /// Applies `event` to the tracked processes. The exe of a process that
+    /// executed a program is read from `source` and returned. A forked
+    /// process runs the exe of its parent.
+    pub(crate) fn apply(
+        &mut self,
+        event: ProcEvent,
+        source: &(impl ProcessSource + ?Sized),
+    ) -> Option<PathBuf> {
+        match event {
+            ProcEvent::Fork { parent, child } => {
+                let exe = self.procs.get(&parent)?.clone();
+                self.procs.insert(child, exe);
+                None
+            }
+            ProcEvent::Exec(pid) => {
+                // the process may be gone already
+                let exe = source.exe(pid).ok()?;
+                self.procs.insert(pid, exe.clone());
+                Some(exe)
+            }
+            ProcEvent::Exit(pid) => {
+                self.procs.remove(&pid);
+                None
+            }
+        }
+    }
+}
+
+/// [`ProcessSource`] that lists the processes tracked from the events, and
+/// reads everything else from `inner`.
+#[derive(Debug)]
+pub(crate) struct EventSource<S> {
+    pub(crate) tracker: Rc<RefCell<Tracker>>,
+    pub(crate) inner: S,
+}
+
+impl<S: ProcessSource> ProcessSource for EventSource<S> {
+    fn processes(&self) -> Result<Vec<(libc::pid_t, PathBuf)>> {
+        Ok(self
+            .tracker
+            .borrow()
+            .procs
+            .iter()
+            .map(|(pid, exe)| (*pid, exe.clone()))
+            .collect())
+    }
+
+    fn exe(&self, pid: libc::pid_t) -> Result<PathBuf> {
+        self.inner.exe(pid)
+    }
+
+    fn maps(&self, pid: libc::pid_t) -> Result<Vec<ProcMap>> {
+        self.inner.maps(pid)
+    }
+
+    fn meminfo(&self) -> Result<MemInfo> {
+        self.inner.meminfo()
+    }
+}
+
+/// A netlink socket subscribed to the process connector.
+#[derive(Debug)]
+struct Connector {
+    fd: RawFd,
+    tracker: Rc<RefCell<Tracker>>,
+
+    /// Where the tracker is resynced from when events are lost.
+    procfs: Procfs,
+}
+
+impl AsRawFd for Connector {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Connector {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl Connector {
+    /// Opens the socket and subscribes to the process events.
+    fn open(
+        tracker: Rc<RefCell<Tracker>>,
+        procfs: Procfs,
+    ) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_CONNECTOR,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // closes the socket if anything below fails
+        let this = Self {
+            fd,
+            tracker,
+            procfs,
+        };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_pid = std::process::id();
+        addr.nl_groups = CN_IDX_PROC;
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of_val(&addr) as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let msg = subscribe_message();
+        let ret = unsafe {
+            libc::send(fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(this)
+    }
+
+    /// Reads every pending datagram and feeds the events to the model.
+    fn serve(&mut self, shared: &mut SharedData) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let read = unsafe {
+                libc::recv(
+                    self.fd,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if read < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EAGAIN) => return Ok(()),
+                    Some(libc::EINTR) => continue,
+                    // the kernel dropped events as we were too slow
+                    Some(libc::ENOBUFS) => {
+                        log::warn!("Lost process events, resyncing.");
+                        self.tracker
+                            .borrow_mut()
+                            .sync(&self.procfs)
+                            .log_on_err(Level::Warn, "Failed to resync")
+                            .ok();
+                        continue;
+                    }
+                    _ => return Err(e),
+                }
+            }
+
+            let scanning = shared.conf.system.doscan && !shared.scan_paused;
+            for event in parse(&buf[..read as usize]) {
+                spy::process_event(
+                    &mut shared.state.borrow_mut(),
+                    event,
+                    &mut self.tracker.borrow_mut(),
+                    &shared.conf.rules.exes,
+                    &*shared.source,
+                    scanning,
+                );
+            }
+        }
+    }
+}
+
+/// Builds the netlink message that subscribes us to the process events.
+fn subscribe_message() -> Vec<u8> {
+    let op = PROC_CN_MCAST_LISTEN.to_ne_bytes();
+    let len = NLMSG_HDRLEN + CN_MSG_LEN + op.len();
+
+    let mut msg = Vec::with_capacity(len);
+    // struct nlmsghdr
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+    msg.extend_from_slice(&0u16.to_ne_bytes()); // flags
+    msg.extend_from_slice(&0u32.to_ne_bytes()); // seq
+    msg.extend_from_slice(&std::process::id().to_ne_bytes());
+    // struct cn_msg
+    msg.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+    msg.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes()); // seq
+    msg.extend_from_slice(&0u32.to_ne_bytes()); // ack
+    msg.extend_from_slice(&(op.len() as u16).to_ne_bytes());
+    msg.extend_from_slice(&0u16.to_ne_bytes()); // flags
+    msg.extend_from_slice(&op);
+    msg
+}
+
+/// Subscribes to the process connector and registers it in the event loop.
+/// Returns the tracker of the running processes that is kept up to date by
+/// the events, seeded from `procfs`.
+pub(crate) fn listen(
+    handle: &LoopHandle<SharedData>,
+    procfs: Procfs,
+) -> Result<Rc<RefCell<Tracker>>> {
+    let tracker = Rc::new(RefCell::new(Tracker::default()));
+    let connector = Connector::open(Rc::clone(&tracker), procfs)
+        .with_context(|| "Failed to subscribe to the process connector")?;
+
+    // events that arrive from now on are queued, so nothing is missed
+    tracker.borrow_mut().sync(&connector.procfs)?;
+
+    handle
+        .insert_source(
+            Generic::new(connector, Interest::READ, Mode::Level),
+            |_, connector, shared| {
+                connector
+                    .serve(shared)
+                    .log_on_err(Level::Error, "Failed to read process events")
+                    .ok();
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    log::info!("Listening for process events.");
+    Ok(tracker)
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc::FakeSource;
+
+    /// Builds a datagram carrying a single process event, whose data starts
+    /// with `fields`.
+    fn datagram(what: u32, fields: &[u32]) -> Vec<u8> {
+        let mut event = vec![];
+        event.extend_from_slice(&what.to_ne_bytes());
+        event.extend_from_slice(&0u32.to_ne_bytes()); // cpu
+        event.extend_from_slice(&0u64.to_ne_bytes()); // timestamp
+        for field in fields {
+            event.extend_from_slice(&field.to_ne_bytes());
+        }
+        // the rest of the event data, which is 24 bytes at most
+        event.resize(EVENT_DATA_OFFSET + 24, 0);
+
+        let len = NLMSG_HDRLEN + CN_MSG_LEN + event.len();
+        let mut msg = vec![];
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&[0; 12]);
+        msg.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        msg.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        msg.extend_from_slice(&[0; 8]);
+        msg.extend_from_slice(&(event.len() as u16).to_ne_bytes());
+        msg.extend_from_slice(&[0; 2]);
+        msg.extend_from_slice(&event);
+        msg
+    }
+
+    #[test]
+    fn events_are_parsed() {
+        let mut buf = datagram(PROC_EVENT_EXEC, &[42, 42]);
+        buf.extend(datagram(PROC_EVENT_EXIT, &[43, 43]));
+        // a thread exiting, and an unknown event
+        buf.extend(datagram(PROC_EVENT_EXIT, &[44, 43]));
+        buf.extend(datagram(0x0000_0004, &[45, 45]));
+
+        assert_eq!(parse(&buf), [ProcEvent::Exec(42), ProcEvent::Exit(43)],);
+        // truncated datagrams are ignored
+        assert_eq!(parse(&buf[..10]), []);
+    }
+
+    #[test]
+    fn forked_processes_run_the_exe_of_their_parent() {
+        let mut source = FakeSource::default();
+        source.spawn(10, "/usr/sbin/daemon", &[]);
+        let mut tracker = Tracker::default();
+        tracker.sync(&source).unwrap();
+
+        // the daemon forks a process, then a thread, and exits
+        let mut buf = datagram(PROC_EVENT_FORK, &[10, 10, 11, 11]);
+        buf.extend(datagram(PROC_EVENT_FORK, &[11, 11, 12, 11]));
+        buf.extend(datagram(PROC_EVENT_EXIT, &[10, 10]));
+        let events = parse(&buf);
+        assert_eq!(
+            events,
+            [
+                ProcEvent::Fork {
+                    parent: 10,
+                    child: 11
+                },
+                ProcEvent::Exit(10)
+            ]
+        );
+        for event in events {
+            assert_eq!(tracker.apply(event, &source), None);
+        }
+
+        // the child still runs the daemon
+        let procs = tracker.procs.into_iter().collect::<Vec<_>>();
+        assert_eq!(procs, [(11, "/usr/sbin/daemon".into())]);
+    }
+
+    #[test]
+    fn subscribe_message_has_its_length() {
+        let msg = subscribe_message();
+        assert_eq!(read_u32(&msg, 0), Some(msg.len() as u32));
+        assert_eq!(msg.len(), 40);
+    }
+}
+// 1}}} //
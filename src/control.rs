@@ -0,0 +1,468 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Control socket of the daemon.
+//!
+//! Signals can neither take arguments nor return any data, hence a Unix
+//! domain socket is provided to query and steer a running daemon. The socket
+//! is registered as a source in the event loop and speaks the line-delimited
+//! JSON protocol described in [`protocol`].
+
+use std::{
+    fs::{self, Permissions},
+    io::{self, Read, Write},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use calloop::{generic::Generic, Interest, LoopHandle, Mode, PostAction};
+use clap::crate_version;
+use log::Level;
+
+use crate::{common::LogResult, event::SharedData, state::State};
+
+pub(crate) mod protocol;
+
+use protocol::{
+    Data, ExeReport, MemReport, Request, Response, StateDump, StatusReport,
+    Target, ThrottleReport,
+};
+
+/// Upper bound on the length of a single request line.
+const MAX_LINE: usize = 64 * 1024;
+
+/// A connected client along with the bytes read from it that do not form a
+/// complete line yet, and the replies that it has not taken yet.
+#[derive(Debug)]
+struct Client {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+    output: Vec<u8>,
+
+    /// Whether the client is done sending requests.
+    hung_up: bool,
+}
+
+impl AsRawFd for Client {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl Client {
+    fn new(stream: UnixStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            buffer: vec![],
+            output: vec![],
+            hung_up: false,
+        })
+    }
+
+    /// Writes the pending replies, then answers the complete request lines
+    /// one by one, reading more of them as needed, until the socket runs dry.
+    ///
+    /// The socket never blocks, so a reply that the client does not take
+    /// waits in `output` for the socket to become writable. No more
+    /// requests are answered until then, so that a client that does not
+    /// read is held back by the socket rather than by our memory.
+    ///
+    /// Returns `false` if the client hung up or misbehaved and should be
+    /// dropped.
+    fn serve(&mut self, shared: &mut SharedData) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+
+        // the source is edge-triggered, so everything available is handled.
+        while self.flush()? {
+            if let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+                self.answer(&line, shared)?;
+                continue;
+            }
+
+            if self.buffer.len() > MAX_LINE {
+                log::warn!(
+                    "Control client sent an overlong request, dropping it"
+                );
+                return Ok(false);
+            }
+            if self.hung_up {
+                return Ok(false);
+            }
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.hung_up = true,
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Queues the reply to the request `line`.
+    fn answer(
+        &mut self,
+        line: &[u8],
+        shared: &mut SharedData,
+    ) -> io::Result<()> {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+
+        let response = match serde_json::from_slice::<Request>(line) {
+            Ok(request) => handle(request, shared),
+            Err(e) => Response::err(format!("Invalid request: {}", e)),
+        };
+
+        serde_json::to_writer(&mut self.output, &response)?;
+        self.output.push(b'\n');
+        Ok(())
+    }
+
+    /// Writes as much of the pending replies as the socket takes. Returns
+    /// whether all of them were written.
+    fn flush(&mut self) -> io::Result<bool> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.output.drain(..written);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(false)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Carry out a single request and build the reply.
+fn handle(request: Request, shared: &mut SharedData) -> Response {
+    log::debug!("Control request: {:?}", request);
+
+    match request {
+        Request::Status => Response::with_data(Data::Status(status(shared))),
+
+        Request::ForceSave => {
+            match shared.state.borrow_mut().save(&shared.conn) {
+                Ok(()) => Response::ok(),
+                Err(e) => {
+                    Response::err(format!("Failed to save state: {}", e))
+                }
+            }
+        }
+
+        Request::ReloadConfig => match shared.reload_config() {
+            Ok(()) => Response::ok(),
+            Err(e) => Response::err(e),
+        },
+
+        Request::DumpState => {
+            Response::with_data(Data::State(shared.state.borrow().dump()))
+        }
+
+        Request::Pause { what } | Request::Resume { what } => {
+            let paused = matches!(request, Request::Pause { .. });
+            if what != Target::Predict {
+                shared.scan_paused = paused;
+            }
+            if what != Target::Scan {
+                shared.predict_paused = paused;
+            }
+            log::warn!(
+                "{} {:?} on control request",
+                if paused { "Paused" } else { "Resumed" },
+                what,
+            );
+            Response::ok()
+        }
+    }
+}
+
+/// Build a status report of the daemon.
+fn status(shared: &SharedData) -> StatusReport {
+    let state = shared.state.borrow();
+    let throttle = shared.limiter.counts();
+
+    StatusReport {
+        version: crate_version!().to_string(),
+        time: state.time,
+        exes: state.exes.len(),
+        bad_exes: state.bad_exes.len(),
+        maps: state.maps.len(),
+        running_exes: state.running_exes.len(),
+        dirty: state.dirty,
+        scanning: shared.conf.system.doscan && !shared.scan_paused,
+        predicting: shared.conf.system.dopredict && !shared.predict_paused,
+        memory: MemReport {
+            total: state.memstat.total,
+            free: state.memstat.free,
+            buffers: state.memstat.buffers,
+            cached: state.memstat.cached,
+        },
+        throttle: ThrottleReport {
+            requests: throttle.requests,
+            bytes: throttle.bytes,
+            throttled: throttle.throttled,
+            waited_ms: throttle.waited.as_millis() as u64,
+        },
+    }
+}
+
+impl State {
+    /// Collects the statistics of every known exe.
+    pub(crate) fn dump(&self) -> StateDump {
+        let exes = self
+            .exes
+            .values()
+            .map(|exe| {
+                let exe = exe.borrow();
+                ExeReport {
+                    path: exe.path.clone(),
+                    time: exe.time,
+                    size: exe.get_size(),
+                    maps: exe.exemaps.len(),
+                    running: exe.is_running(self),
+                    lnprob: *exe.lnprob,
+                }
+            })
+            .collect();
+
+        StateDump {
+            time: self.time,
+            exes,
+        }
+    }
+}
+
+/// Bind the control socket at `path` and register it with the event loop.
+///
+/// A stale socket left behind by a previous instance is removed first, but
+/// anything else at `path`, be it a file or the socket of an instance that is
+/// still running, is left alone and fails the call. The socket is only
+/// accessible by the owner and the group of the daemon.
+pub(crate) fn listen(
+    path: impl AsRef<Path>,
+    handle: &LoopHandle<SharedData>,
+) -> Result<()> {
+    let path = path.as_ref();
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{:?} exists and is not a socket", path);
+        }
+        if UnixStream::connect(path).is_ok() {
+            bail!("Another instance is listening on {:?}", path);
+        }
+        fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale {:?}", path))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .log_on_err(Level::Error, format!("Failed to bind {:?}", path))
+        .with_context(|| format!("Failed to bind {:?}", path))?;
+    listener.set_nonblocking(true)?;
+    fs::set_permissions(path, Permissions::from_mode(0o660))?;
+
+    let client_handle = handle.clone();
+    handle.insert_source(
+        Generic::new(listener, Interest::READ, Mode::Level),
+        move |_, listener, _| {
+            loop {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        log::warn!("Failed to accept control client: {}", e);
+                        break;
+                    }
+                };
+
+                if let Err(e) = accept(stream, &client_handle) {
+                    log::warn!("Failed to register control client: {}", e);
+                }
+            }
+            Ok(PostAction::Continue)
+        },
+    )?;
+
+    log::info!("Listening for control requests on {:?}", path);
+    Ok(())
+}
+
+/// Register a freshly connected client with the event loop.
+fn accept(stream: UnixStream, handle: &LoopHandle<SharedData>) -> Result<()> {
+    log::debug!("Control client connected");
+
+    // the client is served whenever it sends requests, or makes room for
+    // the replies that it has not taken yet.
+    let client = Client::new(stream)?;
+
+    handle.insert_source(
+        Generic::new(client, Interest::BOTH, Mode::Edge),
+        |_, client, shared| match client.serve(shared) {
+            Ok(true) => Ok(PostAction::Continue),
+            Ok(false) => {
+                log::debug!("Control client disconnected");
+                Ok(PostAction::Remove)
+            }
+            Err(e) => {
+                log::warn!("Control client error: {}", e);
+                Ok(PostAction::Remove)
+            }
+        },
+    )?;
+    Ok(())
+}
+
+/// Remove the control socket at `path`, if any.
+pub(crate) fn cleanup(path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    if path != Path::new("") {
+        fs::remove_file(path)
+            .log_on_err(Level::Warn, format!("Failed to remove {:?}", path))
+            .ok();
+    }
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        sync::mpsc,
+        time::Duration,
+    };
+
+    use calloop::EventLoop;
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::{
+        cli::Opt,
+        common::{RcCell, RcCellNew},
+        config::Config,
+        database,
+        proc::FakeSource,
+    };
+
+    fn shared(event_loop: &EventLoop<SharedData>) -> SharedData {
+        SharedData::new(
+            event_loop.get_signal(),
+            RcCell::new_cell(State::default()),
+            Config::default(),
+            Opt::from_iter(&["rustload"]),
+            database::conn_and_migrate(":memory:").unwrap(),
+            Box::new(FakeSource::default()),
+        )
+    }
+
+    #[test]
+    fn requests_are_answered_line_by_line() {
+        let event_loop = EventLoop::try_new().unwrap();
+        let mut shared = shared(&event_loop);
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let mut client = Client::new(stream).unwrap();
+
+        (&peer)
+            .write_all(
+                b"{\"cmd\": \"pause\", \"what\": \"scan\"}\n\n\
+                  {\"cmd\": \"status\"}\n{\"cmd\": \"nope\"}\n{\"cmd\"",
+            )
+            .unwrap();
+        assert!(client.serve(&mut shared).unwrap());
+        assert!(shared.scan_paused);
+        assert!(!shared.predict_paused);
+
+        let mut replies = BufReader::new(&peer).lines();
+        let mut reply = || {
+            let line = replies.next().unwrap().unwrap();
+            serde_json::from_str::<Response>(&line).unwrap()
+        };
+        assert!(reply().ok);
+        match reply() {
+            Response {
+                ok: true,
+                data: Some(Data::Status(status)),
+                ..
+            } => {
+                assert!(!status.scanning);
+                assert!(status.predicting);
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+        let invalid = reply();
+        assert!(!invalid.ok);
+        assert!(invalid.error.unwrap().starts_with("Invalid request"));
+        // the incomplete request waits for the rest of its line
+        assert_eq!(client.buffer, b"{\"cmd\"");
+
+        drop(peer);
+        assert!(!client.serve(&mut shared).unwrap());
+    }
+
+    #[test]
+    fn replies_wait_for_the_client_to_read_them() {
+        let event_loop = EventLoop::try_new().unwrap();
+        let mut shared = shared(&event_loop);
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let mut client = Client::new(stream).unwrap();
+
+        // more than the socket holds, as if the client did not read
+        client.output = vec![b'\n'; 1 << 20];
+        (&peer)
+            .write_all(b"{\"cmd\": \"pause\", \"what\": \"scan\"}\n")
+            .unwrap();
+        assert!(client.serve(&mut shared).unwrap());
+        assert!(!client.output.is_empty());
+        assert!(!shared.scan_paused);
+
+        let (sender, replies) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut lines = BufReader::new(&peer).lines().map(Result::unwrap);
+            sender.send(lines.find(|line| !line.is_empty())).unwrap();
+        });
+        let reply = loop {
+            assert!(client.serve(&mut shared).unwrap());
+            match replies.recv_timeout(Duration::from_millis(1)) {
+                Ok(reply) => break reply.unwrap(),
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert!(serde_json::from_str::<Response>(&reply).unwrap().ok);
+        assert!(shared.scan_paused);
+    }
+
+    #[test]
+    fn live_sockets_and_files_are_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let event_loop = EventLoop::<SharedData>::try_new().unwrap();
+        let handle = event_loop.handle();
+
+        let file = dir.path().join("file");
+        fs::write(&file, "keep me").unwrap();
+        assert!(listen(&file, &handle).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep me");
+
+        let live = dir.path().join("live.sock");
+        let _other = UnixListener::bind(&live).unwrap();
+        assert!(listen(&live, &handle).is_err());
+
+        let stale = dir.path().join("stale.sock");
+        drop(UnixListener::bind(&stale).unwrap());
+        listen(&stale, &handle).unwrap();
+        UnixStream::connect(&stale).unwrap();
+    }
+}
+// 1}}} //
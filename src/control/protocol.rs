@@ -0,0 +1,247 @@
+//! Wire format of the control socket.
+//!
+//! Every request is a single JSON object terminated by a newline, and every
+//! request is answered by exactly one JSON object terminated by a newline.
+//!
+//! ```text
+//! -> {"cmd": "pause", "what": "scan"}
+//! <- {"ok": true}
+//! -> {"cmd": "status"}
+//! <- {"ok": true, "data": {"time": 420, "exes": 12, ...}}
+//! ```
+//!
+//! This module only depends on `serde` so that it can be shared with the
+//! companion binaries.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// A request sent by a client to the daemon.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub(crate) enum Request {
+    /// Report the state statistics and the runtime flags.
+    Status,
+
+    /// Save the state to the database right away.
+    ForceSave,
+
+    /// Reload the configuration file.
+    ReloadConfig,
+
+    /// Report every known exe along with its statistics.
+    DumpState,
+
+    /// Pause scanning, prediction or both.
+    Pause { what: Target },
+
+    /// Resume scanning, prediction or both.
+    Resume { what: Target },
+}
+
+/// The part of the daemon a [`Request::Pause`] or [`Request::Resume`] acts
+/// upon.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Target {
+    /// Scanning of running processes and model updates.
+    Scan,
+
+    /// Prediction and prefetching.
+    Predict,
+
+    /// Both of the above.
+    All,
+}
+
+/// The reply of the daemon to a [`Request`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Response {
+    /// Whether the request was carried out.
+    pub(crate) ok: bool,
+
+    /// The payload of the reply, if the request returns any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) data: Option<Data>,
+
+    /// The reason of failure if `ok` is false.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+impl Response {
+    /// A successful reply without any payload.
+    pub(crate) fn ok() -> Self {
+        Self {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    /// A successful reply with `data` as its payload.
+    pub(crate) fn with_data(data: Data) -> Self {
+        Self {
+            ok: true,
+            data: Some(data),
+            ..Default::default()
+        }
+    }
+
+    /// A failed reply carrying the reason of failure.
+    pub(crate) fn err(error: impl ToString) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Payload of a [`Response`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Data {
+    Status(StatusReport),
+    State(StateDump),
+}
+
+/// The statistics logged by `State::dump_log` along with the runtime flags
+/// of the daemon.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct StatusReport {
+    /// Version of the daemon.
+    pub(crate) version: String,
+
+    /// Total seconds that the model has been trained for.
+    pub(crate) time: i32,
+
+    /// Number of known exes.
+    pub(crate) exes: usize,
+
+    /// Number of exes that are not worth tracking.
+    pub(crate) bad_exes: usize,
+
+    /// Number of known maps.
+    pub(crate) maps: usize,
+
+    /// Number of exes currently running.
+    pub(crate) running_exes: usize,
+
+    /// Whether the state has changed since the last save.
+    pub(crate) dirty: bool,
+
+    /// Whether scanning is currently carried out.
+    pub(crate) scanning: bool,
+
+    /// Whether prediction is currently carried out.
+    pub(crate) predicting: bool,
+
+    /// Memory statistics in kibibytes, as seen in the last prediction.
+    pub(crate) memory: MemReport,
+
+    /// How much prefetching has been slowed down by its rate limits.
+    pub(crate) throttle: ThrottleReport,
+}
+
+/// Memory statistics in kibibytes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct MemReport {
+    pub(crate) total: u32,
+    pub(crate) free: u32,
+    pub(crate) buffers: u32,
+    pub(crate) cached: u32,
+}
+
+/// Statistics of the rate limits of prefetching, since the daemon started.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ThrottleReport {
+    /// Number of requests prefetched.
+    pub(crate) requests: u64,
+
+    /// Number of bytes prefetched.
+    pub(crate) bytes: u64,
+
+    /// Number of requests that had to wait for their turn.
+    pub(crate) throttled: u64,
+
+    /// Total time that they waited, in milliseconds.
+    pub(crate) waited_ms: u64,
+}
+
+/// Every known exe of the model.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct StateDump {
+    pub(crate) time: i32,
+    pub(crate) exes: Vec<ExeReport>,
+}
+
+/// Statistics of a single exe.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ExeReport {
+    /// Absolute path of the executable.
+    pub(crate) path: PathBuf,
+
+    /// Total running time of the exe in seconds.
+    pub(crate) time: i32,
+
+    /// Sum of the length of the maps of the exe in bytes.
+    pub(crate) size: usize,
+
+    /// Number of maps used by the exe.
+    pub(crate) maps: usize,
+
+    /// Whether the exe is currently running.
+    pub(crate) running: bool,
+
+    /// log-probability of NOT being needed in the next period, as computed
+    /// in the last prediction.
+    pub(crate) lnprob: f64,
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_match_the_wire_format() {
+        let request: Request =
+            serde_json::from_str(r#"{"cmd": "pause", "what": "predict"}"#)
+                .unwrap();
+        assert_eq!(
+            request,
+            Request::Pause {
+                what: Target::Predict
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"cmd": "force-save"}"#)
+                .unwrap(),
+            Request::ForceSave
+        );
+        assert!(
+            serde_json::from_str::<Request>(r#"{"cmd": "pause"}"#).is_err()
+        );
+
+        assert_eq!(
+            serde_json::to_string(&Response::ok()).unwrap(),
+            r#"{"ok":true}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Response::err("nope")).unwrap(),
+            r#"{"ok":false,"error":"nope"}"#
+        );
+
+        // payloads are told apart by their fields
+        let reply = serde_json::to_string(&Response::with_data(Data::State(
+            StateDump::default(),
+        )))
+        .unwrap();
+        match serde_json::from_str::<Response>(&reply).unwrap().data {
+            Some(Data::State(dump)) => assert!(dump.exes.is_empty()),
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+}
+// 1}}} //
@@ -0,0 +1,369 @@
+// vim:set et sw=4 ts=4 tw=79:
+//! `rustloadctl` inspects and steers a running rustload daemon through its
+//! control socket.
+//!
+//! Every subcommand sends a single request to the daemon and renders the
+//! reply either as a table meant for humans or as JSON meant for scripts.
+
+#![deny(unused_imports)]
+
+use std::{
+    cmp::Reverse,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use structopt::StructOpt;
+
+#[allow(dead_code)]
+#[path = "control/protocol.rs"]
+mod protocol;
+
+use protocol::{Data, ExeReport, Request, Response, Target};
+
+/// Inspect and steer a running rustload daemon.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "rustloadctl",
+    version = structopt::clap::crate_version!(),
+    global_settings = &[
+        structopt::clap::AppSettings::ColoredHelp,
+        structopt::clap::AppSettings::UnifiedHelpMessage,
+    ],
+)]
+struct Opt {
+    /// Control socket of the daemon.
+    #[structopt(
+        short,
+        long,
+        default_value = "/run/rustload.sock",
+        parse(from_os_str)
+    )]
+    socket: PathBuf,
+
+    /// Print the reply as JSON instead of a table.
+    #[structopt(short, long)]
+    json: bool,
+
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Show the state statistics and whether scanning and prediction are
+    /// active.
+    Status,
+
+    /// Show the exes that have been running the longest.
+    TopExes {
+        /// Number of exes to show. 0 shows all of them.
+        #[structopt(short = "n", long, default_value = "20")]
+        count: usize,
+    },
+
+    /// Show the exes that are most likely to be started next, as computed in
+    /// the last prediction.
+    Predictions {
+        /// Number of exes to show. 0 shows all of them.
+        #[structopt(short = "n", long, default_value = "20")]
+        count: usize,
+    },
+
+    /// Save the state right away.
+    Save,
+
+    /// Reload the configuration file.
+    Reload,
+
+    /// Pause scanning, prediction or both.
+    Pause {
+        /// One of `scan`, `predict` or `all`.
+        #[structopt(default_value = "all", parse(try_from_str = parse_target))]
+        what: Target,
+    },
+
+    /// Resume scanning, prediction or both.
+    Resume {
+        /// One of `scan`, `predict` or `all`.
+        #[structopt(default_value = "all", parse(try_from_str = parse_target))]
+        what: Target,
+    },
+}
+
+fn parse_target(value: &str) -> Result<Target> {
+    Ok(match value {
+        "scan" => Target::Scan,
+        "predict" => Target::Predict,
+        "all" => Target::All,
+        _ => anyhow::bail!("expected one of `scan`, `predict` or `all`"),
+    })
+}
+
+/// Send a single request to the daemon and wait for its reply.
+fn send(opt: &Opt, request: Request) -> Result<Response> {
+    let mut stream = UnixStream::connect(&opt.socket).with_context(|| {
+        format!("Failed to connect to the daemon at {:?}", opt.socket)
+    })?;
+
+    let mut line = serde_json::to_vec(&request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+
+    let mut reply = String::new();
+    BufReader::new(stream)
+        .read_line(&mut reply)
+        .with_context(|| "Failed to read the reply of the daemon")?;
+    anyhow::ensure!(!reply.is_empty(), "The daemon closed the connection");
+
+    Ok(serde_json::from_str(&reply)?)
+}
+
+/// Probability that the exe is needed in the next period.
+fn probability(exe: &ExeReport) -> f64 {
+    1.0 - exe.lnprob.exp()
+}
+
+/// Write `value` into `out` as pretty JSON.
+fn write_json(out: &mut impl Write, value: &impl Serialize) -> Result<()> {
+    serde_json::to_writer_pretty(&mut *out, value)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Write the exes into `out` as a table with `extra` as the header of the
+/// last column.
+fn write_exes(
+    out: &mut impl Write,
+    exes: &[&ExeReport],
+    extra: &str,
+    value: impl Fn(&ExeReport) -> String,
+) -> Result<()> {
+    writeln!(
+        out,
+        "{:>10} {:>10} {:>5} {:>7} {:>10}  PATH",
+        "TIME", "SIZE (kb)", "MAPS", "RUNNING", extra
+    )?;
+    for exe in exes {
+        writeln!(
+            out,
+            "{:>10} {:>10} {:>5} {:>7} {:>10}  {}",
+            exe.time,
+            exe.size / 1024,
+            exe.maps,
+            if exe.running { "yes" } else { "no" },
+            value(exe),
+            exe.path.display(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Keep the first `count` items of `items`, or all of them if `count` is 0.
+fn truncate<T>(items: &mut Vec<T>, count: usize) {
+    if count != 0 {
+        items.truncate(count);
+    }
+}
+
+/// Render the payload of the reply to `cmd` into `out`, as JSON if `json` is
+/// set.
+fn render(
+    cmd: &Command,
+    json: bool,
+    data: Option<Data>,
+    out: &mut impl Write,
+) -> Result<()> {
+    match (cmd, data) {
+        (Command::Status, Some(Data::Status(status))) => {
+            if json {
+                return write_json(out, &status);
+            }
+            writeln!(out, "version          = {}", status.version)?;
+            writeln!(out, "preload time     = {}", status.time)?;
+            writeln!(out, "num exes         = {}", status.exes)?;
+            writeln!(out, "num bad exes     = {}", status.bad_exes)?;
+            writeln!(out, "num maps         = {}", status.maps)?;
+            writeln!(out, "num running exes = {}", status.running_exes)?;
+            writeln!(out, "dirty            = {}", status.dirty)?;
+            writeln!(out, "scanning         = {}", status.scanning)?;
+            writeln!(out, "predicting       = {}", status.predicting)?;
+            writeln!(out, "memory total     = {} kb", status.memory.total)?;
+            writeln!(out, "memory free      = {} kb", status.memory.free)?;
+            writeln!(out, "memory buffers   = {} kb", status.memory.buffers)?;
+            writeln!(out, "memory cached    = {} kb", status.memory.cached)?;
+            writeln!(
+                out,
+                "prefetched       = {} requests, {} bytes",
+                status.throttle.requests, status.throttle.bytes
+            )?;
+            writeln!(
+                out,
+                "throttled        = {} requests, {} ms",
+                status.throttle.throttled, status.throttle.waited_ms
+            )?;
+        }
+
+        (Command::TopExes { count }, Some(Data::State(dump))) => {
+            let mut exes = dump.exes.iter().collect::<Vec<_>>();
+            exes.sort_by_key(|exe| Reverse(exe.time));
+            truncate(&mut exes, *count);
+
+            if json {
+                return write_json(out, &exes);
+            }
+            write_exes(out, &exes, "SHARE", |exe| {
+                format!(
+                    "{:.2}%",
+                    exe.time as f64 * 100.0 / dump.time.max(1) as f64
+                )
+            })?;
+        }
+
+        (Command::Predictions { count }, Some(Data::State(dump))) => {
+            // running exes don't bid, so they are not predicted
+            let mut exes =
+                dump.exes.iter().filter(|e| !e.running).collect::<Vec<_>>();
+            exes.sort_by(|a, b| a.lnprob.total_cmp(&b.lnprob));
+            truncate(&mut exes, *count);
+
+            if json {
+                return write_json(out, &exes);
+            }
+            write_exes(out, &exes, "PROB", |exe| {
+                format!("{:.4}", probability(exe))
+            })?;
+        }
+
+        (_, None) => {
+            if json {
+                return write_json(out, &serde_json::json!({ "ok": true }));
+            }
+            writeln!(out, "ok")?;
+        }
+
+        (_, Some(_)) => anyhow::bail!("Unexpected reply from the daemon"),
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+
+    let request = match opt.cmd {
+        Command::Status => Request::Status,
+        Command::TopExes { .. } | Command::Predictions { .. } => {
+            Request::DumpState
+        }
+        Command::Save => Request::ForceSave,
+        Command::Reload => Request::ReloadConfig,
+        Command::Pause { what } => Request::Pause { what },
+        Command::Resume { what } => Request::Resume { what },
+    };
+
+    let response = send(&opt, request)?;
+    if !response.ok {
+        anyhow::bail!(
+            "The daemon refused the request: {}",
+            response.error.as_deref().unwrap_or("unknown error")
+        );
+    }
+
+    render(&opt.cmd, opt.json, response.data, &mut io::stdout().lock())
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{StateDump, StatusReport};
+
+    fn exe(path: &str, time: i32, running: bool, lnprob: f64) -> ExeReport {
+        ExeReport {
+            path: path.into(),
+            time,
+            size: 8192,
+            maps: 2,
+            running,
+            lnprob,
+        }
+    }
+
+    fn rendered(cmd: Command, json: bool, data: Option<Data>) -> String {
+        let mut out = vec![];
+        render(&cmd, json, data, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn dump() -> Data {
+        Data::State(StateDump {
+            time: 100,
+            exes: vec![
+                exe("/usr/bin/a", 10, false, -0.5),
+                exe("/usr/bin/b", 60, true, -3.0),
+                exe("/usr/bin/c", 30, false, -2.0),
+            ],
+        })
+    }
+
+    #[test]
+    fn targets_are_parsed() {
+        assert_eq!(parse_target("scan").unwrap(), Target::Scan);
+        assert_eq!(parse_target("predict").unwrap(), Target::Predict);
+        assert_eq!(parse_target("all").unwrap(), Target::All);
+        assert!(parse_target("Scan").is_err());
+    }
+
+    #[test]
+    fn zero_keeps_everything() {
+        let mut items = vec![1, 2, 3];
+        truncate(&mut items, 0);
+        assert_eq!(items, [1, 2, 3]);
+        truncate(&mut items, 5);
+        assert_eq!(items, [1, 2, 3]);
+        truncate(&mut items, 2);
+        assert_eq!(items, [1, 2]);
+    }
+
+    #[test]
+    fn replies_are_rendered() {
+        let status = StatusReport {
+            exes: 3,
+            ..Default::default()
+        };
+        let table =
+            rendered(Command::Status, false, Some(Data::Status(status)));
+        assert!(table.contains("num exes         = 3\n"));
+
+        let table =
+            rendered(Command::TopExes { count: 2 }, false, Some(dump()));
+        let paths = table
+            .lines()
+            .skip(1)
+            .map(|line| line.rsplit(' ').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/usr/bin/b", "/usr/bin/c"]);
+        assert!(table.contains("60.00%"));
+
+        // running exes are not predicted, and the likeliest come first
+        let json =
+            rendered(Command::Predictions { count: 0 }, true, Some(dump()));
+        let exes: Vec<ExeReport> = serde_json::from_str(&json).unwrap();
+        let paths = exes
+            .iter()
+            .map(|exe| exe.path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/usr/bin/c", "/usr/bin/a"]);
+
+        assert_eq!(rendered(Command::Save, false, None), "ok\n");
+        let mut out = vec![];
+        assert!(
+            render(&Command::Status, false, Some(dump()), &mut out).is_err()
+        );
+    }
+}
+// 1}}} //
@@ -126,3 +126,146 @@ pub(crate) fn conn_and_migrate(
 
     Ok(conn)
 }
+
+/// Connect to an existing `sqlite` database located at `path` without running
+/// any migrations. The connection refuses to modify the database.
+pub(crate) fn conn_readonly(
+    path: impl AsRef<Path>,
+) -> Result<SqliteConnection> {
+    let path = path.as_ref();
+    anyhow::ensure!(path.is_file(), "State file {:?} does not exist", path);
+
+    let conn = establish_connection(path)
+        .with_context(|| "Failed to connect to the database")?;
+    conn.execute("PRAGMA query_only = ON")
+        .with_context(|| "Failed to open the database read-only")?;
+
+    Ok(conn)
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use diesel::connection::SimpleConnection;
+
+    use super::*;
+    use crate::schema;
+
+    /// A database as written by the versions that appended the model on
+    /// every save, with a snapshot for each of the `sessions`. Each session
+    /// numbered the maps and exes anew.
+    fn appended(sessions: &[&[(&str, &str)]]) -> SqliteConnection {
+        let conn = establish_connection(":memory:").unwrap();
+        conn.batch_execute(include_str!(
+            "../migrations/2021-08-29-175231_create_state/up.sql"
+        ))
+        .unwrap();
+
+        for exes in sessions {
+            let mut rows = String::new();
+            for (seq, (exe, lib)) in exes.iter().enumerate() {
+                rows += &format!(
+                    "INSERT INTO maps (seq, update_time, offset, length, uri) \
+                     VALUES ({0}, 0, 0, 4096, 'file://{1}');
+                     INSERT INTO exes (seq, update_time, time, uri) \
+                     VALUES ({0}, 0, 10, 'file://{2}');
+                     INSERT INTO exemaps (seq, map_seq, prob) \
+                     VALUES ({0}, {0}, 1.0);",
+                    seq + 1,
+                    lib,
+                    exe,
+                );
+            }
+            // the chain of the first two exes, written from both of them
+            rows += &"INSERT INTO markovstates \
+                      (a_seq, b_seq, time, time_to_leave, weight) \
+                      VALUES (1, 2, 0, x'', x'');"
+                .repeat(2);
+            conn.batch_execute(&rows).unwrap();
+        }
+        conn
+    }
+
+    fn count_rows(conn: &SqliteConnection) -> [i64; 4] {
+        [
+            schema::maps::table.count().get_result(conn).unwrap(),
+            schema::exes::table.count().get_result(conn).unwrap(),
+            schema::exemaps::table.count().get_result(conn).unwrap(),
+            schema::markovstates::table
+                .count()
+                .get_result(conn)
+                .unwrap(),
+        ]
+    }
+
+    const INCREMENTAL_SAVES: &str = include_str!(
+        "../migrations/2022-11-20-000000_incremental_saves/up.sql"
+    );
+
+    #[test]
+    fn the_latest_snapshot_is_kept() {
+        use schema::{exes, maps};
+
+        // `b` is gone in the second session, and `c` and `a` were numbered
+        // anew
+        let conn = appended(&[
+            &[
+                ("/usr/bin/a", "/usr/lib/liba.so"),
+                ("/usr/bin/b", "/usr/lib/libb.so"),
+            ],
+            &[
+                ("/usr/bin/c", "/usr/lib/libc.so"),
+                ("/usr/bin/a", "/usr/lib/liba.so"),
+            ],
+        ]);
+        conn.batch_execute(INCREMENTAL_SAVES).unwrap();
+        assert_eq!(count_rows(&conn), [2, 2, 2, 1]);
+
+        let maps = maps::table
+            .select((maps::seq, maps::uri))
+            .order(maps::seq)
+            .load::<(i32, String)>(&conn)
+            .unwrap();
+        assert_eq!(
+            maps,
+            [
+                (1, "file:///usr/lib/libc.so".to_owned()),
+                (2, "file:///usr/lib/liba.so".to_owned())
+            ]
+        );
+        let exes = exes::table
+            .select((exes::seq, exes::uri))
+            .order(exes::seq)
+            .load::<(i32, String)>(&conn)
+            .unwrap();
+        assert_eq!(
+            exes,
+            [
+                (1, "file:///usr/bin/c".to_owned()),
+                (2, "file:///usr/bin/a".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_saves_of_a_session_are_merged() {
+        let session: &[_] = &[
+            ("/usr/bin/a", "/usr/lib/liba.so"),
+            ("/usr/bin/b", "/usr/lib/libb.so"),
+        ];
+        let conn = appended(&[session, session, session]);
+        conn.batch_execute(INCREMENTAL_SAVES).unwrap();
+        assert_eq!(count_rows(&conn), [2, 2, 2, 1]);
+    }
+
+    #[test]
+    fn a_single_snapshot_is_kept() {
+        let conn = appended(&[&[
+            ("/usr/bin/a", "/usr/lib/liba.so"),
+            ("/usr/bin/b", "/usr/lib/libb.so"),
+        ]]);
+        conn.batch_execute(INCREMENTAL_SAVES).unwrap();
+        assert_eq!(count_rows(&conn), [2, 2, 2, 1]);
+    }
+}
+// 1}}} //
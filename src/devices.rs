@@ -0,0 +1,218 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Properties of the devices that files are stored on.
+//!
+//! The block device of a file is found from its `st_dev`, as
+//! `<sysfsroot>/dev/block/<major>:<minor>`. A partition has no request queue
+//! of its own, so that of the disk it is part of is used. Files on network
+//! filesystems or FUSE have no block device to speak of, and are recognized
+//! with `statfs(2)` instead.
+//!
+//! Filesystems like btrfs, overlayfs or tmpfs have an anonymous device, of
+//! major number 0, that is not in sysfs. Whatever they are stored on, they
+//! are not made serial, as a slow disk is a lesser evil than a fast one
+//! queued up one request at a time.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::Level;
+use nix::sys::{
+    stat::{major, minor},
+    statfs,
+};
+
+use crate::{common::LogResult, model::SortStrategy};
+
+/// Magic numbers of the filesystems whose files are not on a local disk, as
+/// in `linux/magic.h`.
+const REMOTE_MAGICS: &[i64] = &[
+    0x6969,      // NFS
+    0x517b,      // SMB
+    0xff53_4d42, // CIFS
+    0xfe53_4d42, // SMB2
+    0x0102_1997, // 9P
+    0x00c3_6400, // Ceph
+    0x5346_414f, // AFS
+    0x7375_7245, // Coda
+    0x6573_5546, // FUSE
+];
+
+/// What a device is, as far as the order of the I/O on it is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    /// A rotating disk, or any block device that cannot be told apart.
+    Rotational,
+
+    /// A disk that does not rotate, like an SSD.
+    Solid,
+
+    /// A network filesystem or FUSE.
+    Remote,
+}
+
+/// The kinds of the devices seen so far.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Devices {
+    /// Where sysfs is mounted.
+    sysfsroot: PathBuf,
+
+    /// Keyed by device number.
+    kinds: BTreeMap<u64, Kind>,
+}
+
+impl Devices {
+    pub(crate) fn new(sysfsroot: impl Into<PathBuf>) -> Self {
+        Self {
+            sysfsroot: sysfsroot.into(),
+            kinds: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the sort strategy suited to the device `dev`, that the file at
+    /// `path` is stored on:
+    ///
+    /// - [`SortStrategy::Path`] for network filesystems and FUSE, whose
+    ///   layout is unknown,
+    /// - [`SortStrategy::None`] for disks that do not rotate, like SSDs, for
+    ///   which the order does not matter, and for anonymous devices,
+    /// - [`SortStrategy::Block`] for rotating disks, and for any other device
+    ///   that cannot be told apart.
+    pub(crate) fn sort_strategy(
+        &mut self,
+        dev: u64,
+        path: &Path,
+    ) -> SortStrategy {
+        match self.kind(dev, path) {
+            Kind::Rotational => SortStrategy::Block,
+            Kind::Solid => SortStrategy::None,
+            Kind::Remote => SortStrategy::Path,
+        }
+    }
+
+    /// Whether the requests to the device `dev`, that the file at `path` is
+    /// stored on, should be made one at a time. A rotating disk loses more to
+    /// the seeks between concurrent requests than it gains from them.
+    pub(crate) fn is_serial(&mut self, dev: u64, path: &Path) -> bool {
+        self.kind(dev, path) == Kind::Rotational
+    }
+
+    /// Whether the device `dev`, that the file at `path` is stored on, is a
+    /// network filesystem or FUSE.
+    pub(crate) fn is_remote(&mut self, dev: u64, path: &Path) -> bool {
+        self.kind(dev, path) == Kind::Remote
+    }
+
+    fn kind(&mut self, dev: u64, path: &Path) -> Kind {
+        let sysfsroot = &self.sysfsroot;
+        *self.kinds.entry(dev).or_insert_with(|| {
+            let kind = if is_remote(path) {
+                Kind::Remote
+            } else {
+                match rotational(sysfsroot, dev).log_on_err(
+                    Level::Debug,
+                    format!("Failed to learn whether {:?} rotates", path),
+                ) {
+                    Ok(false) => Kind::Solid,
+                    Err(_) if major(dev) == 0 => Kind::Solid,
+                    _ => Kind::Rotational,
+                }
+            };
+            log::debug!("Device {}:{} is {:?}", major(dev), minor(dev), kind);
+            kind
+        })
+    }
+}
+
+/// Whether the file at `path` is on a network filesystem or FUSE.
+// the type of the magic number is narrower on 32-bit targets.
+#[allow(clippy::unnecessary_cast)]
+fn is_remote(path: &Path) -> bool {
+    match statfs::statfs(path) {
+        Ok(stat) => REMOTE_MAGICS.contains(&(stat.filesystem_type().0 as i64)),
+        Err(_) => false,
+    }
+}
+
+/// Whether the block device `dev` is a rotating disk, according to the sysfs
+/// at `sysfsroot`.
+fn rotational(sysfsroot: &Path, dev: u64) -> Result<bool> {
+    let device = sysfsroot.join("dev/block").join(format!(
+        "{}:{}",
+        major(dev),
+        minor(dev)
+    ));
+    let queue = if device.join("partition").exists() {
+        device.join("../queue")
+    } else {
+        device.join("queue")
+    };
+
+    let path = queue.join("rotational");
+    let rotational = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    Ok(rotational.trim() == "1")
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use nix::sys::stat::makedev;
+
+    use super::*;
+
+    #[test]
+    fn strategies_follow_the_disks() {
+        let sysfs = tempfile::tempdir().unwrap();
+        let sysfs = sysfs.path();
+        let block = sysfs.join("dev/block");
+        fs::create_dir_all(&block).unwrap();
+        for (disk, rotational) in &[("sda", "1\n"), ("nvme0n1", "0\n")] {
+            let queue = sysfs.join("devices").join(disk).join("queue");
+            fs::create_dir_all(&queue).unwrap();
+            fs::write(queue.join("rotational"), rotational).unwrap();
+        }
+        let partition = sysfs.join("devices/sda/sda1");
+        fs::create_dir_all(&partition).unwrap();
+        fs::write(partition.join("partition"), "1\n").unwrap();
+        symlink("../../devices/sda", block.join("8:0")).unwrap();
+        symlink("../../devices/sda/sda1", block.join("8:1")).unwrap();
+        symlink("../../devices/nvme0n1", block.join("259:0")).unwrap();
+
+        assert!(rotational(sysfs, makedev(8, 0)).unwrap());
+        assert!(rotational(sysfs, makedev(8, 1)).unwrap());
+        assert!(!rotational(sysfs, makedev(259, 0)).unwrap());
+        assert!(rotational(sysfs, makedev(7, 0)).is_err());
+
+        let mut devices = Devices::new(sysfs);
+        let file = sysfs.join("devices/sda/queue/rotational");
+        assert_eq!(
+            devices.sort_strategy(makedev(8, 1), &file),
+            SortStrategy::Block
+        );
+        assert_eq!(
+            devices.sort_strategy(makedev(259, 0), &file),
+            SortStrategy::None
+        );
+        // unknown devices keep the most careful strategy
+        assert_eq!(
+            devices.sort_strategy(makedev(7, 0), &file),
+            SortStrategy::Block
+        );
+        // but anonymous ones are not made serial
+        assert_eq!(
+            devices.sort_strategy(makedev(0, 42), &file),
+            SortStrategy::None
+        );
+        assert!(!devices.is_serial(makedev(0, 42), &file));
+        assert!(devices.is_serial(makedev(8, 1), &file));
+        assert!(!devices.is_serial(makedev(259, 0), &file));
+        assert!(!devices.is_remote(makedev(8, 1), &file));
+    }
+}
+// 1}}} //
@@ -1,4 +1,4 @@
-use std::{convert::TryInto, rc::Rc, time::Duration};
+use std::{convert::TryInto, rc::Rc, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use calloop::{timer::Timer, LoopHandle, LoopSignal};
@@ -6,12 +6,17 @@ use diesel::SqliteConnection;
 use log::Level;
 
 use crate::{
-    cli,
+    acct, cli,
     common::{LogResult, RcCell},
     config,
-    model::SortStrategy,
-    prophet, spy,
+    model::{PrefetchBackend, SortStrategy},
+    proc::ProcessSource,
+    prophet::{self, PrefetchConfig},
+    readahead::{self, Prefetcher},
+    spy,
     state::{self, State},
+    throttle::{Limiter, Paced, Pacer},
+    uring::Uring,
 };
 
 /// Holds the data that will be shared across our event loop. Notably, it also
@@ -23,6 +28,31 @@ pub(crate) struct SharedData {
     pub(crate) conf: config::Config,
     pub(crate) opt: cli::Opt,
     pub(crate) conn: SqliteConnection,
+
+    /// Where the running processes and memory conditions are read from.
+    pub(crate) source: Box<dyn ProcessSource>,
+
+    /// Whether scanning has been paused through the control socket.
+    pub(crate) scan_paused: bool,
+
+    /// Whether prediction has been paused through the control socket.
+    pub(crate) predict_paused: bool,
+
+    /// The error that stopped the event loop, if it did not stop on request.
+    pub(crate) failure: Option<anyhow::Error>,
+
+    /// Follows the process accounting file, if one is configured.
+    pub(crate) acct: Option<acct::Tail>,
+
+    /// The ring prefetching is done with, if the io_uring backend is used.
+    pub(crate) uring: Option<Uring>,
+
+    /// The rate limits of prefetching.
+    pub(crate) limiter: Arc<Limiter>,
+
+    /// Makes the requests of the backends other than io_uring, in the
+    /// background.
+    pub(crate) pacer: Option<Pacer>,
 }
 
 impl SharedData {
@@ -32,13 +62,99 @@ impl SharedData {
         conf: config::Config,
         opt: cli::Opt,
         conn: SqliteConnection,
+        source: Box<dyn ProcessSource>,
     ) -> Self {
+        let limiter = Arc::new(Limiter::new(
+            conf.system.prefetchrate as u64 * 1024,
+            conf.system.prefetchiops as u64,
+        ));
         Self {
             signal,
             state,
             conf,
             opt,
             conn,
+            source,
+            scan_paused: false,
+            predict_paused: false,
+            failure: None,
+            acct: None,
+            uring: None,
+            limiter,
+            pacer: None,
+        }
+    }
+
+    /// Stop the event loop because of `error`. Only the first error is kept,
+    /// and it decides the exit status of the daemon.
+    pub(crate) fn fail(&mut self, error: anyhow::Error) {
+        self.failure.get_or_insert(error);
+        self.signal.stop();
+    }
+
+    /// Reload the configuration file. The old configuration is kept if the
+    /// new one cannot be loaded.
+    pub(crate) fn reload_config(&mut self) -> Result<()> {
+        let conf = config::load_config(&self.opt.conffile).log_on_err(
+            Level::Warn,
+            "Failed to load configuration. Using old configuration.",
+        )?;
+        self.conf = conf;
+        self.limiter.set_limits(
+            self.conf.system.prefetchrate as u64 * 1024,
+            self.conf.system.prefetchiops as u64,
+        );
+        log::info!("Reloading config done!");
+        Ok(())
+    }
+
+    /// Whether prefetch decisions are recorded instead of carried out.
+    fn dryrun(&self) -> bool {
+        self.conf.system.dryrun || self.opt.dry_run
+    }
+
+    /// The prefetcher to use.
+    fn prefetcher(&self) -> Box<dyn Prefetcher> {
+        let system = &self.conf.system;
+        let backend = if self.dryrun() {
+            PrefetchBackend::Noop
+        } else {
+            // checked by `config::load_config`, so the fallback is unused.
+            system
+                .prefetchbackend
+                .try_into()
+                .unwrap_or(PrefetchBackend::Fadvise)
+        };
+        // the ring paces itself, and nothing is read in dry-run mode.
+        match (backend, &self.uring, &self.pacer) {
+            (PrefetchBackend::IoUring, Some(uring), _) => {
+                Box::new(uring.clone())
+            }
+            (PrefetchBackend::Noop, ..) => readahead::prefetcher(backend),
+            (_, _, Some(pacer)) => Box::new(Paced {
+                inner: readahead::prefetcher(backend).into(),
+                pacer: pacer.clone(),
+            }),
+            // without an event loop to report to, the requests are made at
+            // once.
+            _ => readahead::prefetcher(backend),
+        }
+    }
+
+    /// How to prefetch, and within which budget.
+    fn prefetch_config(&self) -> PrefetchConfig<'_> {
+        let (system, model) = (&self.conf.system, &self.conf.model);
+        PrefetchConfig {
+            // checked by `config::load_config`, so the fallback is unused.
+            sort_strategy: system
+                .sortstrategy
+                .try_into()
+                .unwrap_or(SortStrategy::Auto),
+            processes: system.processes,
+            memtotal: model.memtotal,
+            memfree: model.memfree,
+            memcached: model.memcached,
+            dryrun: self.dryrun().then_some(system.dryrunfile.as_path()),
         }
     }
 }
@@ -57,8 +173,13 @@ impl State {
         timer.handle().add_timeout(delay_from_now, ());
 
         handle.insert_source(timer, move |_, meta, shared| {
-            if shared.state.borrow_mut().save(&shared.conn).is_err() {
-                shared.signal.stop()
+            let saved = shared
+                .state
+                .borrow_mut()
+                .save(&shared.conn)
+                .log_on_err(Level::Error, "Failed to autosave state");
+            if let Err(e) = saved {
+                shared.fail(e)
             }
             meta.add_timeout(delay_from_now, ());
         })?;
@@ -69,6 +190,8 @@ impl State {
         handle: LoopHandle<SharedData>,
         shared: &mut SharedData,
     ) -> Result<()> {
+        Self::prefetch_pinned(shared);
+
         // set up ticker
         Self::autosave(handle.clone(), shared)?;
         Self::tick(handle.clone(), shared)?;
@@ -76,6 +199,26 @@ impl State {
         Ok(())
     }
 
+    /// Prefetches the parts of the pinned exes and files that are not in the
+    /// page cache. It is done at start, and on every cycle where no
+    /// prediction does it, so that pinning does not depend on prediction.
+    fn prefetch_pinned(shared: &SharedData) {
+        let conf = &shared.conf;
+        if conf.system.pinned.is_empty() {
+            return;
+        }
+
+        prophet::prefetch_pinned(
+            &mut shared.state.borrow_mut(),
+            &conf.system.pinned,
+            &*shared.source,
+            &*shared.prefetcher(),
+            shared.prefetch_config(),
+        )
+        .log_on_err(Level::Warn, "Failed to prefetch pinned files")
+        .ok();
+    }
+
     fn tick(
         handle: LoopHandle<SharedData>,
         _shared: &mut SharedData,
@@ -88,11 +231,19 @@ impl State {
             let conf = &shared.conf;
             let state = &shared.state;
 
-            if conf.system.doscan {
+            if conf.system.doscan && !shared.scan_paused {
                 log::debug!("State scanning begin");
+                acct::account(
+                    &mut shared.acct,
+                    &conf.system.acctfile,
+                    &state.borrow(),
+                )
+                .log_on_err(Level::Warn, "Failed to read process accounting")
+                .ok();
                 spy::scan(
                     &mut state.borrow_mut(),
-                    Some(&conf.system.mapprefix),
+                    &conf.rules.exes,
+                    &*shared.source,
                 )
                 .log_on_err(Level::Warn, "Failed to scan")
                 .ok();
@@ -104,22 +255,20 @@ impl State {
                 }
                 log::debug!("State scanning end")
             }
-            if conf.system.dopredict {
+            if conf.system.dopredict && !shared.predict_paused {
                 prophet::predict(
                     &mut state.borrow_mut(),
+                    &conf.rules,
+                    &conf.system.pinned,
                     conf.model.usecorrelation,
-                    shared
-                        .conf
-                        .system
-                        .sortstrategy
-                        .try_into()
-                        .unwrap_or(SortStrategy::Block),
-                    conf.model.memtotal,
-                    conf.model.memfree,
-                    conf.model.memcached,
+                    &*shared.source,
+                    &*shared.prefetcher(),
+                    shared.prefetch_config(),
                 )
                 .log_on_err(Level::Warn, "Failed to predict")
                 .ok();
+            } else {
+                Self::prefetch_pinned(shared);
             }
 
             state.borrow_mut().time += conf.model.cycle as i32 / 2;
@@ -144,24 +293,28 @@ impl State {
             let state = &shared.state;
 
             let model_dirty = state.borrow().model_dirty;
-            if model_dirty
-                && spy::update_model(
+            let updated = if model_dirty {
+                spy::update_model(
                     Rc::clone(state),
-                    &conf.system.mapprefix,
+                    &conf.rules,
                     conf.model.minsize as u64,
                     conf.model.cycle,
+                    &*shared.source,
                 )
                 .log_on_err(Level::Error, "Failed to update model")
-                .is_err()
-            {
-                shared.signal.stop()
-            }
+            } else {
+                Ok(())
+            };
 
             state.borrow_mut().time += conf.model.cycle as i32 / 2;
             meta.add_timeout(
                 Duration::from_secs(conf.model.cycle as u64 / 2),
                 (),
             );
+
+            if let Err(e) = updated {
+                shared.fail(e)
+            }
         })?;
         Ok(())
     }
@@ -0,0 +1,322 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Location of files on disk.
+//!
+//! The extents of a file, that is the ranges of its bytes that are stored
+//! contiguously on the device, are learnt with the `FS_IOC_FIEMAP` ioctl.
+//! Filesystems that do not support it may still answer the older `FIBMAP`,
+//! one block at a time, though it needs `CAP_SYS_RAWIO`.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::TryInto,
+    fs::File,
+    os::unix::prelude::AsRawFd,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use log::Level;
+use nix::errno::Errno;
+
+use crate::common::{FileId, LogResult};
+
+/// `_IOWR('f', 11, struct fiemap)`.
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+
+/// `_IO(0x00, 1)`.
+const FIBMAP: libc::c_ulong = 1;
+
+/// `_IO(0x00, 2)`.
+const FIGETBSZ: libc::c_ulong = 2;
+
+/// The extent is the last one of the file.
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+
+/// The location of the extent is not known, like that of a delayed
+/// allocation.
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+
+/// Number of extents asked for by each `FS_IOC_FIEMAP` call.
+const BATCH: usize = 32;
+
+/// `struct fiemap_extent` of `linux/fiemap.h`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+/// `struct fiemap` of `linux/fiemap.h`, with room for [`BATCH`] extents.
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; BATCH],
+}
+
+/// A range of bytes of a file that is stored contiguously on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Extent {
+    /// Offset of the range in the file.
+    logical: u64,
+
+    /// Offset of the range on the device.
+    physical: u64,
+
+    /// Length of the range.
+    length: u64,
+}
+
+/// The extents of a file known so far.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Located {
+    /// Identity of the file the extents belong to.
+    id: FileId,
+
+    /// Extents ordered by offset in the file.
+    extents: Vec<Extent>,
+
+    /// Whether `extents` are all the extents of the file. Otherwise, they
+    /// are the blocks looked up one by one with `FIBMAP` so far.
+    complete: bool,
+}
+
+/// Cache of the extents of files, by identity.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Extents {
+    /// Keyed by device and inode number, so that a file that is replaced
+    /// takes the place of the old one.
+    files: BTreeMap<(u64, u64), Located>,
+}
+
+impl Extents {
+    /// Returns where on its device the byte at `offset` of the file at
+    /// `path` is stored, or the next stored byte if it is in a hole. [`None`]
+    /// is returned if that cannot be learnt.
+    pub(crate) fn locate(
+        &mut self,
+        path: impl AsRef<Path>,
+        offset: u64,
+    ) -> Option<u64> {
+        let path = path.as_ref();
+        let id = FileId::of(path)?;
+
+        let located = self
+            .files
+            .entry((id.dev, id.ino))
+            .and_modify(|located| {
+                if located.id != id {
+                    *located = Located::new(path, id);
+                }
+            })
+            .or_insert_with(|| Located::new(path, id));
+
+        if let Some(physical) =
+            physical(&located.extents, offset, located.complete)
+        {
+            return Some(physical);
+        }
+        if located.complete {
+            return None;
+        }
+
+        match fibmap(path, offset) {
+            Ok(Some(extent)) => {
+                let at = located
+                    .extents
+                    .partition_point(|other| other.logical < extent.logical);
+                located.extents.insert(at, extent);
+                Some(extent.physical + offset - extent.logical)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                log::debug!("Failed to map blocks of {:?}: {}", path, err);
+                // no point in asking again
+                located.complete = true;
+                None
+            }
+        }
+    }
+}
+
+impl Extents {
+    /// Forgets the files whose device and inode number are not in `keep`,
+    /// such as those of the maps the janitor removed.
+    pub(crate) fn retain(&mut self, keep: &HashSet<(u64, u64)>) {
+        self.files.retain(|key, _| keep.contains(key));
+    }
+}
+
+impl Located {
+    fn new(path: &Path, id: FileId) -> Self {
+        match fiemap(path).log_on_err(
+            Level::Debug,
+            format!("Failed to map extents of {:?}", path),
+        ) {
+            Ok(extents) => Self {
+                id,
+                extents,
+                complete: true,
+            },
+            Err(_) => Self {
+                id,
+                extents: vec![],
+                complete: false,
+            },
+        }
+    }
+}
+
+/// Returns where the byte at `offset` is stored according to `extents`. If
+/// they are `complete`, a byte in a hole is located by the next extent.
+fn physical(extents: &[Extent], offset: u64, complete: bool) -> Option<u64> {
+    let at = extents
+        .partition_point(|extent| extent.logical + extent.length <= offset);
+    let extent = extents.get(at)?;
+    if extent.logical <= offset {
+        Some(extent.physical + offset - extent.logical)
+    } else if complete {
+        Some(extent.physical)
+    } else {
+        None
+    }
+}
+
+/// Learns all the extents of the file at `path` with `FS_IOC_FIEMAP`.
+fn fiemap(path: &Path) -> Result<Vec<Extent>> {
+    let file = File::open(path)?;
+    let mut extents = vec![];
+    let mut start = 0;
+
+    loop {
+        let mut request = Fiemap {
+            start,
+            length: u64::MAX,
+            // no FIEMAP_FLAG_SYNC, as we must not write out files. Dirty
+            // pages are in memory anyway.
+            extent_count: BATCH as u32,
+            ..Default::default()
+        };
+
+        // SAFETY: `request` outlives the call and has room for the
+        // `extent_count` extents that the kernel may fill in.
+        Errno::result(unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                FS_IOC_FIEMAP as _,
+                &mut request as *mut Fiemap,
+            )
+        })?;
+
+        let mapped = &request.extents[..request.mapped_extents as usize];
+        extents.extend(
+            mapped
+                .iter()
+                .filter(|extent| extent.flags & FIEMAP_EXTENT_UNKNOWN == 0)
+                .map(|extent| Extent {
+                    logical: extent.logical,
+                    physical: extent.physical,
+                    length: extent.length,
+                }),
+        );
+        match mapped.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.logical + last.length
+            }
+            _ => return Ok(extents),
+        }
+    }
+}
+
+/// Learns the block holding the byte at `offset` of the file at `path` with
+/// `FIBMAP`, or [`None`] if it is in a hole.
+fn fibmap(path: &Path, offset: u64) -> Result<Option<Extent>> {
+    let file = File::open(path)?;
+
+    let mut blocksize: libc::c_int = 0;
+    // SAFETY: the kernel writes an int into `blocksize`.
+    Errno::result(unsafe {
+        libc::ioctl(file.as_raw_fd(), FIGETBSZ as _, &mut blocksize)
+    })?;
+    let blocksize = blocksize.max(1) as u64;
+
+    let mut block: libc::c_int = (offset / blocksize)
+        .try_into()
+        .context("The block is out of the range of FIBMAP")?;
+    // SAFETY: the kernel reads the logical block number from `block`, and
+    // writes the physical one into it.
+    Errno::result(unsafe {
+        libc::ioctl(file.as_raw_fd(), FIBMAP as _, &mut block)
+    })?;
+
+    if block == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Extent {
+        logical: offset - offset % blocksize,
+        physical: block as u64 * blocksize,
+        length: blocksize,
+    }))
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn offsets_are_located_within_extents() {
+        let extents = [
+            Extent {
+                logical: 0,
+                physical: 40960,
+                length: 8192,
+            },
+            Extent {
+                logical: 16384,
+                physical: 4096,
+                length: 4096,
+            },
+        ];
+
+        assert_eq!(physical(&extents, 100, true), Some(41060));
+        assert_eq!(physical(&extents, 16384 + 10, true), Some(4106));
+        // in the hole, the next extent is used if all extents are known
+        assert_eq!(physical(&extents, 8192, true), Some(4096));
+        assert_eq!(physical(&extents, 8192, false), None);
+        assert_eq!(physical(&extents, 20480, true), None);
+    }
+
+    #[test]
+    fn files_are_located_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[7; 4 * 4096]).unwrap();
+        file.sync_all().unwrap();
+
+        let mut extents = Extents::default();
+        // not every filesystem can tell, tmpfs for one.
+        if let Some(start) = extents.locate(&path, 0) {
+            assert_eq!(extents.locate(&path, 4096), Some(start + 4096));
+        }
+
+        // what is known of files that are not used anymore is forgotten
+        assert_eq!(extents.files.len(), 1);
+        extents.retain(&HashSet::new());
+        assert!(extents.files.is_empty());
+    }
+}
+// 1}}} //
@@ -0,0 +1,365 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Offline inspection of the state file.
+//!
+//! The Markov chains are stored as `msgpack` blobs, which makes the state
+//! file unreadable by hand. This module loads the state file read-only into a
+//! [`State`] and prints the requested part of it in a human readable form.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::{
+    cli::{ExeSort, Filter, MapSort, MarkovSort, View},
+    common::{kb, RcCell},
+    config, database,
+    model::Model,
+    state::{MarkovState, State},
+};
+
+impl Filter {
+    /// Whether an entry with the given path should be printed.
+    fn accepts(&self, path: &Path) -> bool {
+        match &self.filter {
+            Some(f) => path.to_string_lossy().contains(f.as_str()),
+            None => true,
+        }
+    }
+
+    /// Keep only the first `count` entries, if asked to.
+    fn truncate<T>(&self, items: &mut Vec<T>) {
+        if self.count != 0 {
+            items.truncate(self.count);
+        }
+    }
+}
+
+/// Print the part of the state file at `statefile` selected by `view`. The
+/// Markov chains are decoded with the cycle of the configuration file at
+/// `conffile`, which is not created if it does not exist.
+pub(crate) fn run(
+    statefile: impl AsRef<Path>,
+    conffile: impl AsRef<Path>,
+    view: &View,
+) -> Result<()> {
+    let conffile = conffile.as_ref();
+    let cycle = if conffile.exists() {
+        config::load_config(conffile)?.model.cycle
+    } else {
+        Model::default().cycle
+    };
+
+    let conn = database::conn_readonly(statefile)?;
+    let state = State::load_offline(cycle, &conn)?;
+
+    match view {
+        View::Exes { sort, filter } => print_exes(&state, *sort, filter),
+        View::Maps { exe, sort, filter } => {
+            print_maps(&state, exe.as_deref(), *sort, filter)?
+        }
+        View::Exemaps { exe, filter } => {
+            print_exemaps(&state, exe.as_deref(), filter)?
+        }
+        View::BadExes { filter } => print_bad_exes(&state, filter),
+        View::Markovs { exe, sort, filter } => {
+            print_markovs(&state, exe.as_deref(), *sort, filter)?
+        }
+    }
+
+    Ok(())
+}
+
+fn print_exes(state: &RcCell<State>, sort: ExeSort, filter: &Filter) {
+    let state = state.borrow();
+    let mut exes = state
+        .exes
+        .values()
+        .filter(|exe| filter.accepts(&exe.borrow().path))
+        .collect::<Vec<_>>();
+
+    match sort {
+        ExeSort::Time => {
+            exes.sort_by_key(|e| std::cmp::Reverse(e.borrow().time))
+        }
+        ExeSort::Size => {
+            exes.sort_by_key(|e| std::cmp::Reverse(e.borrow().get_size()))
+        }
+        ExeSort::Maps => {
+            exes.sort_by_key(|e| std::cmp::Reverse(e.borrow().exemaps.len()))
+        }
+        ExeSort::Path => {
+            exes.sort_by(|a, b| a.borrow().path.cmp(&b.borrow().path))
+        }
+    }
+    filter.truncate(&mut exes);
+
+    println!("preload time = {}", state.time);
+    println!("{:>10} {:>10} {:>5}  PATH", "TIME", "SIZE (kb)", "MAPS");
+    for exe in exes {
+        let exe = exe.borrow();
+        println!(
+            "{:>10} {:>10} {:>5}  {}",
+            exe.time,
+            kb(exe.get_size() as u64),
+            exe.exemaps.len(),
+            exe.path.display(),
+        );
+    }
+}
+
+fn print_maps(
+    state: &RcCell<State>,
+    exe: Option<&Path>,
+    sort: MapSort,
+    filter: &Filter,
+) -> Result<()> {
+    let state = state.borrow();
+
+    let mut maps = match exe {
+        Some(path) => state
+            .exes
+            .get(path)
+            .ok_or_else(|| anyhow::anyhow!("Unknown exe {:?}", path))?
+            .borrow()
+            .exemaps
+            .iter()
+            .map(|exemap| RcCell::clone(&exemap.map))
+            .collect::<Vec<_>>(),
+        None => state.maps.iter().cloned().collect(),
+    };
+    maps.retain(|map| filter.accepts(&map.borrow().path));
+
+    match sort {
+        MapSort::Length => {
+            maps.sort_by_key(|m| std::cmp::Reverse(m.borrow().length))
+        }
+        MapSort::Path => maps.sort(),
+    }
+    filter.truncate(&mut maps);
+
+    println!("{:>12} {:>12}  PATH", "OFFSET", "LENGTH");
+    for map in maps {
+        let map = map.borrow();
+        println!(
+            "{:>12} {:>12}  {}",
+            map.offset,
+            map.length,
+            map.path.display()
+        );
+    }
+    Ok(())
+}
+
+fn print_exemaps(
+    state: &RcCell<State>,
+    exe: Option<&Path>,
+    filter: &Filter,
+) -> Result<()> {
+    let state = state.borrow();
+
+    let mut exes = match exe {
+        Some(path) => vec![state
+            .exes
+            .get(path)
+            .ok_or_else(|| anyhow::anyhow!("Unknown exe {:?}", path))?],
+        None => state.exes.values().collect(),
+    };
+    exes.retain(|exe| filter.accepts(&exe.borrow().path));
+    filter.truncate(&mut exes);
+
+    for exe in exes {
+        let exe = exe.borrow();
+        println!("{}", exe.path.display());
+        for exemap in &exe.exemaps {
+            let map = exemap.map.borrow();
+            println!(
+                "    prob={:.4} offset={} length={}  {}",
+                exemap.prob,
+                map.offset,
+                map.length,
+                map.path.display(),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_bad_exes(state: &RcCell<State>, filter: &Filter) {
+    let state = state.borrow();
+    let mut bad_exes = state
+        .bad_exes
+        .iter()
+        .filter(|(path, _)| filter.accepts(path))
+        .collect::<Vec<_>>();
+    filter.truncate(&mut bad_exes);
+
+    println!("{:>10}  PATH", "SIZE (kb)");
+    for (path, size) in bad_exes {
+        println!("{:>10}  {}", kb(*size as u64), path.display());
+    }
+}
+
+fn print_markovs(
+    state: &RcCell<State>,
+    exe: Option<&Path>,
+    sort: MarkovSort,
+    filter: &Filter,
+) -> Result<()> {
+    let state = state.borrow();
+
+    if let Some(path) = exe {
+        anyhow::ensure!(
+            state.exes.contains_key(path),
+            "Unknown exe {:?}",
+            path
+        );
+    }
+
+    // every chain is shared by both of its exes, so only collect it from
+    // `a` to list it once.
+    let mut markovs = vec![];
+    state.exes.values().for_each(|exe| {
+        exe.borrow()
+            .markovs
+            .iter()
+            .filter(|m| &m.borrow().a.upgrade().unwrap() == exe)
+            .for_each(|m| markovs.push(RcCell::clone(m)))
+    });
+
+    let paths = |markov: &MarkovState| {
+        (
+            markov.a.upgrade().unwrap().borrow().path.clone(),
+            markov.b.upgrade().unwrap().borrow().path.clone(),
+        )
+    };
+
+    let mut markovs = markovs
+        .into_iter()
+        .filter(|markov| {
+            let (a, b) = paths(&markov.borrow());
+            let involved = match exe {
+                Some(path) => a == path || b == path,
+                None => true,
+            };
+            involved && (filter.accepts(&a) || filter.accepts(&b))
+        })
+        .map(|markov| {
+            let correlation = markov.borrow().correlation(&state);
+            (markov, correlation)
+        })
+        .collect::<Vec<_>>();
+
+    match sort {
+        MarkovSort::Correlation => {
+            markovs.sort_by(|(_, a), (_, b)| b.abs().total_cmp(&a.abs()))
+        }
+        MarkovSort::Time => {
+            markovs.sort_by_key(|(m, _)| std::cmp::Reverse(m.borrow().time))
+        }
+    }
+    filter.truncate(&mut markovs);
+
+    for (markov, correlation) in markovs {
+        let markov = markov.borrow();
+        let (a, b) = paths(&markov);
+
+        println!(
+            "correlation={:.4} time={} state={}",
+            correlation, markov.time, markov.state
+        );
+        println!("    a: {}", a.display());
+        println!("    b: {}", b.display());
+        println!(
+            "    time_to_leave: {:?}",
+            markov
+                .time_to_leave
+                .iter()
+                .map(|v| v.into_inner())
+                .collect::<Vec<_>>()
+        );
+        println!("    weight:");
+        for row in &markov.weight {
+            println!("        {:?}", row);
+        }
+    }
+    Ok(())
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{common::RcCellNew, proc::FakeSource, rules::PathRules, spy};
+
+    #[test]
+    fn every_view_is_printed() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let mut source = FakeSource::default();
+        for (pid, exe) in &[(10, "a"), (11, "b")] {
+            let lib = dir.join(format!("lib{}.so", exe));
+            std::fs::write(dir.join(exe), vec![0; 8192]).unwrap();
+            std::fs::write(&lib, vec![0; 8192]).unwrap();
+            source.spawn(
+                *pid,
+                dir.join(exe),
+                &[(lib.to_str().unwrap(), 0, 8192)],
+            );
+        }
+
+        let cycle = Model::default().cycle;
+        let rules = PathRules::from_prefixes(&[dir]);
+        let state = RcCell::new_cell(State::default());
+        for _ in 0..2 {
+            spy::scan(&mut state.borrow_mut(), &rules.exes, &source).unwrap();
+            spy::update_model(Rc::clone(&state), &rules, 0, cycle, &source)
+                .unwrap();
+            state.borrow_mut().time += cycle as i32;
+        }
+        let statefile = dir.join("rustload.state");
+        let conn = database::conn_and_migrate(&statefile).unwrap();
+        state.borrow_mut().save(&conn).unwrap();
+
+        let filter = || Filter {
+            filter: Some("a".into()),
+            count: 1,
+        };
+        let views = [
+            View::Exes {
+                sort: ExeSort::Size,
+                filter: filter(),
+            },
+            View::Maps {
+                exe: Some(dir.join("a")),
+                sort: MapSort::Path,
+                filter: filter(),
+            },
+            View::Exemaps {
+                exe: None,
+                filter: filter(),
+            },
+            View::BadExes { filter: filter() },
+            View::Markovs {
+                exe: None,
+                sort: MarkovSort::Time,
+                filter: filter(),
+            },
+        ];
+        let conffile = dir.join("rustload.conf");
+        for view in &views {
+            run(&statefile, &conffile, view).unwrap();
+        }
+        // the missing configuration is not created
+        assert!(!conffile.exists());
+
+        let unknown = View::Maps {
+            exe: Some(dir.join("c")),
+            sort: MapSort::Length,
+            filter: filter(),
+        };
+        assert!(run(&statefile, &conffile, &unknown).is_err());
+    }
+}
+// 1}}} //
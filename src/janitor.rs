@@ -0,0 +1,273 @@
+//! Pruning of the model from files that no longer exist.
+//!
+//! Package upgrades and uninstalls leave the model with maps and exes whose
+//! files are gone or replaced. Prefetching them is futile, so they are
+//! removed once in a while: when the state is loaded and when it is saved.
+//! Exes removed this way are learnt afresh the next time they run, and exes
+//! that lost maps have their maps read again the next time they start.
+
+use std::{collections::HashSet, rc::Rc};
+
+use crate::{
+    common::{FileId, RcCell},
+    state::{Exe, Map, State},
+};
+
+/// Whether `map` is stale, see [`Map::is_current`]. A current map of unknown
+/// identity adopts the one of its file.
+fn map_is_stale(map: &mut Map) -> bool {
+    if !map.is_current() {
+        return true;
+    }
+    if map.id.is_none() {
+        map.id = FileId::of(&map.path);
+        map.dirty = true;
+    }
+    false
+}
+
+/// Whether `exe` is stale, see [`Exe::is_current`]. A current exe of unknown
+/// identity adopts the one of its binary.
+fn exe_is_stale(exe: &mut Exe) -> bool {
+    if !exe.is_current() {
+        return true;
+    }
+    if exe.id.is_none() {
+        exe.id = FileId::of(&exe.path);
+        exe.dirty = true;
+    }
+    false
+}
+
+impl State {
+    /// Removes the maps whose files are gone or have been replaced, and the
+    /// exes whose binaries have been uninstalled or replaced. The exemaps of
+    /// the removed maps are dropped from every exe, and the markovs of the
+    /// removed exes from their partners. Maps that no exe uses anymore are
+    /// removed too.
+    ///
+    /// Exes that are running are left alone until they exit. Exes that lost
+    /// exemaps are marked, see [`Exe::maps_dropped`].
+    pub(crate) fn janitor(&mut self) {
+        let mut adopted_maps = vec![];
+        let stale_maps = self
+            .maps
+            .iter()
+            .filter(|map| {
+                let unknown = map.borrow().id.is_none();
+                let stale = map_is_stale(&mut map.borrow_mut());
+                if unknown && !stale {
+                    adopted_maps.push(Rc::clone(map));
+                }
+                stale
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut adopted_exes = vec![];
+        let stale_exes = self
+            .exes
+            .values()
+            .filter(|exe| {
+                if exe.borrow().is_running(self) {
+                    return false;
+                }
+                let unknown = exe.borrow().id.is_none();
+                let stale = exe_is_stale(&mut exe.borrow_mut());
+                if unknown && !stale {
+                    adopted_exes.push(Rc::clone(exe));
+                }
+                stale
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // they can be found by their identity from now on
+        adopted_maps.iter().for_each(|map| self.index_map(map));
+        adopted_exes.iter().for_each(|exe| self.index_exe(exe));
+
+        let mut markovs = 0;
+        for exe in &stale_exes {
+            markovs += exe.borrow().markovs.len();
+            self.unregister_exe(exe);
+        }
+
+        let stale = stale_maps.iter().map(Rc::as_ptr).collect::<HashSet<_>>();
+        let mut exemaps = 0;
+        for exe in self.exes.values() {
+            let mut exe = exe.borrow_mut();
+            let removed = exe.retain_exemaps(|exemap| {
+                !stale.contains(&Rc::as_ptr(&exemap.map))
+            });
+            if removed > 0 {
+                exe.maps_dropped = true;
+                exemaps += removed;
+            }
+        }
+
+        // maps of the removed exes may not be used by anyone anymore
+        let used_maps = self
+            .exes
+            .values()
+            .flat_map(|exe| {
+                exe.borrow()
+                    .exemaps
+                    .iter()
+                    .map(|exemap| Rc::as_ptr(&exemap.map))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<HashSet<_>>();
+        let unused_maps = self
+            .maps
+            .iter()
+            .filter(|map| !used_maps.contains(&Rc::as_ptr(map)))
+            .cloned()
+            .collect::<Vec<RcCell<Map>>>();
+
+        let mut maps = 0;
+        for map in stale_maps.iter().chain(&unused_maps) {
+            if self.maps.contains(map) {
+                self.unregister_map(map);
+                maps += 1;
+            }
+        }
+
+        // and so are the locations of the files no map uses anymore
+        let files = self
+            .maps
+            .iter()
+            .filter_map(|map| map.borrow().id)
+            .map(|id| (id.dev, id.ino))
+            .collect();
+        self.extents.retain(&files);
+
+        if maps + stale_exes.len() > 0 {
+            log::info!(
+                "Janitor removed {} exes ({} markovs) and {} maps ({} \
+                 exemaps).",
+                stale_exes.len(),
+                markovs,
+                maps,
+                exemaps,
+            );
+        } else {
+            log::debug!("Janitor found nothing to remove.");
+        }
+    }
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::{common::RcCellNew, proc::FakeSource, rules::PathRules, spy};
+
+    const CYCLE: u32 = 20;
+
+    #[test]
+    fn stale_files_are_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        for file in &["a", "b", "liba.so", "libb.so", "libc.so"] {
+            fs::write(dir.join(file), vec![0; 8192]).unwrap();
+        }
+        let path = |file: &str| dir.join(file).to_str().unwrap().to_owned();
+        let rules = PathRules::from_prefixes(&[dir]);
+
+        let mut source = FakeSource::default();
+        source.spawn(
+            10,
+            path("a"),
+            &[(&path("liba.so"), 0, 8192), (&path("libc.so"), 0, 8192)],
+        );
+        source.spawn(
+            11,
+            path("b"),
+            &[(&path("libb.so"), 0, 8192), (&path("libc.so"), 0, 8192)],
+        );
+
+        let state = RcCell::new_cell(State::default());
+        let run_cycle = |source: &FakeSource| {
+            spy::scan(&mut state.borrow_mut(), &rules.exes, source).unwrap();
+            spy::update_model(Rc::clone(&state), &rules, 0, CYCLE, source)
+                .unwrap();
+            state.borrow_mut().time += CYCLE as i32;
+        };
+
+        // both run once and exit
+        run_cycle(&source);
+        source.kill(10);
+        source.kill(11);
+        run_cycle(&source);
+
+        // `b` is uninstalled and `liba.so` is upgraded
+        fs::remove_file(dir.join("b")).unwrap();
+        fs::write(dir.join("liba.so.new"), vec![0; 8192]).unwrap();
+        fs::rename(dir.join("liba.so.new"), dir.join("liba.so")).unwrap();
+
+        let mut state = state.borrow_mut();
+        state.janitor();
+
+        assert_eq!(state.exes.len(), 1);
+        let a = state.exes[Path::new(&path("a"))].borrow();
+        assert!(a.markovs.is_empty());
+        assert_eq!(a.exemaps.len(), 1);
+        assert_eq!(a.get_size(), 8192);
+
+        // `libb.so` was only used by `b`
+        assert_eq!(state.maps.len(), 1);
+        assert_eq!(
+            state.maps.iter().next().unwrap().borrow().path,
+            dir.join("libc.so"),
+        );
+    }
+
+    #[test]
+    fn replaced_maps_are_learnt_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        for file in &["a", "liba.so", "libc.so"] {
+            fs::write(dir.join(file), vec![0; 8192]).unwrap();
+        }
+        let path = |file: &str| dir.join(file).to_str().unwrap().to_owned();
+        let maps =
+            [(&*path("liba.so"), 0, 8192), (&*path("libc.so"), 0, 8192)];
+        let rules = PathRules::from_prefixes(&[dir]);
+
+        let mut source = FakeSource::default();
+        source.spawn(10, path("a"), &maps);
+
+        let state = RcCell::new_cell(State::default());
+        let run_cycle = |source: &FakeSource| {
+            spy::scan(&mut state.borrow_mut(), &rules.exes, source).unwrap();
+            spy::update_model(Rc::clone(&state), &rules, 0, CYCLE, source)
+                .unwrap();
+            state.borrow_mut().time += CYCLE as i32;
+        };
+
+        run_cycle(&source);
+        source.kill(10);
+        run_cycle(&source);
+
+        // `liba.so` is upgraded, and `a` loses it
+        fs::write(dir.join("liba.so.new"), vec![0; 8192]).unwrap();
+        fs::rename(dir.join("liba.so.new"), dir.join("liba.so")).unwrap();
+        state.borrow_mut().janitor();
+        let a = Rc::clone(&state.borrow().exes[Path::new(&path("a"))]);
+        assert_eq!(a.borrow().exemaps.len(), 1);
+        assert!(a.borrow().maps_dropped);
+
+        // the next run maps the new `liba.so`
+        source.spawn(11, path("a"), &maps);
+        run_cycle(&source);
+
+        let a = a.borrow();
+        assert_eq!(a.exemaps.len(), 2);
+        assert_eq!(a.get_size(), 2 * 8192);
+        assert!(!a.maps_dropped);
+        assert_eq!(state.borrow().maps.len(), 2);
+    }
+}
+// 1}}} //
@@ -32,7 +32,13 @@ extern crate diesel;
 #[macro_use]
 extern crate derivative;
 
-use std::{env::temp_dir, path::PathBuf};
+use std::{
+    env::temp_dir,
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+};
 
 use anyhow::{Context, Result};
 use calloop::{
@@ -46,24 +52,41 @@ use daemonize::Daemonize;
 use lazy_static::lazy_static;
 use log::Level;
 
+mod acct;
 mod cli;
 mod common;
 mod config;
+mod connector;
+mod control;
 mod database;
+mod devices;
 mod event;
+mod extents;
+mod inspect;
+mod janitor;
 mod logging;
 mod model;
+mod mounts;
 mod proc;
 mod prophet;
 mod readahead;
+mod residency;
+mod rules;
 mod spy;
 mod state;
+mod stats;
+mod throttle;
+mod uring;
 
 #[doc(hidden)]
 mod schema;
 
 use common::LogResult;
+use connector::EventSource;
+use devices::Devices;
 use event::SharedData;
+use model::PrefetchBackend;
+use proc::ProcessSource;
 
 use crate::state::State;
 
@@ -72,6 +95,13 @@ lazy_static! {
     static ref PIDFILE: PathBuf = temp_dir().join("rustload.pid");
 }
 
+/// Exit status when a fatal error stopped the daemon while it was running.
+/// Errors during startup exit with `1`.
+const EXIT_FAILURE: u8 = 70;
+
+/// Exit status when the state could not be saved during shutdown.
+const EXIT_SAVE_FAILED: u8 = 74;
+
 /// Create a PID file, change the umask to `0o077` and daemonize.
 ///
 /// If daemonization fails, log it as Error and return an `anyhow::Error`
@@ -90,9 +120,14 @@ fn daemonize() -> Result<()> {
 
 /// Install signal handlers to manipulate [`State`][state::State].
 ///
-/// 1. If SIGTERM is received, shut down the daemon and exit cleanly.
+/// 1. If SIGTERM, SIGINT or SIGQUIT is received, shut down the daemon and
+///    exit cleanly. See [`shutdown`].
 /// 2. If SIGHUP is received, reload the configuration files, if this
 ///    applies.
+/// 3. If SIGUSR1 is received, dump the state and the configuration to the
+///    log.
+/// 4. If SIGUSR2 is received, save the state even if it is not dirty, and
+///    exit cleanly.
 fn set_signal_handlers(event_handle: &LoopHandle<SharedData>) -> Result<()> {
     let signals =
         Signals::new(&[SIGINT, SIGQUIT, SIGTERM, SIGHUP, SIGUSR1, SIGUSR2])
@@ -105,17 +140,7 @@ fn set_signal_handlers(event_handle: &LoopHandle<SharedData>) -> Result<()> {
             // Reload conf
             sig @ SIGHUP => {
                 log::warn!("Recieved {}, reloading configuration.", sig);
-
-                if let Ok(conf) = config::load_config(&shared.opt.conffile)
-                    .log_on_err(
-                        Level::Warn,
-                        "Failed to load configuration. \
-                        Using old configuration.",
-                    )
-                {
-                    shared.conf = conf;
-                    log::info!("Reloading config done!");
-                }
+                shared.reload_config().ok();
             }
 
             // Dump statelog and conflog
@@ -128,16 +153,8 @@ fn set_signal_handlers(event_handle: &LoopHandle<SharedData>) -> Result<()> {
             // save statefile and exit
             sig @ SIGUSR2 => {
                 log::warn!("Caught {}. Saving statefile and exiting", sig);
-                shared
-                    .state
-                    .borrow_mut()
-                    // TODO: change the stuff here
-                    .save(&shared.conn)
-                    .log_on_err(
-                        Level::Error,
-                        "Failed to write to the database",
-                    )
-                    .ok();
+                // the state is saved on shutdown only if it is dirty
+                shared.state.borrow_mut().dirty = true;
                 shared.signal.stop();
             }
 
@@ -152,11 +169,84 @@ fn set_signal_handlers(event_handle: &LoopHandle<SharedData>) -> Result<()> {
     Ok(())
 }
 
+/// Shut the daemon down after the event loop has stopped, and return the
+/// status to exit with.
+///
+/// The state is saved if it is dirty, the control socket and the PID file
+/// are removed. The waiting requests of the io_uring backend are dropped, and
+/// those in flight are waited for with [`uring::Uring::finish`], since their
+/// completions are no longer handled by the event loop. The waiting requests
+/// of the other backends are dropped too, and those in flight are left to
+/// the thread of the [`throttle::Pacer`].
+fn shutdown(shared: &mut SharedData) -> ExitCode {
+    log::info!("Shutting down.");
+
+    let mut status = match &shared.failure {
+        Some(e) => {
+            log::error!("Stopped because of an error: {:#}", e);
+            ExitCode::from(EXIT_FAILURE)
+        }
+        None => ExitCode::SUCCESS,
+    };
+
+    let dirty = shared.state.borrow().dirty;
+    if dirty {
+        let saved = shared.state.borrow_mut().save(&shared.conn);
+        match saved {
+            Ok(()) => log::info!("Saved state to {:?}", shared.opt.statefile),
+            Err(e) => {
+                log::error!(
+                    "Failed to save state to {:?}, changes since the last \
+                     save are lost: {:#}",
+                    shared.opt.statefile,
+                    e,
+                );
+                status = ExitCode::from(EXIT_SAVE_FAILED);
+            }
+        }
+    } else {
+        log::debug!("State is clean, not saving.");
+    }
+
+    // let the readahead in flight finish before exiting
+    if let Some(pacer) = &shared.pacer {
+        pacer.finish();
+    }
+    if let Some(uring) = &shared.uring {
+        uring
+            .finish()
+            .log_on_err(Level::Warn, "Failed to wait for the prefetching")
+            .ok();
+    }
+
+    control::cleanup(&shared.opt.socket);
+
+    if !shared.opt.foreground {
+        fs::remove_file(&*PIDFILE)
+            .log_on_err(Level::Warn, "Failed to remove PID file")
+            .ok();
+    }
+
+    status
+}
+
 #[doc(hidden)]
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     // Parse the CLI.
     let opt = cli::Opt::from_args();
 
+    // Inspection happens offline and must not touch the log file.
+    if let Some(cli::Command::Inspect { view }) = &opt.cmd {
+        return inspect::run(&opt.statefile, &opt.conffile, view)
+            .map(|()| ExitCode::SUCCESS);
+    }
+
+    if let Some(cli::Command::ExplainPath { path }) = &opt.cmd {
+        let conf = config::load_config(&opt.conffile)?;
+        print!("{}", conf.rules.explain(path));
+        return Ok(ExitCode::SUCCESS);
+    }
+
     // Enable logging for this app.
     crate::logging::enable_logging(&opt)
         .log_on_ok(Level::Info, "Enabled logging!")?;
@@ -170,29 +260,73 @@ fn main() -> Result<()> {
     let conn = database::conn_and_migrate(&opt.statefile)?;
 
     // load state from db
+    let source = proc::Procfs::new(&conf.system.procfsroot);
     let state = state::State::load(
         conf.model.cycle,
-        Some(&conf.system.exeprefix),
+        &conf.rules.exes,
         &conn,
+        &source,
     )?;
+    state.borrow_mut().devices = Devices::new(&conf.system.sysfsroot);
 
     let mut event_loop = EventLoop::<SharedData>::try_new()?;
     let handle = event_loop.handle();
 
     set_signal_handlers(&handle)?;
 
+    if opt.socket != Path::new("") {
+        control::listen(&opt.socket, &handle)?;
+    }
+
     // optionally daemonize
     if !opt.foreground {
         daemonize()?;
     }
 
+    // prefer the process events to listing /proc, if asked to
+    let source: Box<dyn ProcessSource> = match conf
+        .system
+        .procevents
+        .then(|| connector::listen(&handle, source.clone()))
+    {
+        Some(Ok(tracker)) => Box::new(EventSource {
+            tracker,
+            inner: source,
+        }),
+        Some(Err(e)) => {
+            log::warn!("{:#}. Falling back to listing /proc.", e);
+            Box::new(source)
+        }
+        None => Box::new(source),
+    };
+
     let signal = event_loop.get_signal();
-    let mut shared = SharedData::new(signal, state, conf, opt, conn);
+    let mut shared = SharedData::new(signal, state, conf, opt, conn, source);
+
+    // prefer io_uring to the thread pool, if asked to
+    let uring = match (shared.conf.system.prefetchbackend
+        == PrefetchBackend::IoUring as u8)
+        .then(|| uring::listen(&handle, Arc::clone(&shared.limiter)))
+    {
+        Some(Ok(uring)) => Some(uring),
+        Some(Err(e)) => {
+            log::warn!("{:#}. Falling back to the thread pool.", e);
+            None
+        }
+        None => None,
+    };
+    shared.uring = uring;
+    shared.pacer =
+        Some(throttle::listen(&handle, Arc::clone(&shared.limiter))?);
 
     State::run(handle, &mut shared)?;
 
-    event_loop.run(None, &mut shared, |_| {})?;
+    if let Err(e) = event_loop.run(None, &mut shared, |_| {}) {
+        shared.fail(e.into());
+    }
+
+    let status = shutdown(&mut shared);
 
     log::debug!("Exiting");
-    Ok(())
+    Ok(status)
 }
@@ -65,9 +65,12 @@ pub(crate) struct Model {
 // TODO: Add functions for generation of optimized defaults.
 impl Model {}
 
-/// How rustload will interact with the system.
+/// How rustload will interact with the system. The keys missing from the
+/// configuration, like those added since it was written, take their default
+/// value.
 #[derive(Derivative, Debug, Serialize, Deserialize)]
 #[derivative(Default)]
+#[serde(default)]
 pub(crate) struct System {
     /// Whether preload should monitor running processes and update its model
     /// state. Normally you do want that, that's all preload is about, but you
@@ -122,6 +125,10 @@ pub(crate) struct System {
     ///
     /// If /lib matches all of /lib, /lib64, and even /libexec if there was
     /// one. If one really meant /lib only, they should use /lib/ instead.
+    ///
+    /// The files of processes in containers and sandboxes are matched at the
+    /// path they are found at from our mount namespace, like
+    /// /var/lib/flatpak/runtime/... for the /usr of a Flatpak app.
     #[derivative(Default(value = r#"vec![
         "/opt",
         "!/usr/sbin/",
@@ -143,27 +150,174 @@ pub(crate) struct System {
     ].to_pathbuf()"#))]
     pub(crate) exeprefix: Vec<PathBuf>,
 
-    /// Maximum number of processes to use to do parallel readahead. If
-    /// equal to 0, no parallel processing is done and all readahead is
-    /// done in-process. Parallel readahead supposedly gives a better I/O
-    /// performance as it allows the kernel to batch several I/O requests
-    /// of nearby blocks.
+    /// Rules deciding which maps are tracked and prefetched, tried before
+    /// mapprefix. Each rule is an action among accept, reject,
+    /// always-prefetch and never-prefetch, followed by a pattern among
+    /// prefix:, glob:, regex: and basename:, like
+    /// `reject glob:/usr/lib/debug/**`.
+    ///
+    /// See [`rules`](crate::rules) for the details.
+    #[derivative(Default(value = "vec![]"))]
+    pub(crate) maprules: Vec<String>,
+
+    /// The syntax for this is exactly the same as for maprules. The rules
+    /// are tried before exeprefix, and apply to binary executable files. An
+    /// exe that is always or never prefetched applies this to all its maps.
+    #[derivative(Default(value = "vec![]"))]
+    pub(crate) exerules: Vec<String>,
+
+    /// Exes or files that should be prefetched whatever the prediction, such
+    /// as the fixed set of apps of a kiosk. The maps of an exe are
+    /// prefetched once it has been learnt, and the whole file before that.
+    ///
+    /// # Note
+    ///
+    /// They are prefetched at start, and then on every cycle, the parts of
+    /// them that were evicted from the page cache are prefetched again. They
+    /// are taken from the memory budget before any other map. This does not
+    /// depend on `dopredict`, nor on prediction being paused.
+    #[derivative(Default(value = "vec![]"))]
+    pub(crate) pinned: Vec<PathBuf>,
+
+    /// Maximum number of processes to use to do parallel readahead on each
+    /// device. If equal to 0, no parallel processing is done and all
+    /// readahead is done in-process. Parallel readahead supposedly gives a
+    /// better I/O performance as it allows the kernel to batch several I/O
+    /// requests of nearby blocks.
+    ///
+    /// # Note
+    ///
+    /// Rotating disks are always read one request at a time, in the sorted
+    /// order, since concurrent requests make them seek back and forth.
     #[derivative(Default(value = "30"))]
     pub(crate) processes: u32,
 
-    /// The I/O sorting strategy. Ideally this should be automatically
-    /// decided, but it's not currently.
+    /// The I/O sorting strategy. By default, it is decided for each device
+    /// from whether it is a rotating disk, an SSD or a network filesystem.
+    /// Any other value is used for all the devices.
     ///
     /// See [`SortStrategy`] for possible values.
-    #[derivative(Default(value = "SortStrategy::Block as u8"))]
+    #[derivative(Default(value = "SortStrategy::Auto as u8"))]
     pub(crate) sortstrategy: u8, // we need an enum
+
+    /// The system call used to prefetch files into the page cache. Different
+    /// filesystems respond very differently to these, so the best choice
+    /// depends on the host.
+    ///
+    /// See [`PrefetchBackend`] for possible values.
+    #[derivative(Default(value = "PrefetchBackend::Fadvise as u8"))]
+    pub(crate) prefetchbackend: u8,
+
+    /// Maximum number of kilobytes prefetched per second, so that a big
+    /// prediction does not hog the disk while the user is working.
+    /// Prefetching slows down to keep under it rather than giving up. 0
+    /// means no limit.
+    #[derivative(Default(value = "0"))]
+    pub(crate) prefetchrate: u32,
+
+    /// Maximum number of prefetch requests made per second, each one being a
+    /// range of a file. 0 means no limit.
+    #[derivative(Default(value = "0"))]
+    pub(crate) prefetchiops: u32,
+
+    /// Whether prediction should run its full course while only recording
+    /// what would be prefetched, without touching the page cache. Each
+    /// chosen map is recorded with its log-probability and the memory budget
+    /// left after it. Unlike turning dopredict off, this allows checking the
+    /// model before enabling prefetching.
+    #[derivative(Default(value = "false"))]
+    pub(crate) dryrun: bool,
+
+    /// File to append the dry-run records to, one JSON object per line.
+    /// Empty string means the records are written to the log instead. Once
+    /// the file reaches 4 MiB, it is renamed with a `.1` suffix and a new one
+    /// is started.
+    #[derivative(Default(value = "PathBuf::new()"))]
+    pub(crate) dryrunfile: PathBuf,
+
+    /// Whether process starts and exits should be learnt from the kernel's
+    /// process connector instead of listing `/proc` on every scan. Processes
+    /// are then noticed as soon as they start, even if they exit before the
+    /// next scan.
+    ///
+    /// # Note
+    ///
+    /// The connector needs root privileges. If it cannot be used, rustload
+    /// falls back to listing `/proc`. Changing this needs a restart.
+    #[derivative(Default(value = "false"))]
+    pub(crate) procevents: bool,
+
+    /// Process accounting file to learn the processes that exit between two
+    /// scans from, such as `/var/log/account/pacct`. Accounting has to be
+    /// turned on with `accton(8)` for the file to be written. Empty string
+    /// means it is not read.
+    #[derivative(Default(value = "PathBuf::new()"))]
+    pub(crate) acctfile: PathBuf,
+
+    /// Where the procfs to learn the running processes and the memory
+    /// conditions from is mounted. When running in a container, this is where
+    /// the host's `/proc` is bind mounted. Changing this needs a restart.
+    #[derivative(Default(value = r#"PathBuf::from("/proc")"#))]
+    pub(crate) procfsroot: PathBuf,
+
+    /// Where the sysfs to learn the properties of the block devices from is
+    /// mounted. When running in a container, this is where the host's `/sys`
+    /// is bind mounted. Changing this needs a restart.
+    #[derivative(Default(value = r#"PathBuf::from("/sys")"#))]
+    pub(crate) sysfsroot: PathBuf,
 }
 
 // TODO: Add functions for generation of optimized defaults.
 impl System {}
 
+/// Settings overridden for the exes matching a pattern. Each one is written
+/// as an `[[apps]]` table of the configuration, like:
+///
+/// ```toml
+/// [[apps]]
+/// exe = "basename:code"
+/// pinned = true
+/// maxbytes = 500000000
+/// ```
+#[derive(Derivative, Debug, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub(crate) struct App {
+    /// The exes this applies to. Either an absolute path, or a pattern like
+    /// those of the rules, such as `basename:code` or `glob:/opt/*/bin/*`.
+    /// Only the first app matching an exe applies.
+    ///
+    /// See [`rules`](crate::rules) for the patterns.
+    pub(crate) exe: String,
+
+    /// Overrides [`Model::minsize`] for the app.
+    pub(crate) minsize: Option<u32>,
+
+    /// Whether the maps of the app should be prefetched whenever there is
+    /// room for them, whatever the prediction, like with an always-prefetch
+    /// rule in exerules.
+    #[derivative(Default(value = "false"))]
+    pub(crate) pinned: bool,
+
+    /// Whether the app should not be tracked at all, like with a reject rule
+    /// in exerules.
+    #[derivative(Default(value = "false"))]
+    pub(crate) notrack: bool,
+
+    /// Maximum number of bytes prefetched for the app in each cycle. The
+    /// maps it shares with other apps count as well.
+    pub(crate) maxbytes: Option<u64>,
+
+    /// How the app is ranked against the others when the maps to prefetch
+    /// are picked.
+    ///
+    /// See [`Priority`] for possible values.
+    #[derivative(Default(value = "Priority::Normal as u8"))]
+    pub(crate) priority: u8,
+}
+
 /// The I/O sorting strategy.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) enum SortStrategy {
     /// No I/O sorting. Useful on Flash memory for example.
     None = 0,
@@ -178,6 +332,11 @@ pub(crate) enum SortStrategy {
     /// Sort I/O based on disk block. Most sophisticated. And useful for most
     /// Linux filesystems.
     Block = 3,
+
+    /// Group I/O by device, and sort that of each device with the strategy
+    /// suited to it: none for SSDs, path for network filesystems and block
+    /// for the others.
+    Auto = 4,
 }
 
 // For easy conversion from u8 to SortStrategy.
@@ -190,8 +349,88 @@ impl TryFrom<u8> for SortStrategy {
             1 => Self::Path,
             2 => Self::Inode,
             3 => Self::Block,
+            4 => Self::Auto,
             _ => anyhow::bail!("Invalid value for SortStrategy: {:?}", value),
         };
         Ok(strat)
     }
 }
+
+/// The mechanism used to prefetch files into the page cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PrefetchBackend {
+    /// `posix_fadvise(POSIX_FADV_WILLNEED)`. Portable, asynchronous, and
+    /// the kernel is free to ignore it.
+    Fadvise = 0,
+
+    /// The Linux specific `readahead(2)`. Blocks until the data is read.
+    /// Files on network filesystems and FUSE are advised with
+    /// [`Fadvise`](Self::Fadvise) instead.
+    Readahead = 1,
+
+    /// `mmap(2)` with `MAP_POPULATE`. Faults every page in, which also works
+    /// on filesystems that ignore readahead hints. Files on network
+    /// filesystems and FUSE are advised with [`Fadvise`](Self::Fadvise)
+    /// instead.
+    MmapPopulate = 2,
+
+    /// Do not touch the page cache, only record what would be prefetched.
+    Noop = 3,
+
+    /// `openat`, `fadvise` and `close` operations of an io_uring, whose
+    /// completions are handled by the event loop. Hundreds of small maps are
+    /// prefetched without a thread each. There is no `read` operation: it
+    /// would need a buffer as large as each request for data that is thrown
+    /// away, while `fadvise` starts the same reads into the page cache alone.
+    /// Falls back to
+    /// [`Fadvise`](Self::Fadvise) on a thread pool if io_uring is not
+    /// available. Changing to it needs a restart.
+    IoUring = 4,
+}
+
+// For easy conversion from u8 to PrefetchBackend.
+impl TryFrom<u8> for PrefetchBackend {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let backend = match value {
+            0 => Self::Fadvise,
+            1 => Self::Readahead,
+            2 => Self::MmapPopulate,
+            3 => Self::Noop,
+            4 => Self::IoUring,
+            _ => {
+                anyhow::bail!("Invalid value for PrefetchBackend: {:?}", value)
+            }
+        };
+        Ok(backend)
+    }
+}
+
+/// The priority class of an app.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Priority {
+    /// The app is less likely to be prefetched than predicted.
+    Low = 0,
+
+    /// The app is prefetched as predicted.
+    Normal = 1,
+
+    /// The app is more likely to be prefetched than predicted.
+    High = 2,
+}
+
+// For easy conversion from u8 to Priority.
+impl TryFrom<u8> for Priority {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let priority = match value {
+            0 => Self::Low,
+            1 => Self::Normal,
+            2 => Self::High,
+            _ => anyhow::bail!("Invalid value for Priority: {:?}", value),
+        };
+        Ok(priority)
+    }
+}
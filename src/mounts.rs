@@ -0,0 +1,199 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Translation of paths across mount namespaces.
+//!
+//! Processes in containers and sandboxes, like those of Flatpak, Snap, Docker
+//! or systemd's `RootDirectory=`, see their files at paths that mean
+//! something else, or nothing at all, in our mount namespace. A path seen by
+//! such a process is translated by finding the filesystem it lies on and the
+//! directory it is in within that filesystem, from the mounts of the process.
+//! That directory is then looked up in our own mounts.
+//!
+//! See `proc(5)` for the format of `/proc/<pid>/mountinfo`.
+
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// A mount, as listed in `/proc/<pid>/mountinfo`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Mount {
+    /// `major:minor` of the device of the filesystem.
+    pub(crate) dev: String,
+
+    /// Directory of the filesystem that is mounted.
+    pub(crate) root: PathBuf,
+
+    /// Where it is mounted, relative to the root directory of the process.
+    pub(crate) point: PathBuf,
+}
+
+/// The mounts seen by a process, in the order they were mounted.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Mounts(Vec<Mount>);
+
+/// Undo the octal escapes of spaces, tabs, newlines and backslashes in the
+/// paths of `mountinfo`.
+fn unescape(field: &str) -> PathBuf {
+    let mut path = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        path.push_str(&rest[..i]);
+        let code = rest.get(i + 1..i + 4);
+        match code.and_then(|c| u8::from_str_radix(c, 8).ok()) {
+            Some(c) => {
+                path.push(c as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                path.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    path.push_str(rest);
+    path.into()
+}
+
+impl Mounts {
+    /// Parse the contents of a `mountinfo` file. Malformed lines are skipped.
+    pub(crate) fn parse(mountinfo: &str) -> Self {
+        Self(
+            mountinfo
+                .lines()
+                .filter_map(|line| {
+                    let mut fields = line.split(' ').skip(2);
+                    Some(Mount {
+                        dev: fields.next()?.to_owned(),
+                        root: unescape(fields.next()?),
+                        point: unescape(fields.next()?),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Read the mounts from a `mountinfo` file at `path`.
+    pub(crate) fn read(path: &Path) -> Result<Self> {
+        let mountinfo = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        Ok(Self::parse(&mountinfo))
+    }
+
+    /// Finds the mount that `path` lies on, and returns the device of its
+    /// filesystem along with the path within that filesystem.
+    fn locate(&self, path: &Path) -> Option<(&str, PathBuf)> {
+        // a later mount at the same point hides the earlier ones
+        let mount = self
+            .0
+            .iter()
+            .filter(|m| path.starts_with(&m.point))
+            .max_by_key(|m| m.point.components().count())?;
+
+        let rest = path.strip_prefix(&mount.point).ok()?;
+        Some((&mount.dev, mount.root.join(rest)))
+    }
+
+    /// Lists the paths in our namespace where the file at `path`, as seen by
+    /// a process with the mounts `theirs`, may be. `self` are our mounts.
+    ///
+    /// A mount may be hidden by another one, so whether a candidate really is
+    /// the same file is up to the caller to check.
+    pub(crate) fn translate(
+        &self,
+        theirs: &Self,
+        path: &Path,
+    ) -> Vec<PathBuf> {
+        let (dev, fspath) = match theirs.locate(path) {
+            Some(found) => found,
+            None => return vec![],
+        };
+
+        // the most specific mount first
+        let mut candidates = self
+            .0
+            .iter()
+            .filter(|m| m.dev == dev)
+            .filter_map(|m| {
+                let rest = fspath.strip_prefix(&m.root).ok()?;
+                Some((m.root.components().count(), m.point.join(rest)))
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|&(depth, _)| Reverse(depth));
+        candidates.into_iter().map(|(_, path)| path).collect()
+    }
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mounts of the host: the root filesystem, a separate `/home`, and the
+    /// root of a Docker container.
+    const HOST: &str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+23 22 8:2 / /home rw,relatime shared:2 - ext4 /dev/sda2 rw
+24 22 0:50 / /var/lib/docker/overlay2/abc/merged rw - overlay overlay rw
+";
+
+    /// Mounts of a Flatpak app: its runtime bind mounted on `/usr` and the
+    /// home directory of the user.
+    const FLATPAK: &str = "\
+40 39 0:60 / / rw - tmpfs tmpfs rw
+41 40 8:1 /var/lib/flatpak/runtime/files /usr ro - ext4 /dev/sda1 rw
+42 40 8:2 /alice /home/alice rw - ext4 /dev/sda2 rw
+43 42 8:2 /alice/My\\040Files /home/alice/files rw - ext4 /dev/sda2 rw
+";
+
+    /// Mounts of a Docker container.
+    const DOCKER: &str = "\
+50 49 0:50 / / rw - overlay overlay rw
+";
+
+    #[test]
+    fn mountinfo_is_parsed() {
+        let mounts = Mounts::parse(FLATPAK);
+        assert_eq!(mounts.0.len(), 4);
+        assert_eq!(
+            mounts.0[3],
+            Mount {
+                dev: "8:2".into(),
+                root: "/alice/My Files".into(),
+                point: "/home/alice/files".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn paths_are_translated_to_our_namespace() {
+        let host = Mounts::parse(HOST);
+        let translate = |theirs: &str, path: &str| {
+            host.translate(&Mounts::parse(theirs), Path::new(path))
+        };
+
+        assert_eq!(
+            translate(FLATPAK, "/usr/lib/libgtk.so"),
+            [PathBuf::from(
+                "/var/lib/flatpak/runtime/files/lib/libgtk.so"
+            )]
+        );
+        assert_eq!(
+            translate(FLATPAK, "/home/alice/files/a.so"),
+            [PathBuf::from("/home/alice/My Files/a.so")]
+        );
+        assert_eq!(
+            translate(DOCKER, "/usr/bin/nginx"),
+            [PathBuf::from(
+                "/var/lib/docker/overlay2/abc/merged/usr/bin/nginx"
+            )]
+        );
+
+        // the tmpfs root of the sandbox is not mounted in our namespace
+        assert!(translate(FLATPAK, "/app.so").is_empty());
+    }
+}
+// 1}}} //
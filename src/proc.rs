@@ -1,205 +1,495 @@
 // vim:set et sw=4 ts=4 tw=79 fdm=marker:
 //! Process listing routines.
 
-use std::{collections::BTreeSet, path::Path, rc::Rc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use crate::{
-    common::{kb, LogResult, RcCell},
+    common::{kb, FileId, LogResult, RcCell},
+    mounts::Mounts,
+    rules::Rules,
     state::{ExeMap, Map, State},
 };
 use anyhow::{anyhow, Result};
 use log::Level;
 use procfs::process::MMapPath;
 
-/// Holds all information about memory conditions of the system.
+/// A file-backed memory map of a process, as listed in `/proc/<pid>/maps`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ProcMap {
+    /// Path of the mapped file.
+    pub(crate) path: PathBuf,
+
+    /// Offset of the map in the file, in bytes.
+    pub(crate) offset: u64,
+
+    /// Length of the map, in bytes.
+    pub(crate) length: u64,
+
+    /// Whether the file has been unlinked or replaced since it was mapped.
+    pub(crate) deleted: bool,
+}
+
+/// The kernel appends this to the paths of unlinked files in `/proc`.
+const DELETED_SUFFIX: &str = " (deleted)";
+
+/// Splits the `" (deleted)"` marker off a path read from `/proc`. Returns the
+/// path of the file along with whether it was marked.
+fn strip_deleted(path: PathBuf) -> (PathBuf, bool) {
+    match path.to_str().and_then(|p| p.strip_suffix(DELETED_SUFFIX)) {
+        Some(stripped) => (stripped.into(), true),
+        None => (path, false),
+    }
+}
+
+/// Source of information about the running processes and the memory
+/// conditions of the system.
 ///
-/// All memory information is represented in
-/// [**Kibibytes**](https://en.wikipedia.org/wiki/Kilobyte)
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
-pub(crate) struct MemInfo {
-    /// Total memory of the system.
-    pub(crate) total: u32,
+/// The model is trained solely from what this trait reports, which allows the
+/// training loop to run against something other than the host's `/proc`.
+pub(crate) trait ProcessSource {
+    /// Lists the running processes along with the path of their executable.
+    /// Processes whose executable cannot be read, or cannot be reached from
+    /// our mount namespace, are left out.
+    fn processes(&self) -> Result<Vec<(libc::pid_t, PathBuf)>>;
+
+    /// Reads the path of the executable of the process `pid`.
+    fn exe(&self, pid: libc::pid_t) -> Result<PathBuf>;
+
+    /// Lists the file-backed maps of the process `pid`. The maps of the files
+    /// that cannot be reached from our mount namespace are left out.
+    fn maps(&self, pid: libc::pid_t) -> Result<Vec<ProcMap>>;
+
+    /// Reads the memory conditions of the system.
+    fn meminfo(&self) -> Result<MemInfo>;
+}
 
-    /// Free memory of the system.
-    pub(crate) free: u32,
+/// [`ProcessSource`] backed by a procfs, normally the host's `/proc`.
+#[derive(Clone, Debug)]
+pub(crate) struct Procfs {
+    /// Where the procfs is mounted.
+    root: PathBuf,
+}
 
-    /// Buffer memory.
-    pub(crate) buffers: u32,
+impl Default for Procfs {
+    fn default() -> Self {
+        Self::new("/proc")
+    }
+}
 
-    /// Page-cache memory.
-    pub(crate) cached: u32,
+impl Procfs {
+    /// Reads the procfs mounted at `root`, such as the host's `/proc` bind
+    /// mounted in a container.
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
 
-    /// Total data paged (read) in since boot.
-    pub(crate) pagein: u32,
+    fn process(&self, pid: libc::pid_t) -> Result<procfs::process::Process> {
+        Ok(procfs::process::Process::new_with_root(
+            self.root.join(pid.to_string()),
+        )?)
+    }
+}
 
-    /// Total data paged (written) in since boot.
-    pub(crate) pageout: u32,
+/// Device and inode of the file at `path`, which may be a directory.
+fn dev_ino(path: &Path) -> Option<(u64, u64)> {
+    fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
 }
 
-impl MemInfo {
-    pub(crate) fn new() -> Result<Self> {
-        let mut this = Self::default();
-        this.update()?;
-        Ok(this)
+/// Resolves the paths seen by processes to paths that we can open.
+///
+/// A process with another root directory or mount namespace than ours, like
+/// one running in a container, sees its files elsewhere than we do. Such
+/// paths are translated to where the file is mounted in our namespace, so
+/// that they stay the same for every process of the container. If the file
+/// is not mounted in our namespace, it could only be reached through the root
+/// directory of the process, which is gone once the process exits, so it is
+/// left out.
+///
+/// The mounts of every namespace, and the namespace and root directory of
+/// every process, are read once, so a resolver should not outlive a listing
+/// of the processes.
+struct Resolver<'a> {
+    procfs: &'a Procfs,
+
+    /// Our mount namespace and root directory.
+    ns: Option<PathBuf>,
+    root: Option<(u64, u64)>,
+
+    /// The mount namespace and root directory of the processes.
+    views: HashMap<libc::pid_t, View>,
+
+    /// Our mounts, read when first needed.
+    mounts: Option<Option<Mounts>>,
+
+    /// The mounts of the other namespaces and root directories.
+    others: HashMap<View, Option<Mounts>>,
+}
+
+/// A mount namespace and a root directory, either of which may be unknown.
+type View = (Option<PathBuf>, Option<(u64, u64)>);
+
+impl<'a> Resolver<'a> {
+    fn new(procfs: &'a Procfs) -> Self {
+        Self {
+            procfs,
+            ns: fs::read_link(procfs.root.join("self/ns/mnt")).ok(),
+            root: dev_ino(Path::new("/")),
+            views: HashMap::new(),
+            mounts: None,
+            others: HashMap::new(),
+        }
     }
 
-    /// Updates the memory information.
-    pub(crate) fn update(&mut self) -> Result<()> {
-        let mem = procfs::Meminfo::new().log_on_err(
-            Level::Error,
-            "Failed to fetch memory info. Is /proc mounted?",
-        )?;
+    /// Resolves `path`, as seen by the process `pid`, to the same file in
+    /// our namespace. Returns [`None`] if the file is not mounted here.
+    fn resolve(&mut self, pid: libc::pid_t, path: PathBuf) -> Option<PathBuf> {
+        let dir = self.procfs.root.join(pid.to_string());
+        let (ns, root) = self
+            .views
+            .entry(pid)
+            .or_insert_with(|| {
+                (
+                    fs::read_link(dir.join("ns/mnt")).ok(),
+                    dev_ino(&dir.join("root")),
+                )
+            })
+            .clone();
+
+        // what we cannot tell is assumed to be the same as ours
+        if (ns.is_none() || ns == self.ns)
+            && (root.is_none() || root == self.root)
+        {
+            return Some(path);
+        }
+
+        let seen = dir
+            .join("root")
+            .join(path.strip_prefix("/").unwrap_or(&path));
+        let id = FileId::of(&seen)?;
+
+        let procfs = self.procfs;
+        let ours = self.mounts.get_or_insert_with(|| {
+            Mounts::read(&procfs.root.join("self/mountinfo"))
+                .log_on_err(Level::Warn, "Failed to read our mounts")
+                .ok()
+        });
+        let theirs = self
+            .others
+            .entry((ns, root))
+            .or_insert_with(|| Mounts::read(&dir.join("mountinfo")).ok());
+
+        if let (Some(ours), Some(theirs)) = (ours, theirs) {
+            for candidate in ours.translate(theirs, &path) {
+                if FileId::of(&candidate) == Some(id) {
+                    return Some(candidate);
+                }
+            }
+        }
+        log::debug!("{:?} of {} is not mounted here", path, pid);
+        None
+    }
+}
+
+impl ProcessSource for Procfs {
+    fn processes(&self) -> Result<Vec<(libc::pid_t, PathBuf)>> {
+        let procs = procfs::process::all_processes_with_root(&self.root)
+            .log_on_err(Level::Error, "Failed to get process details")?;
+
+        // an upgraded exe keeps running from its old, unlinked binary, but it
+        // is still the same application.
+        let mut resolver = Resolver::new(self);
+        Ok(procs
+            .into_iter()
+            .filter_map(|proc| {
+                let exe = strip_deleted(proc.exe().ok()?).0;
+                Some((proc.pid, resolver.resolve(proc.pid, exe)?))
+            })
+            .collect())
+    }
+
+    fn exe(&self, pid: libc::pid_t) -> Result<PathBuf> {
+        let exe = strip_deleted(self.process(pid)?.exe()?).0;
+        Resolver::new(self)
+            .resolve(pid, exe.clone())
+            .ok_or_else(|| anyhow!("{:?} of {} is not mounted here", exe, pid))
+    }
 
-        self.total = kb(mem.mem_total) as u32;
-        self.free = kb(mem.mem_free) as u32;
-        self.buffers = kb(mem.buffers) as u32;
-        self.cached = kb(mem.cached) as u32;
+    fn maps(&self, pid: libc::pid_t) -> Result<Vec<ProcMap>> {
+        let procmaps = self
+            .process(pid)
+            .log_on_err(Level::Error, "Failed to fetch process info")?
+            .maps()
+            .log_on_err(Level::Error, "Failed to fetch process map info")?;
+
+        let mut resolver = Resolver::new(self);
+        Ok(procmaps
+            .into_iter()
+            .filter_map(|procmap| match procmap.pathname {
+                // we only accept actual paths
+                MMapPath::Path(path) => {
+                    let (path, deleted) = strip_deleted(path);
+                    Some(ProcMap {
+                        path: resolver.resolve(pid, path)?,
+                        offset: procmap.offset,
+                        length: procmap.address.1 - procmap.address.0,
+                        deleted,
+                    })
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn meminfo(&self) -> Result<MemInfo> {
+        let mem = fs::File::open(self.root.join("meminfo"))
+            .map_err(procfs::ProcError::from)
+            .and_then(procfs::Meminfo::from_reader)
+            .log_on_err(
+                Level::Error,
+                format!(
+                    "Failed to fetch memory info. Is {:?} mounted?",
+                    self.root
+                ),
+            )?;
 
         let pagesize = kb(procfs::page_size()
             .log_on_err(Level::Error, "Failed to fetch pagesize value")?
             as u64) as u32;
 
-        let vm = procfs::vmstat()
+        let vm = vmstat(&self.root.join("vmstat"))
             .log_on_err(Level::Error, "Failed to fetch vmstat info")?;
 
-        self.pagein = *vm
+        let pagein = *vm
             .get("pgpgin")
             .ok_or_else(|| anyhow!("Failed to fetch vmstat.pgpgin value"))
             .log_on_err(Level::Error, "Failed to fetch vmstat.pgpgin value")?
             as u32;
 
-        self.pageout = *vm
+        let pageout = *vm
             .get("pgpgout")
             .ok_or_else(|| anyhow!("Failed to fetch vmstat.pgpgin value"))
             .log_on_err(Level::Error, "Failed to fetch vmstat.pgpgin value")?
             as u32;
 
-        self.pagein *= pagesize;
-        self.pageout *= pagesize;
+        Ok(MemInfo {
+            total: kb(mem.mem_total) as u32,
+            free: kb(mem.mem_free) as u32,
+            buffers: kb(mem.buffers) as u32,
+            cached: kb(mem.cached) as u32,
+            pagein: pagein * pagesize,
+            pageout: pageout * pagesize,
+        })
+    }
+}
 
-        Ok(())
+/// Reads the virtual memory statistics from `path`, like `/proc/vmstat`.
+fn vmstat(path: &Path) -> Result<HashMap<String, i64>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key.to_owned(), value.trim().parse().ok()?))
+        })
+        .collect())
+}
+
+/// In-memory [`ProcessSource`] whose processes are set by hand.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct FakeSource {
+    /// Running processes keyed by their pid, along with their exe and maps.
+    pub(crate) procs:
+        std::collections::BTreeMap<libc::pid_t, (PathBuf, Vec<ProcMap>)>,
+
+    /// Memory conditions to report.
+    pub(crate) mem: MemInfo,
+}
+
+#[cfg(test)]
+impl FakeSource {
+    /// Start the process `pid` running `exe` with the given `maps`, given as
+    /// `(path, offset, length)`.
+    pub(crate) fn spawn(
+        &mut self,
+        pid: libc::pid_t,
+        exe: impl Into<PathBuf>,
+        maps: &[(&str, u64, u64)],
+    ) {
+        let maps = maps
+            .iter()
+            .map(|&(path, offset, length)| ProcMap {
+                path: path.into(),
+                offset,
+                length,
+                deleted: false,
+            })
+            .collect();
+        self.procs.insert(pid, (exe.into(), maps));
+    }
+
+    /// Stop the process `pid`.
+    pub(crate) fn kill(&mut self, pid: libc::pid_t) {
+        self.procs.remove(&pid);
     }
 }
 
-/// Checks if the given file (`file`) is acceptable by comparing against a list
-/// of prefixes (`prefixes`), if provided; otherwise it recognises the file as
-/// acceptable.
-///
-/// # Steps
-///
-/// 1. If `prefixes` is [`None`], the file is acceptable.
-/// 2. If a prefix starts with `!` **AND** the `file` starts with the prefix
-///    (excluding the `!`), it is marked as unacceptable. Otherwise it is
-///    acceptable.
-///
-/// # Example
+#[cfg(test)]
+impl ProcessSource for FakeSource {
+    fn processes(&self) -> Result<Vec<(libc::pid_t, PathBuf)>> {
+        Ok(self
+            .procs
+            .iter()
+            .map(|(pid, (exe, _))| (*pid, exe.clone()))
+            .collect())
+    }
+
+    fn exe(&self, pid: libc::pid_t) -> Result<PathBuf> {
+        self.procs
+            .get(&pid)
+            .map(|(exe, _)| exe.clone())
+            .ok_or_else(|| anyhow!("No such process: {}", pid))
+    }
+
+    fn maps(&self, pid: libc::pid_t) -> Result<Vec<ProcMap>> {
+        self.procs
+            .get(&pid)
+            .map(|(_, maps)| maps.clone())
+            .ok_or_else(|| anyhow!("No such process: {}", pid))
+    }
+
+    fn meminfo(&self) -> Result<MemInfo> {
+        Ok(self.mem)
+    }
+}
+
+/// Holds all information about memory conditions of the system.
 ///
-/// ```
-/// # fn main() {
-/// let file = "/bin/ls";
-/// let prefixes = [
-///     "/sbin",
-///     "/lib",
-///     "/bin",
-/// ]
-/// assert!(accept_file(file, Some(&prefixes)));
-/// # }
-/// ```
-fn accept_file(
-    file: impl AsRef<Path>,
-    prefixes: Option<&[impl AsRef<Path>]>,
-) -> bool {
-    if let Some(prefixes) = prefixes {
-        for prefix in prefixes {
-            let mut is_accepted = true;
-            let mut prefix = &*prefix.as_ref().to_string_lossy();
-
-            if prefix.starts_with('!') {
-                prefix = &prefix[1..];
-                is_accepted = false;
-            }
+/// All memory information is represented in
+/// [**Kibibytes**](https://en.wikipedia.org/wiki/Kilobyte)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct MemInfo {
+    /// Total memory of the system.
+    pub(crate) total: u32,
 
-            if file.as_ref().starts_with(prefix) {
-                return is_accepted;
-            }
-        }
+    /// Free memory of the system.
+    pub(crate) free: u32,
+
+    /// Buffer memory.
+    pub(crate) buffers: u32,
+
+    /// Page-cache memory.
+    pub(crate) cached: u32,
+
+    /// Total data paged (read) in since boot.
+    pub(crate) pagein: u32,
+
+    /// Total data paged (written) in since boot.
+    pub(crate) pageout: u32,
+}
+
+impl MemInfo {
+    pub(crate) fn new(source: &(impl ProcessSource + ?Sized)) -> Result<Self> {
+        let mut this = Self::default();
+        this.update(source)?;
+        Ok(this)
     }
 
-    // accept if no match
-    true
+    /// Updates the memory information.
+    pub(crate) fn update(
+        &mut self,
+        source: &(impl ProcessSource + ?Sized),
+    ) -> Result<()> {
+        *self = source.meminfo()?;
+        Ok(())
+    }
 }
 
-/// TODO:
+/// Sums up the length of the maps of the process `pid` and returns it.
+///
+/// If `exemaps` is given, an [`ExeMap`] is also added to it for every map
+/// accepted by `mapprefix`. Maps already known to `state` are reused, the rest
+/// are registered with it. Maps of files that have been unlinked or replaced
+/// since they were mapped are not learnt, as the file at their path is not
+/// the one in use.
 pub(crate) fn get_maps(
     pid: libc::pid_t,
-    maps: Option<&[RcCell<Map>]>,
     mut exemaps: Option<&mut BTreeSet<ExeMap>>,
-    mapprefix: &[impl AsRef<Path>],
+    maprules: &Rules,
     state: RcCell<State>,
+    source: &(impl ProcessSource + ?Sized),
 ) -> Result<u64> {
-    let procmaps = procfs::process::Process::new(pid)
-        .log_on_err(Level::Error, "Failed to fetch process info")?
-        .maps()
-        .log_on_err(Level::Error, "Failed to fetch process map info")?;
+    let procmaps = source.maps(pid)?;
 
     let mut size = 0;
 
-    for procmap in &procmaps {
-        // we only accept actual paths
-        if let MMapPath::Path(ref path) = procmap.pathname {
-            let length = procmap.address.1 - procmap.address.0;
-            size += length;
+    for procmap in procmaps {
+        size += procmap.length;
 
-            // also check if the file is "acceptable" using "conf"
-            if !accept_file(path, Some(mapprefix)) {
+        // also check if the file is "acceptable" using "conf"
+        if !maprules.accepts(&procmap.path) {
+            continue;
+        }
+
+        // if (exemaps) { ... }
+        if let Some(ref mut exemaps) = exemaps {
+            if procmap.deleted {
                 continue;
             }
 
-            if maps != None || exemaps != None {
-                let mut newmap = Rc::clone(&Map::new(
-                    path.clone(),
-                    procmap.offset as usize,
-                    length as usize,
-                    Rc::downgrade(&state),
-                ));
-
-                // if (maps) { ... }
-                if let Some(maps) = maps {
-                    if let Some(key) = maps.iter().find(|v| v == &&newmap) {
-                        newmap = Rc::clone(key);
-                    }
-                }
-
-                // if (exemaps) { ... }
-                if let Some(ref mut exemaps) = exemaps {
-                    exemaps
-                        .insert(ExeMap::new(newmap, &mut state.borrow_mut())?);
+            let mut newmap = Map::new(
+                procmap.path,
+                procmap.offset as usize,
+                procmap.length as usize,
+                Rc::downgrade(&state),
+            );
+
+            // if (maps) { ... }
+            let known = state.borrow().maps.get(&newmap).cloned();
+            if let Some(key) = known {
+                newmap = key;
+            } else {
+                let id = FileId::of(&newmap.borrow().path);
+                // the file may be known under another path, as seen from
+                // another mount namespace
+                let same_file = id.and_then(|id| {
+                    let map = newmap.borrow();
+                    state.borrow().map_by_id(id, map.offset, map.length)
+                });
+                match same_file {
+                    Some(key) => newmap = key,
+                    None => newmap.borrow_mut().id = id,
                 }
             }
+
+            exemaps.insert(ExeMap::new(newmap, &mut state.borrow_mut())?);
         }
     }
 
     Ok(size)
 }
 
+/// Calls `func` with the pid and the exe of every running process, except for
+/// ourselves, whose exe is accepted by `exeprefix`.
 pub(crate) fn proc_foreach(
     mut func: impl FnMut(libc::pid_t, &Path),
-    exeprefix: Option<&[impl AsRef<Path>]>,
+    exerules: &Rules,
+    source: &(impl ProcessSource + ?Sized),
 ) -> Result<()> {
-    let procs = procfs::process::all_processes()
-        .log_on_err(Level::Error, "Failed to get process details")?;
-
-    for proc in procs {
-        if proc.pid == std::process::id() as i32 {
+    for (pid, exe_name) in source.processes()? {
+        if pid == std::process::id() as i32 {
             continue;
         }
 
-        if let Ok(exe_name) = proc.exe() {
-            if !accept_file(&exe_name, exeprefix) {
-                continue;
-            }
-            func(proc.pid, &exe_name);
+        if !exerules.accepts(&exe_name) {
+            continue;
         }
+        func(pid, &exe_name);
     }
 
     Ok(())
@@ -215,9 +505,176 @@ mod tests {
         let file = "/bin/ls";
         let prefixes = ["/sbin", "/lib", "/bin"];
 
-        assert!(accept_file(file, None::<&[&str]>));
-        assert!(accept_file(file, Some(&prefixes)));
-        assert!(!accept_file(file, Some(&["/sbin", "/lib", "!/bin"])));
+        assert!(Rules::default().accepts(file));
+        assert!(Rules::from_prefixes(&prefixes).accepts(file));
+        assert!(
+            !Rules::from_prefixes(&["/sbin", "/lib", "!/bin"]).accepts(file)
+        );
+    }
+
+    #[test]
+    fn deleted_marker_is_stripped() {
+        assert_eq!(
+            strip_deleted("/usr/lib/libc.so.6 (deleted)".into()),
+            (PathBuf::from("/usr/lib/libc.so.6"), true),
+        );
+        assert_eq!(
+            strip_deleted("/usr/lib/libc.so.6".into()),
+            (PathBuf::from("/usr/lib/libc.so.6"), false),
+        );
+    }
+
+    /// Lays out a procfs with the process `pid` running `exe` with one map,
+    /// and `root` as its root directory.
+    fn fake_proc(dir: &Path, pid: libc::pid_t, exe: &str, root: &Path) {
+        use std::os::unix::fs::symlink;
+
+        let proc = dir.join(pid.to_string());
+        fs::create_dir_all(&proc).unwrap();
+        fs::write(
+            proc.join("stat"),
+            format!(
+                "{} (fake) S 1 {0} {0} 0 -1 4194304 0 0 0 0 0 0 0 0 20 0 1 0 \
+                 1 4096 1 18446744073709551615 1 1 0 0 0 0 0 0 0 0 0 0 17 0 \
+                 0 0 0 0 0 0 0 0 0 0 0 0 0",
+                pid
+            ),
+        )
+        .unwrap();
+        fs::write(
+            proc.join("maps"),
+            format!("00400000-00402000 r-xp 00001000 08:02 1 {}\n", exe),
+        )
+        .unwrap();
+        symlink(exe, proc.join("exe")).unwrap();
+        symlink(root, proc.join("root")).unwrap();
+    }
+
+    /// A `meminfo` with the fields that the procfs crate requires.
+    const MEMINFO: &str = "\
+MemTotal: 1000 kB
+MemFree: 500 kB
+Buffers: 10 kB
+Cached: 100 kB
+SwapCached: 0 kB
+Active: 200 kB
+Inactive: 100 kB
+SwapTotal: 0 kB
+SwapFree: 0 kB
+Dirty: 0 kB
+Writeback: 0 kB
+Mapped: 50 kB
+Slab: 20 kB
+Committed_AS: 300 kB
+VmallocTotal: 0 kB
+VmallocUsed: 0 kB
+VmallocChunk: 0 kB
+";
+
+    #[test]
+    fn procfs_can_be_mounted_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        fake_proc(dir, 10, "/usr/bin/host", Path::new("/"));
+        fs::write(dir.join("meminfo"), MEMINFO).unwrap();
+        fs::write(dir.join("vmstat"), "pgpgin 4\npgpgout 8\n").unwrap();
+
+        let procfs = Procfs::new(dir);
+        assert_eq!(
+            procfs.processes().unwrap(),
+            [(10, "/usr/bin/host".into())]
+        );
+        assert_eq!(
+            procfs.maps(10).unwrap(),
+            [ProcMap {
+                path: "/usr/bin/host".into(),
+                offset: 0x1000,
+                length: 0x2000,
+                deleted: false,
+            }]
+        );
+
+        let mem = procfs.meminfo().unwrap();
+        assert_eq!((mem.total, mem.free, mem.cached), (1000, 500, 100));
+    }
+
+    #[test]
+    fn paths_are_translated_or_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (mounted, unmounted) =
+            (dir.join("mounted"), dir.join("unmounted"));
+        for root in [&mounted, &unmounted] {
+            fs::create_dir_all(root.join("usr/bin")).unwrap();
+            fs::write(root.join("usr/bin/guest"), "guest").unwrap();
+        }
+        fake_proc(dir, 20, "/usr/bin/guest", &mounted);
+        fake_proc(dir, 30, "/usr/bin/guest", &unmounted);
+
+        // the root directory of the first container is mounted here, that of
+        // the other one is not
+        let mount = |root: &Path, point: &Path| {
+            format!(
+                "1 0 0:1 {} {} rw - ext4 /dev/sda1 rw\n",
+                root.display(),
+                point.display()
+            )
+        };
+        fs::create_dir_all(dir.join("self")).unwrap();
+        fs::write(dir.join("self/mountinfo"), mount(&mounted, &mounted))
+            .unwrap();
+        fs::write(dir.join("20/mountinfo"), mount(&mounted, Path::new("/")))
+            .unwrap();
+        fs::write(dir.join("30/mountinfo"), mount(&unmounted, Path::new("/")))
+            .unwrap();
+
+        let procfs = Procfs::new(dir);
+        let guest = mounted.join("usr/bin/guest");
+        assert_eq!(procfs.processes().unwrap(), [(20, guest.clone())]);
+        assert_eq!(procfs.exe(20).unwrap(), guest);
+        assert!(procfs.exe(30).is_err());
+        assert_eq!(
+            procfs.maps(20).unwrap(),
+            [ProcMap {
+                path: guest,
+                offset: 0x1000,
+                length: 0x2000,
+                deleted: false,
+            }]
+        );
+        assert!(procfs.maps(30).unwrap().is_empty());
+    }
+
+    #[test]
+    fn maps_are_shared_by_identity() {
+        use crate::common::RcCellNew;
+
+        // a file seen at two paths, as from two mount namespaces
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (host, guest) = (dir.join("host.so"), dir.join("guest.so"));
+        fs::write(&host, vec![0; 8192]).unwrap();
+        fs::hard_link(&host, &guest).unwrap();
+
+        let mut source = FakeSource::default();
+        source.spawn(10, "/usr/bin/a", &[(host.to_str().unwrap(), 0, 8192)]);
+        source.spawn(20, "/usr/bin/a", &[(guest.to_str().unwrap(), 0, 8192)]);
+
+        let rules = Rules::from_prefixes(&[dir]);
+        let state = RcCell::new_cell(State::default());
+        for pid in [10, 20] {
+            let mut exemaps = BTreeSet::new();
+            get_maps(
+                pid,
+                Some(&mut exemaps),
+                &rules,
+                Rc::clone(&state),
+                &source,
+            )
+            .unwrap();
+        }
+        assert_eq!(state.borrow().maps.len(), 1);
     }
 }
 // 1}}} //
@@ -1,15 +1,32 @@
 //! Inference and prediction routines.
 // TODO: Add docs
 
-use anyhow::Result;
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    rc::{Rc, Weak},
+};
+
+use anyhow::{Context, Result};
+use log::Level;
+use serde::Serialize;
 
 use crate::{
-    common::{kb, RcCell},
-    model::SortStrategy,
-    proc, readahead,
+    common::{kb, LogResult, RcCell},
+    model::{Priority, SortStrategy},
+    proc::{self, ProcessSource},
+    readahead::{self, Prefetcher},
+    rules::{Action, PathRules, Policies},
     state::{Exe, ExeMap, Map, MarkovState, State},
 };
 
+/// Log-probability given to the maps that are always prefetched, so that they
+/// are picked before any other.
+const PINNED: f64 = f64::MIN;
+
 impl MarkovState {
     /// Computes the $P(Y \text{ runs in next period} | \text{current state})$
     /// and bids in for the $Y$. $Y$ should not be running.
@@ -114,25 +131,65 @@ impl Exe {
 }
 
 impl ExeMap {
-    // TODO: add docs
-    pub(crate) fn bid_in_maps(&mut self, exe: &Exe, state: &State) {
+    /// Adds the bid of `exe` to the map, weighted by the `priority` of its
+    /// app.
+    pub(crate) fn bid_in_maps(
+        &mut self,
+        exe: &Exe,
+        priority: Priority,
+        state: &State,
+    ) {
         // FIXME: (original author) use exemap->prob, needs some theory work.
         let mut map = self.map.borrow_mut();
         if exe.is_running(state) {
             map.lnprob = 1.0.into();
         } else {
-            map.lnprob += exe.lnprob;
+            map.lnprob += exe.lnprob * priority.weight();
+        }
+    }
+}
+
+impl Priority {
+    /// Factor of the log-probability of an exe of this priority. Raising the
+    /// probability of not being needed to a power above 1 makes it lower.
+    fn weight(self) -> f64 {
+        match self {
+            Self::Low => 0.5,
+            Self::Normal => 1.0,
+            Self::High => 2.0,
         }
     }
 }
 
+/// How the picked maps are prefetched, and the memory budget they are picked
+/// within. See [`readahead`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PrefetchConfig<'a> {
+    /// See [`System::sortstrategy`](crate::model::System::sortstrategy).
+    pub(crate) sort_strategy: SortStrategy,
+
+    /// See [`System::processes`](crate::model::System::processes).
+    pub(crate) processes: u32,
+
+    /// Percentages of the total, free and cached memory that make up the
+    /// budget. See [`Model::memtotal`](crate::model::Model::memtotal).
+    pub(crate) memtotal: i32,
+    pub(crate) memfree: i32,
+    pub(crate) memcached: i32,
+
+    /// Where the decisions are recorded instead of prefetching, if anywhere.
+    /// See [`System::dryrun`](crate::model::System::dryrun).
+    pub(crate) dryrun: Option<&'a Path>,
+}
+
 pub(crate) fn predict(
     state: &mut State,
+    rules: &PathRules,
+    pinned: &[PathBuf],
     use_correlation: bool,
-    sort_strategy: SortStrategy,
-    memtotal: i32,
-    memfree: i32,
-    memcached: i32,
+    source: &(impl ProcessSource + ?Sized),
+    prefetcher: &dyn Prefetcher,
+    config: PrefetchConfig,
 ) -> Result<()> {
     state.maps = std::mem::take(&mut state.maps)
         .into_iter()
@@ -158,15 +215,31 @@ pub(crate) fn predict(
 
         exe.borrow().prob_print(state);
 
+        // the maps of an exe that is never prefetched may still be needed by
+        // others
+        if rules.exes.action(&exe.borrow().path) == Action::NeverPrefetch {
+            return;
+        }
+        // the maps of a pinned exe are prefetched apart from the prediction
+        if pinned.contains(&exe.borrow().path) {
+            return;
+        }
+
+        let priority = rules
+            .apps
+            .matching(&exe.borrow().path)
+            .map_or(Priority::Normal, |app| app.priority);
         let exemaps = std::mem::take(&mut exe.borrow_mut().exemaps)
             .into_iter()
             .map(|mut exemap| {
-                exemap.bid_in_maps(&exe.borrow(), state);
+                exemap.bid_in_maps(&exe.borrow(), priority, state);
                 exemap
             });
         exe.borrow_mut().exemaps = exemaps.collect();
     });
 
+    apply_rules(state, rules);
+
     // prevent logic error by collecting all the values into vec...
     let mut maps_on_prob = std::mem::take(&mut state.maps)
         .into_iter()
@@ -174,30 +247,248 @@ pub(crate) fn predict(
 
     maps_on_prob.sort_unstable_by_key(|a| a.borrow().lnprob);
 
-    readahead(
-        &mut maps_on_prob,
+    // the pinned files come first, and are not prefetched twice
+    let mut candidates = pinned_maps(state, pinned);
+    let pinned_paths = candidates
+        .iter()
+        .map(|map| map.borrow().path.clone())
+        .collect::<BTreeSet<_>>();
+    candidates.extend(
+        maps_on_prob
+            .iter()
+            .filter(|map| !pinned_paths.contains(&map.borrow().path))
+            .cloned(),
+    );
+
+    let prefetched = readahead(
+        &mut candidates,
         state,
-        sort_strategy,
-        memtotal,
-        memfree,
-        memcached,
-    )?;
+        &rules.apps,
+        source,
+        prefetcher,
+        config,
+    );
 
-    // ...and then filling it back again
+    // ...and then filling it back again, even if prefetching failed
     state.maps = maps_on_prob.into_iter().collect();
 
+    prefetched
+}
+
+/// Prefetches the parts of the `pinned` exes and files that are not in the
+/// page cache, apart from any prediction. They are taken from the memory
+/// budget like the predicted maps.
+pub(crate) fn prefetch_pinned(
+    state: &mut State,
+    pinned: &[PathBuf],
+    source: &(impl ProcessSource + ?Sized),
+    prefetcher: &dyn Prefetcher,
+    config: PrefetchConfig,
+) -> Result<()> {
+    let mut maps = pinned_maps(state, pinned);
+    let apps = Policies::default();
+    readahead(&mut maps, state, &apps, source, prefetcher, config)
+}
+
+/// The maps of the `pinned` exes that have been learnt, along with maps
+/// standing for the other files whole. They rank before any predicted map.
+fn pinned_maps(state: &State, pinned: &[PathBuf]) -> Vec<RcCell<Map>> {
+    let mut maps = BTreeSet::new();
+    let mut ranges = BTreeSet::new();
+    for path in pinned {
+        match state.exes.get(path) {
+            Some(exe) if !exe.borrow().exemaps.is_empty() => {
+                for exemap in &exe.borrow().exemaps {
+                    maps.insert(Rc::clone(&exemap.map));
+                }
+            }
+            _ => {
+                let size = fs::metadata(path)
+                    .log_on_err(
+                        Level::Debug,
+                        format!("Failed to stat pinned file {:?}", path),
+                    )
+                    .map_or(0, |meta| meta.len() as usize);
+                ranges.insert((path.clone(), 0, size));
+            }
+        }
+    }
+
+    let files = ranges.into_iter().filter(|&(_, _, length)| length > 0).map(
+        |(path, offset, length)| Map::new(path, offset, length, Weak::new()),
+    );
+    maps.into_iter()
+        .chain(files)
+        .map(|map| {
+            map.borrow_mut().lnprob = PINNED.into();
+            map
+        })
+        .collect()
+}
+
+/// Overrides the predicted probabilities of the maps that are always or never
+/// prefetched, according to their rules or the rules of their exes. The rules
+/// of a map take precedence over those of its exes.
+fn apply_rules(state: &State, rules: &PathRules) {
+    for exe in state.exes.values() {
+        let exe = exe.borrow();
+        // the maps of a running exe are already in memory
+        if rules.exes.action(&exe.path) == Action::AlwaysPrefetch
+            && !exe.is_running(state)
+        {
+            for exemap in &exe.exemaps {
+                exemap.map.borrow_mut().lnprob = PINNED.into();
+            }
+        }
+    }
+
+    for map in &state.maps {
+        let mut map = map.borrow_mut();
+        match rules.maps.action(&map.path) {
+            Action::AlwaysPrefetch => map.lnprob = PINNED.into(),
+            // maps that are not worth it are never picked
+            Action::NeverPrefetch => map.lnprob = 1.0.into(),
+            Action::Accept | Action::Reject => (),
+        }
+    }
+}
+
+/// The bytes that the apps with a cap may still have prefetched, see
+/// [`App::maxbytes`](crate::model::App::maxbytes).
+struct Caps {
+    left: Vec<u64>,
+
+    /// The capped apps each map belongs to, by index in `left`.
+    apps_of: HashMap<*const RefCell<Map>, Vec<usize>>,
+}
+
+impl Caps {
+    fn new(state: &State, apps: &Policies) -> Self {
+        let mut caps = Self {
+            left: vec![],
+            apps_of: HashMap::new(),
+        };
+        for exe in state.exes.values() {
+            let exe = exe.borrow();
+            // the maps of a running exe are not prefetched for its sake
+            let maxbytes = match apps.matching(&exe.path) {
+                Some(app) if !exe.is_running(state) => app.maxbytes,
+                _ => None,
+            };
+            if let Some(maxbytes) = maxbytes {
+                for exemap in &exe.exemaps {
+                    caps.apps_of
+                        .entry(Rc::as_ptr(&exemap.map))
+                        .or_default()
+                        .push(caps.left.len());
+                }
+                caps.left.push(maxbytes);
+            }
+        }
+        caps
+    }
+
+    /// Counts the map against the caps of its apps, unless it does not fit
+    /// in one of them.
+    fn charge(&mut self, map: &RcCell<Map>, length: u64) -> bool {
+        let apps = match self.apps_of.get(&Rc::as_ptr(map)) {
+            Some(apps) => apps,
+            None => return true,
+        };
+        if apps.iter().any(|&app| self.left[app] < length) {
+            return false;
+        }
+        for &app in apps {
+            self.left[app] -= length;
+        }
+        true
+    }
+}
+
+/// A map chosen to be prefetched, as recorded in dry-run mode.
+#[derive(Debug, Serialize)]
+struct Decision {
+    /// State time at which the decision was made.
+    time: i32,
+    path: PathBuf,
+    offset: usize,
+    length: usize,
+    lnprob: f64,
+
+    /// Bytes of the map that were not in the page cache, which are the ones
+    /// prefetched.
+    missing: usize,
+
+    /// Memory budget left after prefetching this map, in kibibytes.
+    memavail: i64,
+}
+
+/// Size from which the dry-run file is rotated.
+const DRYRUN_MAX_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Moves the file at `path` to `path` with a `.1` suffix if it has grown to
+/// `max_size`, replacing the one rotated before.
+fn rotate(path: &Path, max_size: u64) -> Result<()> {
+    if matches!(fs::metadata(path), Ok(meta) if meta.len() >= max_size) {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        fs::rename(path, &rotated)
+            .with_context(|| format!("Failed to rotate {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Append the `decisions` as JSON lines to the file at `path`, or to the log
+/// if `path` is empty.
+fn record_decisions(decisions: &[Decision], path: &Path) -> Result<()> {
+    if path == Path::new("") {
+        for decision in decisions {
+            log::info!("Dry run: {}", serde_json::to_string(decision)?);
+        }
+        return Ok(());
+    }
+
+    rotate(path, DRYRUN_MAX_SIZE)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open dry-run file {:?}", path))?;
+
+    let mut lines = vec![];
+    for decision in decisions {
+        serde_json::to_writer(&mut lines, decision)?;
+        lines.push(b'\n');
+    }
+    file.write_all(&lines)?;
     Ok(())
 }
 
+/// Picks the most probable maps that fit in the memory budget and prefetches
+/// them. `maps_arr` must be sorted by increasing `lnprob`. A map is skipped if
+/// it does not fit in the cap of one of the `apps` it belongs to.
+///
+/// Only the parts of a map that are not in the page cache are prefetched and
+/// taken from the budget, so maps that are fully resident are skipped.
+///
+/// If the `config` has a dry-run file, every picked map is recorded to it
+/// along with the remaining budget.
 pub(crate) fn readahead(
     maps_arr: &mut [RcCell<Map>],
     state: &mut State,
-    sort_strategy: SortStrategy,
-    memtotal: i32,
-    memfree: i32,
-    memcached: i32,
+    apps: &Policies,
+    source: &(impl ProcessSource + ?Sized),
+    prefetcher: &dyn Prefetcher,
+    config: PrefetchConfig,
 ) -> Result<()> {
-    let memstat = proc::MemInfo::new()?;
+    let PrefetchConfig {
+        memtotal,
+        memfree,
+        memcached,
+        dryrun,
+        ..
+    } = config;
+    let memstat = proc::MemInfo::new(source)?;
 
     // memory we are allowed to use (in kilobytes)
     let mut memavail = memtotal.clamp(-100, 100) as i64
@@ -212,18 +503,79 @@ pub(crate) fn readahead(
     state.memstat = memstat;
     state.memstat_timestamp = state.time;
 
-    let mut is_available = false;
-    maps_arr.iter().for_each(|map| {
-        let map = map.borrow();
+    let mut caps = Caps::new(state, apps);
+    let mut covered = vec![];
+    let mut wasted = vec![];
+    let mut selected = vec![];
+    let mut decisions = vec![];
+    for map_rc in maps_arr.iter() {
+        let map = map_rc.borrow();
+
+        // the maps are sorted, so nothing after this one is worth it either
+        if map.lnprob >= 0.0.into() {
+            break;
+        }
 
-        if !(map.lnprob < 0.0.into()
-            && kb(map.length as u64) <= memavail as u64)
-        {
-            memavail -= kb(map.length as u64) as i64;
-            map.prob_print();
-            is_available = true;
+        // the file has been replaced since the map was learnt, so we would
+        // be fetching something else. The janitor removes it on next save.
+        if !map.is_current() {
+            log::debug!("Skipping replaced map {:?}", map.path);
+            continue;
         }
-    });
+
+        let missing = map.missing();
+        let bytes = missing.iter().map(|range| range.len()).sum::<usize>();
+        if bytes == 0 {
+            log::debug!("Skipping resident map {:?}", map.path);
+            covered.push(Rc::clone(map_rc));
+            continue;
+        }
+
+        // it was covered before, but no exe started while it was resident
+        let unused = match (map.prefetched, map.used) {
+            (Some(time), Some(used)) => used < time,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if unused {
+            wasted.push(Rc::clone(map_rc));
+        }
+        if kb(bytes as u64) as i64 > memavail {
+            break;
+        }
+
+        if !caps.charge(map_rc, bytes as u64) {
+            log::debug!("Skipping map {:?} over the cap", map.path);
+            continue;
+        }
+
+        memavail -= kb(bytes as u64) as i64;
+        map.prob_print();
+        // requests made later may be dropped by the next prediction, so the
+        // map only counts as covered once that one finds it resident
+        if !prefetcher.completes_later() {
+            covered.push(Rc::clone(map_rc));
+        }
+        if bytes == map.length {
+            selected.push(Rc::clone(map_rc));
+        } else {
+            selected.extend(missing.iter().map(|range| {
+                Map::new(&map.path, range.start, range.len(), Weak::new())
+            }));
+        }
+
+        if dryrun.is_some() {
+            decisions.push(Decision {
+                time: state.time,
+                path: map.path.clone(),
+                offset: map.offset,
+                length: map.length,
+                lnprob: *map.lnprob,
+                missing: bytes,
+                memavail,
+            });
+        }
+    }
 
     log::info!(
         "{} kb available for preloading, using {} kb of it.",
@@ -231,8 +583,25 @@ pub(crate) fn readahead(
         memavailtotal - memavail,
     );
 
-    if is_available {
-        let num_processed = readahead::readahead(maps_arr, sort_strategy)?;
+    if let Some(path) = dryrun {
+        record_decisions(&decisions, path)?;
+    } else {
+        state.count_waste(&wasted);
+        for map in covered {
+            map.borrow_mut().prefetched = Some(state.time);
+        }
+        state.stats.predicted = true;
+    }
+
+    if !selected.is_empty() {
+        let num_processed = readahead::readahead(
+            &mut selected,
+            config.sort_strategy,
+            config.processes,
+            &mut state.extents,
+            &mut state.devices,
+            prefetcher,
+        )?;
         log::debug!("Readahead {} files.", num_processed);
     } else {
         log::debug!("Nothing to readahead.");
@@ -240,3 +609,405 @@ pub(crate) fn readahead(
 
     Ok(())
 }
+
+// tests {{{1 //
+// exemaps are ordered by the path and range of their maps, which never change
+#[allow(clippy::mutable_key_type)]
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        os::unix::prelude::{AsRawFd, FileExt},
+    };
+
+    use nix::fcntl::{self, PosixFadviseAdvice};
+
+    use super::*;
+    use crate::{
+        model::{App, System},
+        proc::FakeSource,
+        readahead::{Recorder, Request},
+    };
+
+    /// Creates a file of `length` bytes at `path` that is not in the page
+    /// cache, as if it had been evicted by memory pressure. Returns whether
+    /// it could be evicted, which some filesystems, like tmpfs, do not allow.
+    #[must_use]
+    fn evicted_file(path: &Path, length: u64) -> bool {
+        // holes are never resident, so the pages are written out for real
+        let mut file = File::create(path).unwrap();
+        file.write_all(&vec![0; length as usize]).unwrap();
+        file.sync_all().unwrap();
+        fcntl::posix_fadvise(
+            file.as_raw_fd(),
+            0,
+            0,
+            PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+        )
+        .unwrap();
+
+        let whole = 0..length as usize;
+        crate::residency::missing(path, whole.clone()).unwrap() == [whole]
+    }
+
+    /// Reports that a test is skipped, as the files in `dir` cannot be
+    /// evicted from the page cache.
+    fn skip(dir: &Path) {
+        eprintln!("Skipped, as pages of files in {:?} stay resident", dir);
+    }
+
+    /// Prefetches unsorted within the free memory.
+    fn config(dryrun: Option<&Path>) -> PrefetchConfig<'_> {
+        PrefetchConfig {
+            sort_strategy: SortStrategy::None,
+            processes: 4,
+            memtotal: 0,
+            memfree: 100,
+            memcached: 0,
+            dryrun,
+        }
+    }
+
+    #[test]
+    fn readahead_respects_budget_and_records_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let mut maps = vec![];
+        for (i, &lnprob) in [-2.0, -1.0, -0.5, 0.0].iter().enumerate() {
+            let path = dir.join(format!("lib{}.so", i));
+            if !evicted_file(&path, 400 * 1024) {
+                return skip(dir);
+            }
+            let map = Map::new(path, 0, 400 * 1024, Weak::new());
+            map.borrow_mut().lnprob = lnprob.into();
+            maps.push(map);
+        }
+
+        let mut source = FakeSource::default();
+        source.mem.free = 1000;
+
+        let dryrun = dir.join("dryrun");
+
+        let recorder = Recorder::default();
+        readahead(
+            &mut maps,
+            &mut State::default(),
+            &Policies::default(),
+            &source,
+            &recorder,
+            config(Some(&dryrun)),
+        )
+        .unwrap();
+
+        // only two maps of 400 kb fit in a budget of 1000 kb
+        assert_eq!(recorder.requests.into_inner().unwrap().len(), 2);
+
+        let records = std::fs::read_to_string(&dryrun).unwrap();
+        let records = records
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["path"], dir.join("lib0.so").to_str().unwrap());
+        assert_eq!(records[0]["lnprob"], -2.0);
+        assert_eq!(records[0]["memavail"], 600);
+        assert_eq!(records[1]["memavail"], 200);
+    }
+
+    #[test]
+    fn readahead_respects_app_caps() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let mut state = State::default();
+        let mut maps = vec![];
+        let mut exemaps = BTreeSet::new();
+        for (i, &lnprob) in [-3.0, -2.0, -1.0].iter().enumerate() {
+            let path = dir.join(format!("lib{}.so", i));
+            if !evicted_file(&path, 400 * 1024) {
+                return skip(dir);
+            }
+            let map = Map::new(path, 0, 400 * 1024, Weak::new());
+            map.borrow_mut().lnprob = lnprob.into();
+            // the last map is not used by the app
+            if i < 2 {
+                let exemap = ExeMap::new(Rc::clone(&map), &mut state);
+                exemaps.insert(exemap.unwrap());
+            }
+            maps.push(map);
+        }
+        let exe = Exe::new("/usr/bin/app", false, Some(exemaps), &state);
+        state.register_exe(exe, false, 20).unwrap();
+
+        let app = App {
+            exe: "/usr/bin/app".into(),
+            maxbytes: Some(500 * 1024),
+            ..Default::default()
+        };
+        let rules = PathRules::compile(&System::default(), &[app]).unwrap();
+
+        let mut source = FakeSource::default();
+        source.mem.free = 10000;
+
+        let recorder = Recorder::default();
+        readahead(
+            &mut maps,
+            &mut state,
+            &rules.apps,
+            &source,
+            &recorder,
+            config(None),
+        )
+        .unwrap();
+
+        // the second map of the app does not fit in its cap
+        let requests = recorder.requests.into_inner().unwrap();
+        let paths = requests.iter().map(|r| &r.path).collect::<Vec<_>>();
+        assert_eq!(paths, [&dir.join("lib0.so"), &dir.join("lib2.so")]);
+    }
+
+    #[test]
+    fn evicted_pinned_files_are_prefetched_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join("kiosk");
+        if !evicted_file(&path, 3 * 4096) {
+            return skip(dir);
+        }
+
+        let recorder = Recorder::default();
+        let mut source = FakeSource::default();
+        source.mem.free = 10000;
+        let prefetch = || {
+            prefetch_pinned(
+                &mut State::default(),
+                std::slice::from_ref(&path),
+                &source,
+                &recorder,
+                config(None),
+            )
+            .unwrap();
+        };
+
+        prefetch();
+        std::fs::read(&path).unwrap();
+        prefetch();
+
+        // nothing is left to prefetch once the file is back in the cache
+        let requests = recorder.requests.into_inner().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, path);
+        assert_eq!((requests[0].offset, requests[0].length), (0, 3 * 4096));
+    }
+
+    #[test]
+    fn only_missing_pages_are_prefetched() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        let mut maps = vec![];
+        for name in &["resident.so", "partial.so"] {
+            let path = dir.join(name);
+            if !evicted_file(&path, 4 * 4096) {
+                return skip(dir);
+            }
+            let file = File::open(&path).unwrap();
+            // read a single page, without the kernel reading ahead
+            fcntl::posix_fadvise(
+                file.as_raw_fd(),
+                0,
+                0,
+                PosixFadviseAdvice::POSIX_FADV_RANDOM,
+            )
+            .unwrap();
+            let pages = if *name == "resident.so" { 0..4 } else { 1..2 };
+            for page in pages {
+                file.read_exact_at(&mut [0; 4096], page * 4096).unwrap();
+            }
+
+            let map = Map::new(path, 0, 4 * 4096, Weak::new());
+            map.borrow_mut().lnprob = (-1.0).into();
+            maps.push(map);
+        }
+
+        let mut source = FakeSource::default();
+        source.mem.free = 1000;
+        let recorder = Recorder::default();
+        readahead(
+            &mut maps,
+            &mut State::default(),
+            &Policies::default(),
+            &source,
+            &recorder,
+            config(None),
+        )
+        .unwrap();
+
+        // the resident map is skipped, the other is split around its page
+        let requests = recorder.requests.into_inner().unwrap();
+        let ranges = requests
+            .iter()
+            .map(|r| (r.path.file_name().unwrap(), r.offset, r.length))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            [
+                ("partial.so".as_ref(), 0, 4096),
+                ("partial.so".as_ref(), 2 * 4096, 2 * 4096)
+            ]
+        );
+    }
+
+    #[test]
+    fn evicted_maps_are_wasted_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join("lib.so");
+        if !evicted_file(&path, 400 * 1024) {
+            return skip(dir);
+        }
+
+        let mut state = State::default();
+        let map = Map::new(path, 0, 400 * 1024, Weak::new());
+        map.borrow_mut().lnprob = (-1.0).into();
+        map.borrow_mut().prefetched = Some(10);
+        let mut exemaps = BTreeSet::new();
+        exemaps.insert(ExeMap::new(Rc::clone(&map), &mut state).unwrap());
+        let exe = Exe::new("/usr/bin/app", false, Some(exemaps), &state);
+        state.register_exe(Rc::clone(&exe), false, 20).unwrap();
+
+        // the map no longer fits, so it is not covered again
+        let mut source = FakeSource::default();
+        source.mem.free = 100;
+        for _ in 0..2 {
+            readahead(
+                &mut [Rc::clone(&map)],
+                &mut state,
+                &Policies::default(),
+                &source,
+                &Recorder::default(),
+                config(None),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(exe.borrow().stats.waste, 1);
+        assert_eq!(state.stats.total.waste, 1);
+        assert_eq!(map.borrow().prefetched, None);
+    }
+
+    /// Records the requests, as if it made them in the background.
+    #[derive(Default)]
+    struct Background(Recorder);
+
+    impl Prefetcher for Background {
+        fn prefetch(&self, request: &Request) -> Result<()> {
+            self.0.prefetch(request)
+        }
+
+        fn completes_later(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn maps_prefetched_in_the_background_are_covered_once_resident() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let path = dir.join("lib.so");
+        if !evicted_file(&path, 4096) {
+            return skip(dir);
+        }
+
+        let map = Map::new(&path, 0, 4096, Weak::new());
+        map.borrow_mut().lnprob = (-1.0).into();
+        let mut state = State::default();
+        let mut source = FakeSource::default();
+        source.mem.free = 1000;
+        let background = Background::default();
+        let mut predict = |time| {
+            state.time = time;
+            readahead(
+                &mut [Rc::clone(&map)],
+                &mut state,
+                &Policies::default(),
+                &source,
+                &background,
+                config(None),
+            )
+            .unwrap();
+        };
+
+        // the request may still be dropped
+        predict(10);
+        assert_eq!(background.0.requests.lock().unwrap().len(), 1);
+        assert_eq!(map.borrow().prefetched, None);
+
+        std::fs::read(&path).unwrap();
+        predict(20);
+        assert_eq!(map.borrow().prefetched, Some(20));
+    }
+
+    #[test]
+    fn dry_runs_count_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let dryrun = dir.path().join("dryrun");
+
+        let mut state = State::default();
+        readahead(
+            &mut [],
+            &mut state,
+            &Policies::default(),
+            &FakeSource::default(),
+            &Recorder::default(),
+            config(Some(&dryrun)),
+        )
+        .unwrap();
+
+        assert!(!state.stats.predicted);
+    }
+
+    #[test]
+    fn maps_are_kept_when_the_dry_run_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let dryrun = dir.path().join("missing/dryrun");
+
+        let mut state = State::default();
+        let map = Map::new(dir.path().join("lib.so"), 0, 4096, Weak::new());
+        state.register_map(map).unwrap();
+
+        let rules = PathRules::compile(&System::default(), &[]).unwrap();
+        let predicted = predict(
+            &mut state,
+            &rules,
+            &[],
+            true,
+            &FakeSource::default(),
+            &Recorder::default(),
+            config(Some(&dryrun)),
+        );
+
+        assert!(predicted.is_err());
+        assert_eq!(state.maps.len(), 1);
+    }
+
+    #[test]
+    fn dry_run_files_are_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dryrun");
+        let rotated = dir.path().join("dryrun.1");
+
+        // missing and small files are left alone
+        rotate(&path, 10).unwrap();
+        fs::write(&path, "123").unwrap();
+        rotate(&path, 10).unwrap();
+        assert!(!rotated.exists());
+
+        fs::write(&path, "0123456789").unwrap();
+        rotate(&path, 10).unwrap();
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), "0123456789");
+    }
+}
+// 1}}} //
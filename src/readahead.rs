@@ -1,47 +1,260 @@
 use std::{
     cmp::Ordering,
-    fs::OpenOptions,
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
     os::unix::{
         fs::MetadataExt,
         prelude::{AsRawFd, OpenOptionsExt},
     },
     path::{Path, PathBuf},
-    sync::atomic::{self, AtomicI32},
+    ptr,
+    rc::Rc,
+    sync::{
+        atomic::{self, AtomicI32},
+        Mutex,
+    },
 };
 
 use crate::{
     common::{LogResult, RcCell},
-    model::SortStrategy,
+    devices::Devices,
+    extents::Extents,
+    model::{PrefetchBackend, SortStrategy},
     state::Map,
 };
 use anyhow::Result;
+use lazy_static::lazy_static;
 use log::Level;
-use nix::fcntl::{self, PosixFadviseAdvice};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use nix::{
+    errno::Errno,
+    fcntl::{self, PosixFadviseAdvice},
+    sys::mman::{self, MapFlags, ProtFlags},
+};
 
-impl Map {
-    /// Sets the inode number for the file by reading the metadata of the file.
-    /// If the metadata is not available, error is returned.
+/// A request to prefetch `length` bytes of the file at `path`, starting at
+/// `offset`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Request {
+    pub(crate) path: PathBuf,
+    pub(crate) offset: i64,
+    pub(crate) length: i64,
+}
+
+/// A mechanism that pulls files into the page cache.
+///
+/// Requests are carried out in parallel, and in the background, hence the
+/// implementors must be [`Send`] and [`Sync`].
+pub(crate) trait Prefetcher: Send + Sync {
+    /// Prefetch the range of the file described by `request`.
+    fn prefetch(&self, request: &Request) -> Result<()>;
+
+    /// Prefetch the range of the file described by `request`, which is on a
+    /// network filesystem or FUSE, without waiting for the data. There, every
+    /// page read in a blocking way is a round trip, so the backends that
+    /// read fall back to [`Fadvise`]. By default, the same as
+    /// [`Self::prefetch`].
+    fn prefetch_remote(&self, request: &Request) -> Result<()> {
+        self.prefetch(request)
+    }
+
+    /// Prefetch the requests of all the `queues`. By default, they are made
+    /// with [`Self::prefetch`], or [`Self::prefetch_remote`] for the remote
+    /// queues, on a thread pool, with up to `processes` of
+    /// them at a time for each queue that is not serial.
     ///
-    /// Currently `_use_inode` is not used.
-    fn set_block(&mut self, _use_inode: bool) -> Result<()> {
+    /// # Returns
+    ///
+    /// Number of requests that succeeded, or that were started if they
+    /// complete later.
+    fn prefetch_all(&self, queues: Vec<Queue>, processes: u32) -> Result<i32> {
+        schedule(queues, processes, self)
+    }
+
+    /// Whether [`Self::prefetch_all`] returns before the requests are made,
+    /// in which case those not made by the next call may be dropped. By
+    /// default, `false`.
+    fn completes_later(&self) -> bool {
+        false
+    }
+}
+
+/// Create the [`Prefetcher`] corresponding to `backend`.
+pub(crate) fn prefetcher(backend: PrefetchBackend) -> Box<dyn Prefetcher> {
+    match backend {
+        PrefetchBackend::Fadvise => Box::new(Fadvise),
+        PrefetchBackend::Readahead => Box::new(Readahead),
+        PrefetchBackend::MmapPopulate => Box::new(MmapPopulate),
+        PrefetchBackend::Noop => Box::new(Recorder::default()),
+        // without a ring, the thread pool does the work.
+        PrefetchBackend::IoUring => Box::new(Fadvise),
+    }
+}
+
+/// Opens a file in readonly mode without updating its access time, and
+/// without making it the controlling terminal of the process.
+fn open_noatime(path: impl AsRef<Path>) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NOATIME)
+        .open(path.as_ref())?)
+}
+
+/// Prefetches using `posix_fadvise` with `POSIX_FADV_WILLNEED` as the advice
+/// value. For more info on `posix_fadvise` vs `readahead`, [see this][this].
+///
+/// [this]: https://unix.stackexchange.com/q/681188
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Fadvise;
+
+impl Prefetcher for Fadvise {
+    fn prefetch(&self, request: &Request) -> Result<()> {
+        let file = open_noatime(&request.path)?;
+
+        // the raw file descriptor is alive as long as the `file` variable is
+        // in scope.
+        fcntl::posix_fadvise(
+            file.as_raw_fd(),
+            request.offset,
+            request.length,
+            PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Prefetches using the Linux specific `readahead(2)`, which blocks until the
+/// data has been read.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Readahead;
+
+impl Prefetcher for Readahead {
+    fn prefetch(&self, request: &Request) -> Result<()> {
+        let file = open_noatime(&request.path)?;
+
+        // SAFETY: the file descriptor is alive as long as `file` is.
+        Errno::result(unsafe {
+            libc::readahead(
+                file.as_raw_fd(),
+                request.offset,
+                request.length as usize,
+            )
+        })?;
+
+        Ok(())
+    }
+
+    fn prefetch_remote(&self, request: &Request) -> Result<()> {
+        Fadvise.prefetch(request)
+    }
+}
+
+/// Prefetches by mapping the file with `MAP_POPULATE`, which faults every page
+/// of the mapping in before `mmap(2)` returns.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct MmapPopulate;
+
+impl Prefetcher for MmapPopulate {
+    fn prefetch(&self, request: &Request) -> Result<()> {
+        let file = open_noatime(&request.path)?;
+
+        // the offset of a mapping must be a multiple of the page size.
+        let pagesize = procfs::page_size()? as i64;
+        let offset = request.offset - request.offset % pagesize;
+        let length = (request.length + request.offset - offset) as usize;
+
+        if length == 0 {
+            return Ok(());
+        }
+
+        // SAFETY: the mapping is private to this function and is never
+        // dereferenced.
+        unsafe {
+            let addr = mman::mmap(
+                ptr::null_mut(),
+                length,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_SHARED | MapFlags::MAP_POPULATE,
+                file.as_raw_fd(),
+                offset,
+            )?;
+            mman::munmap(addr, length)?;
+        }
+
+        Ok(())
+    }
+
+    fn prefetch_remote(&self, request: &Request) -> Result<()> {
+        Fadvise.prefetch(request)
+    }
+}
+
+/// Does not touch the page cache at all. The requests are only logged and
+/// recorded.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    pub(crate) requests: Mutex<Vec<Request>>,
+}
+
+impl Prefetcher for Recorder {
+    fn prefetch(&self, request: &Request) -> Result<()> {
+        log::debug!(
+            "Not prefetching {:?} (offset = {}, length = {})",
+            request.path,
+            request.offset,
+            request.length
+        );
+        self.requests.lock().unwrap().push(request.clone());
+        Ok(())
+    }
+}
+
+impl Map {
+    /// Sets the on-disk location of the start of the map, as found in
+    /// `extents`. The inode number of the file is used instead if
+    /// `use_inode` is set or the location cannot be learnt. If the metadata
+    /// of the file is not available, error is returned.
+    fn set_block(
+        &mut self,
+        extents: &mut Extents,
+        use_inode: bool,
+    ) -> Result<()> {
         // in case we can get block, set to 0 to not retry
         self.block = 0;
 
+        if !use_inode {
+            if let Some(physical) =
+                extents.locate(&self.path, self.offset as u64)
+            {
+                self.block = physical as i64;
+                return Ok(());
+            }
+        }
+
         let stat = self.path.metadata()?;
-        // TODO: Can we somehow use inode?
         // fall back to inode
         self.block = stat.ino() as i64;
 
         Ok(())
     }
 
+    /// Orders by path, then by offset so that the maps of a file can be
+    /// merged.
     fn path_compare(&self, other: &Self) -> Ordering {
-        self.path.cmp(&other.path)
+        self.path
+            .cmp(&other.path)
+            .then(self.offset.cmp(&other.offset))
     }
 }
 
-/// Performs readahead on files based on the map information and sort strategy.
+/// Performs readahead on files based on the map information and sort strategy,
+/// using `prefetcher` to do the actual work.
+///
+/// The requests are queued by device. Those to a rotating disk are made one
+/// at a time in order, while up to `processes` requests to each other device
+/// are made at once. If `processes` is 0, all the requests are made in turn.
+/// Files on network filesystems and FUSE are only advised, whatever the
+/// backend.
 ///
 /// # Returns
 ///
@@ -53,8 +266,15 @@ impl Map {
 pub(crate) fn readahead(
     maps: &mut [RcCell<Map>],
     sort_strategy: SortStrategy,
+    processes: u32,
+    extents: &mut Extents,
+    devices: &mut Devices,
+    prefetcher: &dyn Prefetcher,
 ) -> Result<i32> {
-    sort_maps(maps, sort_strategy)?;
+    match sort_strategy {
+        SortStrategy::Auto => sort_by_device(maps, extents, devices)?,
+        _ => sort_maps(maps, sort_strategy, extents)?,
+    }
 
     let mut path: PathBuf = Default::default();
     let mut length = 0;
@@ -71,12 +291,16 @@ pub(crate) fn readahead(
             && file.path == path
         {
             // merge requests
-            length = file.offset + file.length - offset;
+            length = length.max(file.offset + file.length - offset);
             continue;
         }
 
         if !path.as_os_str().is_empty() {
-            to_process.push((path, offset as i64, length as i64));
+            to_process.push(Request {
+                path,
+                offset: offset as i64,
+                length: length as i64,
+            });
         }
 
         path = file.path.clone();
@@ -84,68 +308,173 @@ pub(crate) fn readahead(
         length = file.length;
     }
 
-    // parallelize the readahead calls via threads. Btw, `AtomicI32` is
-    // supported only on platforms tht support atomic ops on `i32`.
-    let processed = AtomicI32::new(0);
-    to_process
-        .into_par_iter()
-        .for_each(|(path, offset, length)| {
-            process_file(&path, offset, length)
-                .log_on_err(
-                    Level::Warn,
-                    format!("Could not readahead file {:?}", path),
-                )
-                .map_or((), |_| {
-                    processed.fetch_add(1, atomic::Ordering::SeqCst);
-                });
+    // don't forget the last one
+    if !path.as_os_str().is_empty() {
+        to_process.push(Request {
+            path,
+            offset: offset as i64,
+            length: length as i64,
         });
+    }
 
-    Ok(processed.into_inner())
+    prefetcher.prefetch_all(queues(to_process, devices), processes)
 }
 
-/// Acutal workhorse of the entire program. This function opens a file in
-/// readonly mode and uses portable `posix_fadvise` to perform readahead.
-/// `POSIX_FADV_WILLNEED` is used as the advice value. For more info on
-/// `posix_fadvise` vs `readahead`, [see this][this].
-///
-/// Note that the access time of the file is not changed.
-///
-/// # Error
+/// The requests to a device, in the order they are to be made.
+#[derive(Debug, Default)]
+pub(crate) struct Queue {
+    /// Whether the requests are made one at a time.
+    pub(crate) serial: bool,
+
+    /// Whether the device is a network filesystem or FUSE.
+    pub(crate) remote: bool,
+
+    pub(crate) requests: Vec<Request>,
+}
+
+/// Queues `requests` by the device of their file, keeping their order. The
+/// requests to the files that are gone are queued apart.
+fn queues(requests: Vec<Request>, devices: &mut Devices) -> Vec<Queue> {
+    let mut by_device: BTreeMap<Option<u64>, Queue> = BTreeMap::new();
+    for request in requests {
+        let dev = request.path.metadata().ok().map(|meta| meta.dev());
+        let queue = by_device.entry(dev).or_insert_with(|| match dev {
+            Some(dev) => Queue {
+                serial: devices.is_serial(dev, &request.path),
+                remote: devices.is_remote(dev, &request.path),
+                requests: vec![],
+            },
+            None => Queue::default(),
+        });
+        queue.requests.push(request);
+    }
+    by_device.into_values().collect()
+}
+
+/// The threads that make the requests of [`schedule_until`], kept from one
+/// call to the next. The pool is built on first use, and built again only
+/// when a call needs more threads than it has.
+#[derive(Debug, Default)]
+pub(crate) struct Workers(Option<rayon::ThreadPool>);
+
+impl Workers {
+    /// A pool of at least `threads` threads.
+    fn pool(&mut self, threads: usize) -> Result<&rayon::ThreadPool> {
+        let pool = match self.0.take() {
+            Some(pool) if pool.current_num_threads() >= threads => pool,
+            _ => rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()?,
+        };
+        Ok(self.0.insert(pool))
+    }
+}
+
+lazy_static! {
+    /// The workers of the prefetchers that make their requests on the
+    /// thread of the caller.
+    static ref WORKERS: Mutex<Workers> = Mutex::default();
+}
+
+/// Makes the requests of all the `queues` at once, with up to `processes`
+/// of them at a time for each queue that is not serial.
 ///
-/// Returns error if file cannot be accessed or call to `posix_fadvise` failed.
+/// # Returns
 ///
-/// [this]: https://unix.stackexchange.com/q/681188
-#[inline]
-fn process_file(
-    path: impl AsRef<Path>,
-    offset: i64,
-    length: i64,
-) -> Result<()> {
-    // do not update the access time and don't make it the controlling terminal
-    // for the process.
-    let file = OpenOptions::new()
-        .read(true)
-        .custom_flags(libc::O_NOCTTY | libc::O_NOATIME)
-        .open(path.as_ref())?;
-
-    // the raw file descriptor is alive as long as the `file` variable is in
-    // scope.
-    // We use `posix_fadvise` instead of `readahead` because the former is
-    // portable and also provides the appropriate error message.
-    fcntl::posix_fadvise(
-        file.as_raw_fd(),
-        offset,
-        length,
-        PosixFadviseAdvice::POSIX_FADV_WILLNEED,
-    )?;
+/// Number of requests that succeeded.
+fn schedule<P: Prefetcher + ?Sized>(
+    queues: Vec<Queue>,
+    processes: u32,
+    prefetcher: &P,
+) -> Result<i32> {
+    let mut workers = WORKERS.lock().unwrap();
+    schedule_until(queues, processes, prefetcher, &mut workers, &|| false)
+}
 
-    Ok(())
+/// Like [`schedule`], but the requests are made by `workers`, and those that
+/// are not made yet are dropped once `stop` returns `true`.
+pub(crate) fn schedule_until<P: Prefetcher + ?Sized>(
+    queues: Vec<Queue>,
+    processes: u32,
+    prefetcher: &P,
+    workers: &mut Workers,
+    stop: &(dyn Fn() -> bool + Sync),
+) -> Result<i32> {
+    // Btw, `AtomicI32` is supported only on platforms tht support atomic ops
+    // on `i32`.
+    let processed = AtomicI32::new(0);
+    let prefetch = |queue: &Queue, request: &Request| {
+        let result = if queue.remote {
+            prefetcher.prefetch_remote(request)
+        } else {
+            prefetcher.prefetch(request)
+        };
+        if result
+            .log_on_err(
+                Level::Warn,
+                format!("Could not readahead file {:?}", request.path),
+            )
+            .is_ok()
+        {
+            processed.fetch_add(1, atomic::Ordering::SeqCst);
+        }
+    };
+
+    if processes == 0 {
+        for queue in &queues {
+            for request in &queue.requests {
+                if stop() {
+                    break;
+                }
+                prefetch(queue, request);
+            }
+        }
+        return Ok(processed.into_inner());
+    }
+
+    // each worker takes the next request of its queue, so that a serial
+    // queue has a single worker.
+    let limits = queues
+        .iter()
+        .map(|queue| {
+            let limit = if queue.serial { 1 } else { processes as usize };
+            limit.min(queue.requests.len())
+        })
+        .collect::<Vec<_>>();
+    let cursors = queues
+        .iter()
+        .map(|queue| (queue, Mutex::new(queue.requests.iter())))
+        .collect::<Vec<_>>();
+
+    // the requests block, so every worker gets a thread of its own.
+    let pool = workers.pool(limits.iter().sum::<usize>().max(1))?;
+    pool.scope(|scope| {
+        for ((queue, cursor), &limit) in cursors.iter().zip(&limits) {
+            for _ in 0..limit {
+                scope.spawn(move |_| {
+                    let next = || {
+                        if stop() {
+                            None
+                        } else {
+                            cursor.lock().unwrap().next()
+                        }
+                    };
+                    while let Some(request) = next() {
+                        prefetch(queue, request);
+                    }
+                });
+            }
+        }
+    });
+
+    Ok(processed.into_inner())
 }
 
 /// Sort the maps (and thus the files) according to the sort strategy.
 fn sort_maps(
     maps: &mut [RcCell<Map>],
     sort_strategy: SortStrategy,
+    extents: &mut Extents,
 ) -> Result<()> {
     match sort_strategy {
         SortStrategy::None => (),
@@ -153,16 +482,54 @@ fn sort_maps(
             maps.sort_unstable_by(|a, b| a.borrow().path_compare(&b.borrow()))
         }
         SortStrategy::Inode | SortStrategy::Block => {
-            sort_by_block_or_inode(maps, sort_strategy)?
+            sort_by_block_or_inode(maps, sort_strategy, extents)?
+        }
+        SortStrategy::Auto => {
+            unreachable!("the strategy is decided for each device")
         }
     }
 
     Ok(())
 }
 
+/// Groups the maps by the device their files are on, and sorts each group
+/// with the strategy suited to the device. The maps whose files are gone are
+/// left at the end.
+fn sort_by_device(
+    maps: &mut [RcCell<Map>],
+    extents: &mut Extents,
+    devices: &mut Devices,
+) -> Result<()> {
+    let mut by_device: BTreeMap<u64, Vec<RcCell<Map>>> = BTreeMap::new();
+    let mut gone = vec![];
+    for map in maps.iter() {
+        match map.borrow().path.metadata() {
+            Ok(meta) => by_device
+                .entry(meta.dev())
+                .or_default()
+                .push(Rc::clone(map)),
+            Err(_) => gone.push(Rc::clone(map)),
+        }
+    }
+
+    let mut sorted = Vec::with_capacity(maps.len());
+    for (dev, mut group) in by_device {
+        let path = group[0].borrow().path.clone();
+        sort_maps(&mut group, devices.sort_strategy(dev, &path), extents)?;
+        sorted.extend(group);
+    }
+    sorted.extend(gone);
+
+    maps.clone_from_slice(&sorted);
+    Ok(())
+}
+
+/// Sorts by the on-disk location of the maps, or by the inode number of their
+/// files for [`SortStrategy::Inode`], so that the disk seeks the least.
 fn sort_by_block_or_inode(
     files: &mut [RcCell<Map>],
     sort_strategy: SortStrategy,
+    extents: &mut Extents,
 ) -> Result<()> {
     let mut needs_block = false;
 
@@ -182,7 +549,7 @@ fn sort_by_block_or_inode(
             let mut file = file.borrow_mut();
 
             if file.block == -1 {
-                file.set_block(sort_strategy == SortStrategy::Inode)?;
+                file.set_block(extents, sort_strategy == SortStrategy::Inode)?;
             }
         }
     }
@@ -191,3 +558,269 @@ fn sort_by_block_or_inode(
     files.sort_unstable_by_key(|v| v.borrow().block);
     Ok(())
 }
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryFrom, io::Write, rc::Weak, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn backends_prefetch_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        File::create(&path)
+            .unwrap()
+            .write_all(&[7; 3 * 4096])
+            .unwrap();
+
+        let request = Request {
+            path: path.clone(),
+            offset: 100,
+            length: 2 * 4096,
+        };
+        for backend in 0..=3 {
+            let backend = PrefetchBackend::try_from(backend).unwrap();
+            let prefetcher = prefetcher(backend);
+            prefetcher.prefetch(&request).unwrap();
+        }
+    }
+
+    #[test]
+    fn overlapping_maps_are_merged() {
+        let mut maps = vec![
+            Map::new("/usr/lib/b.so", 0, 4096, Weak::new()),
+            Map::new("/usr/lib/a.so", 4096, 4096, Weak::new()),
+            Map::new("/usr/lib/a.so", 0, 8192 + 4096, Weak::new()),
+            Map::new("/usr/lib/a.so", 8192, 4096, Weak::new()),
+        ];
+
+        let recorder = Recorder::default();
+        let processed = readahead(
+            &mut maps,
+            SortStrategy::Path,
+            4,
+            &mut Extents::default(),
+            &mut Devices::default(),
+            &recorder,
+        )
+        .unwrap();
+
+        assert_eq!(processed, 2);
+        let mut requests = recorder.requests.into_inner().unwrap();
+        requests.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(
+            requests,
+            vec![
+                Request {
+                    path: "/usr/lib/a.so".into(),
+                    offset: 0,
+                    length: 8192 + 4096,
+                },
+                Request {
+                    path: "/usr/lib/b.so".into(),
+                    offset: 0,
+                    length: 4096,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_are_sorted_by_disk_location() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let mut maps = vec![];
+        for name in &["a.so", "b.so", "c.so"] {
+            let path = dir.join(name);
+            let mut file = File::create(&path).unwrap();
+            file.write_all(&[7; 2 * 4096]).unwrap();
+            file.sync_all().unwrap();
+            maps.push(Map::new(path, 4096, 4096, Weak::new()));
+        }
+
+        let mut extents = Extents::default();
+        sort_maps(&mut maps, SortStrategy::Block, &mut extents).unwrap();
+
+        for map in &maps {
+            let map = map.borrow();
+            // either the location on disk or the inode number.
+            let expected = extents.locate(&map.path, 4096).map_or_else(
+                || map.path.metadata().unwrap().ino() as i64,
+                |physical| physical as i64,
+            );
+            assert_eq!(map.block, expected);
+        }
+        assert!(maps
+            .windows(2)
+            .all(|pair| pair[0].borrow().block <= pair[1].borrow().block));
+    }
+
+    #[test]
+    fn maps_of_gone_files_are_sorted_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let mut maps =
+            vec![Map::new(dir.join("gone.so"), 0, 4096, Weak::new())];
+        for name in &["b.so", "a.so"] {
+            let path = dir.join(name);
+            std::fs::write(&path, [7; 4096]).unwrap();
+            maps.push(Map::new(path, 0, 4096, Weak::new()));
+        }
+
+        let mut devices = Devices::default();
+        sort_by_device(&mut maps, &mut Extents::default(), &mut devices)
+            .unwrap();
+
+        assert_eq!(maps.len(), 3);
+        assert_eq!(maps[2].borrow().path, dir.join("gone.so"));
+    }
+
+    /// Records the order of the requests, and the most of them that were
+    /// made at once to each directory.
+    #[derive(Default)]
+    struct Gauge {
+        order: Mutex<Vec<PathBuf>>,
+        running: Mutex<BTreeMap<PathBuf, usize>>,
+        most: Mutex<BTreeMap<PathBuf, usize>>,
+    }
+
+    impl Prefetcher for Gauge {
+        fn prefetch(&self, request: &Request) -> Result<()> {
+            let dir = request.path.parent().unwrap().to_owned();
+            {
+                let mut running = self.running.lock().unwrap();
+                let now = running.entry(dir.clone()).or_default();
+                *now += 1;
+                let mut most = self.most.lock().unwrap();
+                let most = most.entry(dir.clone()).or_default();
+                *most = (*most).max(*now);
+            }
+            self.order.lock().unwrap().push(request.path.clone());
+
+            std::thread::sleep(Duration::from_millis(20));
+            *self.running.lock().unwrap().get_mut(&dir).unwrap() -= 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rotating_disks_are_read_in_order_and_others_in_parallel() {
+        let requests = |dir: &str| -> Vec<Request> {
+            (0..6)
+                .map(|i| Request {
+                    path: format!("/{}/{}", dir, i).into(),
+                    offset: 0,
+                    length: 4096,
+                })
+                .collect()
+        };
+        let queues = || {
+            vec![
+                Queue {
+                    serial: true,
+                    remote: false,
+                    requests: requests("hdd"),
+                },
+                Queue {
+                    serial: false,
+                    remote: false,
+                    requests: requests("ssd"),
+                },
+            ]
+        };
+
+        let gauge = Gauge::default();
+        assert_eq!(schedule(queues(), 3, &gauge).unwrap(), 12);
+
+        let most = gauge.most.into_inner().unwrap();
+        assert_eq!(most[Path::new("/hdd")], 1);
+        assert!((2..=3).contains(&most[Path::new("/ssd")]));
+        let hdd = gauge
+            .order
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .filter(|path| path.starts_with("/hdd"))
+            .collect::<Vec<_>>();
+        let expected = requests("hdd").into_iter().map(|request| request.path);
+        assert!(hdd.into_iter().eq(expected));
+
+        // everything in turn
+        let gauge = Gauge::default();
+        assert_eq!(schedule(queues(), 0, &gauge).unwrap(), 12);
+        assert!(gauge
+            .most
+            .into_inner()
+            .unwrap()
+            .values()
+            .all(|&most| most == 1));
+        let expected = queues()
+            .into_iter()
+            .flat_map(|queue| queue.requests)
+            .map(|request| request.path);
+        assert!(gauge.order.into_inner().unwrap().into_iter().eq(expected));
+    }
+
+    #[test]
+    fn workers_are_kept_until_more_are_needed() {
+        let mut workers = Workers::default();
+        let first = workers.pool(4).unwrap() as *const _;
+        assert_eq!(workers.pool(2).unwrap() as *const _, first);
+
+        let pool = workers.pool(6).unwrap();
+        assert_eq!(pool.current_num_threads(), 6);
+    }
+
+    /// Records the requests made with [`Prefetcher::prefetch_remote`] apart.
+    #[derive(Default)]
+    struct Remote {
+        local: Recorder,
+        remote: Recorder,
+    }
+
+    impl Prefetcher for Remote {
+        fn prefetch(&self, request: &Request) -> Result<()> {
+            self.local.prefetch(request)
+        }
+
+        fn prefetch_remote(&self, request: &Request) -> Result<()> {
+            self.remote.prefetch(request)
+        }
+    }
+
+    #[test]
+    fn remote_queues_are_prefetched_apart() {
+        let request = |path: &str| Request {
+            path: path.into(),
+            offset: 0,
+            length: 4096,
+        };
+        let queues = || {
+            vec![
+                Queue {
+                    serial: false,
+                    remote: true,
+                    requests: vec![request("/nfs/a"), request("/nfs/b")],
+                },
+                Queue {
+                    serial: true,
+                    remote: false,
+                    requests: vec![request("/hdd/a")],
+                },
+            ]
+        };
+
+        for processes in [0, 2] {
+            let prefetcher = Remote::default();
+            assert_eq!(schedule(queues(), processes, &prefetcher).unwrap(), 3);
+            let local = prefetcher.local.requests.into_inner().unwrap();
+            let mut remote = prefetcher.remote.requests.into_inner().unwrap();
+            remote.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+            assert_eq!(local, vec![request("/hdd/a")]);
+            assert_eq!(remote, vec![request("/nfs/a"), request("/nfs/b")]);
+        }
+    }
+}
+// 1}}} //
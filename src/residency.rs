@@ -0,0 +1,127 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Page cache residency of files.
+//!
+//! The pages of a file that are in the page cache are learnt with
+//! `mincore(2)` on a read-only mapping of the file, which is never touched,
+//! so that learning it does not fault any page in.
+
+use std::{fs::File, ops::Range, os::unix::prelude::AsRawFd, path::Path, ptr};
+
+use anyhow::{Context, Result};
+use log::Level;
+use nix::{
+    errno::Errno,
+    sys::mman::{self, MapFlags, ProtFlags},
+};
+
+use crate::{common::LogResult, state::Map};
+
+/// Returns the ranges of bytes within `range` of the file at `path` that are
+/// not in the page cache, in order. The part of `range` past the end of the
+/// file is ignored.
+pub(crate) fn missing(
+    path: impl AsRef<Path>,
+    range: Range<usize>,
+) -> Result<Vec<Range<usize>>> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    let size = file.metadata()?.len() as usize;
+    let end = range.end.min(size);
+    if range.start >= end {
+        return Ok(vec![]);
+    }
+
+    // the offset of a mapping must be a multiple of the page size.
+    let pagesize = procfs::page_size()? as usize;
+    let base = range.start - range.start % pagesize;
+    let length = end - base;
+    let mut residency = vec![0; length.div_ceil(pagesize)];
+
+    // SAFETY: the mapping is private to this function and is never
+    // dereferenced. `residency` has a byte for each page of the mapping.
+    unsafe {
+        let addr = mman::mmap(
+            ptr::null_mut(),
+            length,
+            ProtFlags::PROT_READ,
+            MapFlags::MAP_SHARED,
+            file.as_raw_fd(),
+            base as i64,
+        )?;
+        let probed =
+            Errno::result(libc::mincore(addr, length, residency.as_mut_ptr()));
+        mman::munmap(addr, length)?;
+        probed?;
+    }
+
+    Ok(missing_pages(&residency, base, pagesize, range.start..end))
+}
+
+impl Map {
+    /// The ranges of the map that are not in the page cache. The whole map is
+    /// assumed to be missing if that cannot be learnt.
+    pub(crate) fn missing(&self) -> Vec<Range<usize>> {
+        let range = self.offset..self.offset + self.length;
+        missing(&self.path, range.clone())
+            .log_on_err(
+                Level::Debug,
+                format!("Failed to probe residency of {:?}", self.path),
+            )
+            .unwrap_or_else(|_| vec![range])
+    }
+}
+
+/// Turns the `residency` of the pages starting at `base`, as filled by
+/// `mincore(2)`, into the ranges of bytes within `range` that are not
+/// resident.
+fn missing_pages(
+    residency: &[u8],
+    base: usize,
+    pagesize: usize,
+    range: Range<usize>,
+) -> Vec<Range<usize>> {
+    let mut missing: Vec<Range<usize>> = vec![];
+    for (i, page) in residency.iter().enumerate() {
+        if page & 1 != 0 {
+            continue;
+        }
+        let start = (base + i * pagesize).max(range.start);
+        let end = (base + (i + 1) * pagesize).min(range.end);
+        match missing.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => missing.push(start..end),
+        }
+    }
+    missing
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_pages_are_merged_into_ranges() {
+        // pages 0, 2 and 3 are not resident
+        let residency = [0, 1, 0, 0, 1];
+        assert_eq!(
+            missing_pages(&residency, 4096, 4096, 4196..4 * 4096 + 4196),
+            [4196..8192, 12288..20480]
+        );
+        assert!(missing_pages(&[1, 1], 0, 4096, 0..8192).is_empty());
+    }
+
+    #[test]
+    fn resident_files_miss_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, vec![7; 3 * 4096]).unwrap();
+        // just written, so it is in the page cache
+        std::fs::read(&path).unwrap();
+
+        assert!(missing(&path, 0..usize::MAX).unwrap().is_empty());
+        assert!(missing(&path, 5 * 4096..6 * 4096).unwrap().is_empty());
+    }
+}
+// 1}}} //
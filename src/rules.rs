@@ -0,0 +1,483 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Rules deciding which exes and maps are tracked and prefetched.
+//!
+//! A rule is written as an action followed by a pattern, like
+//! `reject glob:/usr/lib/debug/**` or `always-prefetch basename:code`. The
+//! actions are:
+//!
+//! - `accept`: the file is tracked and prefetched as predicted.
+//! - `reject`: the file is not tracked at all.
+//! - `always-prefetch`: the file is tracked, and prefetched whenever there is
+//!   room for it, whatever the prediction.
+//! - `never-prefetch`: the file is tracked, so that the model learns from it,
+//!   but it is never prefetched.
+//!
+//! The patterns are:
+//!
+//! - `prefix:<path>`: the path starts with `<path>`, like the prefix lists.
+//! - `glob:<glob>`: the whole path matches the shell pattern `<glob>`. `*`
+//!   does not match `/`, while `**` does.
+//! - `regex:<regex>`: the path matches the regular expression `<regex>`
+//!   somewhere, unless anchored.
+//! - `basename:<glob>`: the file name matches the shell pattern `<glob>`.
+//!
+//! Rules are tried in order, and the first one to match decides. The prefix
+//! lists come after the rules, with `<path>` read as `accept prefix:<path>`
+//! and `!<path>` as `reject prefix:<path>`. A file that matches nothing is
+//! accepted.
+//!
+//! The apps of the configuration come before all of them for exes: a pinned
+//! app is read as an `always-prefetch` rule, and an app that is not tracked
+//! as a `reject` rule.
+
+use std::{
+    convert::TryFrom,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
+
+use crate::model::{App, Priority, System};
+
+/// What to do with a file matched by a rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Action {
+    Accept,
+    Reject,
+    AlwaysPrefetch,
+    NeverPrefetch,
+}
+
+impl TryFrom<&str> for Action {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        Ok(match value {
+            "accept" => Self::Accept,
+            "reject" => Self::Reject,
+            "always-prefetch" => Self::AlwaysPrefetch,
+            "never-prefetch" => Self::NeverPrefetch,
+            _ => bail!("Unknown action {:?}", value),
+        })
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Accept => "accept",
+            Self::Reject => "reject",
+            Self::AlwaysPrefetch => "always-prefetch",
+            Self::NeverPrefetch => "never-prefetch",
+        })
+    }
+}
+
+/// What a rule matches paths against.
+#[derive(Debug)]
+enum Matcher {
+    Path(PathBuf),
+    Prefix(PathBuf),
+    Glob(Pattern),
+    Regex(Regex),
+    Basename(Pattern),
+}
+
+/// `*` should stay within a path component, like in the shell.
+const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl Matcher {
+    fn parse(pattern: &str) -> Result<Self> {
+        let (kind, pattern) = pattern
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Missing pattern kind in {:?}", pattern))?;
+        Ok(match kind {
+            "prefix" => Self::Prefix(pattern.into()),
+            "glob" => Self::Glob(Pattern::new(pattern)?),
+            "regex" => Self::Regex(Regex::new(pattern)?),
+            "basename" => Self::Basename(Pattern::new(pattern)?),
+            _ => bail!("Unknown pattern kind {:?}", kind),
+        })
+    }
+
+    /// Parse the exe of an app, which may be an absolute path as well.
+    fn parse_exe(exe: &str) -> Result<Self> {
+        if exe.starts_with('/') {
+            Ok(Self::Path(exe.into()))
+        } else {
+            Self::parse(exe)
+        }
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            Self::Path(exact) => path == exact,
+            // string prefix, so that /lib matches /lib64 as well
+            Self::Prefix(prefix) => path
+                .to_string_lossy()
+                .starts_with(&*prefix.to_string_lossy()),
+            Self::Glob(pattern) => {
+                pattern.matches_path_with(path, GLOB_OPTIONS)
+            }
+            Self::Regex(regex) => regex.is_match(&path.to_string_lossy()),
+            Self::Basename(pattern) => match path.file_name() {
+                Some(name) => pattern.matches_path(Path::new(name)),
+                None => false,
+            },
+        }
+    }
+}
+
+/// A single rule, along with where it was written.
+#[derive(Debug)]
+pub(crate) struct Rule {
+    pub(crate) action: Action,
+    matcher: Matcher,
+
+    /// The rule as written in the configuration, and the setting it comes
+    /// from.
+    pub(crate) text: String,
+    pub(crate) origin: String,
+}
+
+impl Rule {
+    /// Parse a rule written as `<action> <kind>:<pattern>`.
+    pub(crate) fn parse(
+        rule: &str,
+        origin: impl Into<String>,
+    ) -> Result<Self> {
+        let (action, pattern) = rule
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("Missing pattern in rule {:?}", rule))?;
+        Ok(Self {
+            action: Action::try_from(action)?,
+            matcher: Matcher::parse(pattern.trim_start())
+                .with_context(|| format!("Invalid rule {:?}", rule))?,
+            text: rule.to_owned(),
+            origin: origin.into(),
+        })
+    }
+
+    /// Build the rule equivalent to an entry of a prefix list.
+    fn from_prefix(prefix: &Path, origin: impl Into<String>) -> Self {
+        let text = prefix.to_string_lossy();
+        let (action, prefix) = match text.strip_prefix('!') {
+            Some(prefix) => (Action::Reject, prefix),
+            None => (Action::Accept, &*text),
+        };
+        Self {
+            action,
+            matcher: Matcher::Prefix(prefix.into()),
+            text: text.clone().into_owned(),
+            origin: origin.into(),
+        }
+    }
+}
+
+/// An ordered list of rules. An empty list accepts everything.
+#[derive(Debug, Default)]
+pub(crate) struct Rules(Vec<Rule>);
+
+impl Rules {
+    /// Compile the `rules` of the setting `name`, followed by the compatible
+    /// prefix list of the setting `prefixes_name`.
+    pub(crate) fn compile(
+        name: &str,
+        rules: &[String],
+        prefixes_name: &str,
+        prefixes: &[impl AsRef<Path>],
+    ) -> Result<Self> {
+        let mut this = rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| Rule::parse(rule, format!("{}[{}]", name, i)))
+            .collect::<Result<Vec<_>>>()?;
+        this.extend(prefixes.iter().enumerate().map(|(i, prefix)| {
+            Rule::from_prefix(
+                prefix.as_ref(),
+                format!("{}[{}]", prefixes_name, i),
+            )
+        }));
+        Ok(Self(this))
+    }
+
+    /// Rules made of a prefix list alone.
+    pub(crate) fn from_prefixes(prefixes: &[impl AsRef<Path>]) -> Self {
+        Self(
+            prefixes
+                .iter()
+                .map(|prefix| Rule::from_prefix(prefix.as_ref(), "prefix"))
+                .collect(),
+        )
+    }
+
+    /// The rule that decides for `path`, if any.
+    pub(crate) fn matching(&self, path: &Path) -> Option<&Rule> {
+        self.0.iter().find(|rule| rule.matcher.matches(path))
+    }
+
+    /// What to do with `path`.
+    pub(crate) fn action(&self, path: impl AsRef<Path>) -> Action {
+        self.matching(path.as_ref())
+            .map_or(Action::Accept, |rule| rule.action)
+    }
+
+    /// Whether `path` should be tracked at all.
+    pub(crate) fn accepts(&self, path: impl AsRef<Path>) -> bool {
+        self.action(path) != Action::Reject
+    }
+}
+
+/// The settings an app overrides, see [`App`].
+#[derive(Debug)]
+pub(crate) struct Policy {
+    matcher: Matcher,
+    pub(crate) minsize: Option<u64>,
+    pub(crate) maxbytes: Option<u64>,
+    pub(crate) priority: Priority,
+
+    /// The exe of the app as written in the configuration, and the entry of
+    /// the apps it comes from.
+    pub(crate) text: String,
+    pub(crate) origin: String,
+}
+
+/// The apps of the configuration, in order.
+#[derive(Debug, Default)]
+pub(crate) struct Policies(Vec<Policy>);
+
+impl Policies {
+    /// Compile the `apps`, along with the exe rules they stand for.
+    fn compile(apps: &[App]) -> Result<(Self, Vec<Rule>)> {
+        let mut policies = vec![];
+        let mut rules = vec![];
+        for (i, app) in apps.iter().enumerate() {
+            let origin = format!("apps[{}]", i);
+            let context = || format!("Invalid {} {:?}", origin, app.exe);
+
+            let action = match (app.pinned, app.notrack) {
+                (true, true) => {
+                    bail!("{} cannot be both pinned and not tracked", origin)
+                }
+                (true, false) => Some(Action::AlwaysPrefetch),
+                (false, true) => Some(Action::Reject),
+                (false, false) => None,
+            };
+            if let Some(action) = action {
+                rules.push(Rule {
+                    action,
+                    matcher: Matcher::parse_exe(&app.exe)
+                        .with_context(context)?,
+                    text: app.exe.clone(),
+                    origin: origin.clone(),
+                });
+            }
+
+            policies.push(Policy {
+                matcher: Matcher::parse_exe(&app.exe).with_context(context)?,
+                minsize: app.minsize.map(u64::from),
+                maxbytes: app.maxbytes,
+                priority: Priority::try_from(app.priority)
+                    .with_context(context)?,
+                text: app.exe.clone(),
+                origin,
+            });
+        }
+        Ok((Self(policies), rules))
+    }
+
+    /// The app that the exe at `path` belongs to, if any.
+    pub(crate) fn matching(&self, path: &Path) -> Option<&Policy> {
+        self.0.iter().find(|policy| policy.matcher.matches(path))
+    }
+}
+
+/// The compiled rules of the configuration.
+#[derive(Debug, Default)]
+pub(crate) struct PathRules {
+    pub(crate) exes: Rules,
+    pub(crate) maps: Rules,
+    pub(crate) apps: Policies,
+}
+
+impl PathRules {
+    pub(crate) fn compile(system: &System, apps: &[App]) -> Result<Self> {
+        let (apps, mut exes) = Policies::compile(apps)?;
+        exes.extend(
+            Rules::compile(
+                "exerules",
+                &system.exerules,
+                "exeprefix",
+                &system.exeprefix,
+            )?
+            .0,
+        );
+        Ok(Self {
+            exes: Rules(exes),
+            maps: Rules::compile(
+                "maprules",
+                &system.maprules,
+                "mapprefix",
+                &system.mapprefix,
+            )?,
+            apps,
+        })
+    }
+
+    /// Rules made of the same prefix list for both exes and maps.
+    pub(crate) fn from_prefixes(prefixes: &[impl AsRef<Path>]) -> Self {
+        Self {
+            exes: Rules::from_prefixes(prefixes),
+            maps: Rules::from_prefixes(prefixes),
+            apps: Policies::default(),
+        }
+    }
+
+    /// Tell what is done with `path` and why, both as an exe and as a map.
+    pub(crate) fn explain(&self, path: &Path) -> String {
+        let explain = |kind: &str, rules: &Rules| match rules.matching(path) {
+            Some(rule) => format!(
+                "As {}: {}, by {} = {:?}\n",
+                kind, rule.action, rule.origin, rule.text
+            ),
+            None => format!("As {}: accept, as no rule matches\n", kind),
+        };
+        let mut explained =
+            explain("an exe", &self.exes) + &explain("a map", &self.maps);
+        if let Some(policy) = self.apps.matching(path) {
+            explained += &format!(
+                "As an app: settings of {} = {:?}\n",
+                policy.origin, policy.text
+            );
+        }
+        explained
+    }
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = Rules::compile(
+            "maprules",
+            &[
+                "never-prefetch regex:\\.log$".into(),
+                "reject glob:/usr/lib/debug/**".into(),
+                "always-prefetch basename:libgtk-*.so*".into(),
+                "accept glob:/home/*/bin/*".into(),
+            ],
+            "mapprefix",
+            &["/usr/", "!/"],
+        )
+        .unwrap();
+
+        let action = |path: &str| rules.action(path);
+        assert_eq!(action("/usr/share/app.log"), Action::NeverPrefetch);
+        assert_eq!(action("/usr/lib/debug/a/b.debug"), Action::Reject);
+        assert_eq!(action("/usr/lib/libgtk-3.so.0"), Action::AlwaysPrefetch);
+        assert_eq!(action("/home/user/bin/tool"), Action::Accept);
+        // `*` does not cross directories
+        assert_eq!(action("/home/user/bin/sub/tool"), Action::Reject);
+        assert_eq!(action("/usr/lib/libc.so.6"), Action::Accept);
+
+        let rule = rules.matching(Path::new("/opt/app")).unwrap();
+        assert_eq!(rule.origin, "mapprefix[1]");
+        assert_eq!(rule.text, "!/");
+    }
+
+    #[test]
+    fn matching_rules_are_explained() {
+        let mut system = System {
+            exerules: vec!["always-prefetch basename:code".into()],
+            ..Default::default()
+        };
+        let rules = PathRules::compile(&system, &[]).unwrap();
+
+        assert_eq!(
+            rules.explain(Path::new("/usr/bin/code")),
+            "As an exe: always-prefetch, by exerules[0] = \
+             \"always-prefetch basename:code\"\n\
+             As a map: accept, by mapprefix[3] = \"/usr/\"\n"
+        );
+        system.mapprefix.clear();
+        let rules = PathRules::compile(&system, &[]).unwrap();
+        assert!(rules
+            .explain(Path::new("/usr/lib/libc.so"))
+            .ends_with("As a map: accept, as no rule matches\n"));
+    }
+
+    #[test]
+    fn invalid_rules_are_refused() {
+        for rule in
+            &["accept", "keep prefix:/", "accept foo:/", "reject regex:("]
+        {
+            assert!(Rule::parse(rule, "").is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn apps_come_before_exe_rules() {
+        let system = System {
+            exerules: vec!["reject basename:code".into()],
+            ..Default::default()
+        };
+        let apps = [
+            App {
+                exe: "/usr/bin/code".into(),
+                pinned: true,
+                maxbytes: Some(1 << 20),
+                ..Default::default()
+            },
+            App {
+                exe: "basename:tail-*".into(),
+                notrack: true,
+                ..Default::default()
+            },
+            App {
+                exe: "glob:/usr/bin/*".into(),
+                minsize: Some(0),
+                priority: Priority::High as u8,
+                ..Default::default()
+            },
+        ];
+        let rules = PathRules::compile(&system, &apps).unwrap();
+
+        let action = |path: &str| rules.exes.action(path);
+        assert_eq!(action("/usr/bin/code"), Action::AlwaysPrefetch);
+        assert_eq!(action("/opt/code"), Action::Reject);
+        assert_eq!(action("/usr/bin/tail-logs"), Action::Reject);
+        assert_eq!(action("/usr/bin/vim"), Action::Accept);
+
+        let app = |path: &str| rules.apps.matching(Path::new(path));
+        assert_eq!(app("/usr/bin/code").unwrap().maxbytes, Some(1 << 20));
+        let vim = app("/usr/bin/vim").unwrap();
+        assert_eq!(vim.origin, "apps[2]");
+        assert_eq!(vim.minsize, Some(0));
+        assert_eq!(vim.priority, Priority::High);
+        assert!(app("/opt/code").is_none());
+
+        assert!(rules.explain(Path::new("/usr/bin/code")).ends_with(
+            "As an app: settings of apps[0] = \"/usr/bin/code\"\n"
+        ));
+
+        let invalid = App {
+            exe: "/usr/bin/code".into(),
+            pinned: true,
+            notrack: true,
+            ..Default::default()
+        };
+        assert!(PathRules::compile(&system, &[invalid]).is_err());
+    }
+}
+// 1}}} //
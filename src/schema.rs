@@ -22,6 +22,10 @@ table! {
         update_time -> Integer,
         time -> Integer,
         uri -> Text,
+        dev -> Nullable<BigInt>,
+        ino -> Nullable<BigInt>,
+        size -> Nullable<BigInt>,
+        mtime -> Nullable<BigInt>,
     }
 }
 
@@ -33,6 +37,10 @@ table! {
         offset -> Integer,
         length -> BigInt,
         uri -> Text,
+        dev -> Nullable<BigInt>,
+        ino -> Nullable<BigInt>,
+        size -> Nullable<BigInt>,
+        mtime -> Nullable<BigInt>,
     }
 }
 
@@ -47,6 +55,17 @@ table! {
     }
 }
 
+table! {
+    prefetchstats (id) {
+        id -> BigInt,
+        day -> BigInt,
+        exe_seq -> Integer,
+        hits -> Integer,
+        misses -> Integer,
+        waste -> Integer,
+    }
+}
+
 table! {
     states (id) {
         id -> BigInt,
@@ -61,5 +80,6 @@ allow_tables_to_appear_in_same_query!(
     exes,
     maps,
     markovstates,
+    prefetchstats,
     states,
 );
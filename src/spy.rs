@@ -1,10 +1,17 @@
-use std::{collections::BTreeSet, path::Path, rc::Rc};
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::Path,
+    rc::Rc,
+};
 
 use anyhow::Result;
+use log::Level;
 
 use crate::{
-    common::RcCell,
-    proc,
+    common::{FileId, LogResult, RcCell},
+    connector::{ProcEvent, Tracker},
+    proc::{self, ProcessSource},
+    rules::{PathRules, Rules},
     state::{Exe, ExeMap, MarkovState, State},
 };
 
@@ -16,16 +23,32 @@ impl State {
     ) {
         let path = path.as_ref();
 
-        if let Some(exe) = self.exes.get(path) {
+        let exe = match self.exes.get(path) {
+            Some(exe) => Some(Rc::clone(exe)),
+            None if self.bad_exes.contains_key(path) => return,
+            // the binary may be known under another path, as seen from
+            // another mount namespace or through a hard link
+            None => FileId::of(path).and_then(|id| self.exe_by_id(id)),
+        };
+
+        if let Some(exe) = exe {
             // has the exe been running already?
             if !exe.borrow().is_running(self) {
-                self.new_running_exes.push(Rc::clone(exe));
-                self.state_changed_exes.push(Rc::clone(exe));
+                let since = exe.borrow().running_timestamp;
+                self.count_prefetches(&exe, since);
+                self.new_running_exes.push(Rc::clone(&exe));
+                self.state_changed_exes.push(Rc::clone(&exe));
+
+                // a fresh process maps the files that replaced dropped ones
+                if exe.borrow().maps_dropped {
+                    let path = exe.borrow().path.clone();
+                    self.relearn_exes.insert(path, pid);
+                }
             }
 
             // update timestamp
             exe.borrow_mut().running_timestamp = self.time;
-        } else if self.bad_exes.get(path) == None {
+        } else {
             // we have never seen the exe before
             self.new_exes.insert(path.to_owned(), pid);
         }
@@ -49,35 +72,46 @@ impl State {
         this: RcCell<Self>,
         path: impl AsRef<Path>,
         pid: libc::pid_t,
-        mapprefix: &[impl AsRef<Path>],
+        rules: &PathRules,
         minsize: u64,
         cycle: u32,
+        source: &(impl ProcessSource + ?Sized),
     ) -> Result<()> {
         let path = path.as_ref();
+        let maprules = &rules.maps;
+        let minsize = rules
+            .apps
+            .matching(path)
+            .and_then(|app| app.minsize)
+            .unwrap_or(minsize);
+
+        // another path of the binary may have been learnt since the scan
+        let id = FileId::of(path);
+        if id.and_then(|id| this.borrow().exe_by_id(id)).is_some() {
+            return Ok(());
+        }
+
         let mut size =
-            proc::get_maps(pid, None, None, mapprefix, Rc::clone(&this))?;
+            proc::get_maps(pid, None, maprules, Rc::clone(&this), source)?;
         let want_it = size >= minsize;
 
         if want_it {
             let mut exemaps: BTreeSet<ExeMap> = Default::default();
 
-            let maps = std::mem::take(&mut this.borrow_mut().maps)
-                .into_iter()
-                .collect::<Vec<_>>();
             size = proc::get_maps(
                 pid,
-                Some(&maps),
                 Some(&mut exemaps),
-                mapprefix,
+                maprules,
                 Rc::clone(&this),
+                source,
             )?;
-            this.borrow_mut().maps = maps.into_iter().collect();
 
             // TODO: Should this return an error? Since the original code
             // uses this as a cleanup point.
             anyhow::ensure!(size != 0, "The process died");
 
             let exe = Exe::new(path, true, Some(exemaps), &this.borrow());
+            exe.borrow_mut().id = id;
             {
                 let mut this = this.borrow_mut();
                 this.register_exe(Rc::clone(&exe), true, cycle)?;
@@ -93,6 +127,40 @@ impl State {
         Ok(())
     }
 
+    /// Reads the maps of the exe at `path` again from the process `pid`, and
+    /// adds those the exe does not use yet.
+    // exemaps are ordered by the path and range of their maps, which never
+    // change
+    #[allow(clippy::mutable_key_type)]
+    fn relearn_exe_callback(
+        this: RcCell<Self>,
+        path: impl AsRef<Path>,
+        pid: libc::pid_t,
+        rules: &PathRules,
+        source: &(impl ProcessSource + ?Sized),
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let exe = match this.borrow().exes.get(path) {
+            Some(exe) => Rc::clone(exe),
+            None => return Ok(()),
+        };
+
+        let mut exemaps = BTreeSet::new();
+        proc::get_maps(
+            pid,
+            Some(&mut exemaps),
+            &rules.maps,
+            Rc::clone(&this),
+            source,
+        )?;
+
+        let mut exe = exe.borrow_mut();
+        let added = exe.merge_exemaps(exemaps);
+        exe.maps_dropped = false;
+        log::debug!("Learnt {} new maps of {:?}.", added, path);
+        Ok(())
+    }
+
     /// Adjust states on exes that change state (running/not-running).
     ///
     /// We take an `RcCell<Exe>` instead of a `&mut Exe` to prevent borrow
@@ -111,13 +179,63 @@ impl State {
             });
         exe.borrow_mut().markovs = markovs.collect();
     }
+
+    /// Accounts for a run of `exe` from `start` to `end` that no scan has
+    /// seen, as if it had been noticed starting and then exiting.
+    pub(crate) fn short_lived_callback(
+        &self,
+        exe: &RcCell<Exe>,
+        start: i32,
+        end: i32,
+    ) {
+        let end = end.min(self.time);
+        let start = start.min(end);
+
+        let markovs = std::mem::take(&mut exe.borrow_mut().markovs)
+            .into_iter()
+            .map(|markov| {
+                markov.borrow_mut().short_lived(exe, start, end);
+                markov
+            });
+        exe.borrow_mut().markovs = markovs.collect();
+
+        let mut exe = exe.borrow_mut();
+        exe.time += end - start;
+        exe.change_timestamp = end;
+        exe.dirty = true;
+    }
 }
 
 impl MarkovState {
+    /// Moves the chain through the state where `exe` runs from `start` to
+    /// `end`, and back.
+    fn short_lived(&mut self, exe: &RcCell<Exe>, start: i32, end: i32) {
+        let bit = if self.a.as_ptr() == Rc::as_ptr(exe) {
+            1
+        } else {
+            2
+        };
+        if self.state & bit != 0 {
+            return;
+        }
+
+        // the chain may have changed state after the run began
+        let start = start.max(self.change_timestamp);
+        let end = end.max(start);
+
+        let old_state = self.state;
+        self.transition(old_state | bit, start);
+        if old_state | bit == 3 {
+            self.time += end - start;
+        }
+        self.transition(old_state, end);
+    }
+
     #[inline]
     fn running_inc_time(&mut self, time: i32) {
         if self.state == 3 {
             self.time += time;
+            self.dirty = true;
         }
     }
 }
@@ -127,23 +245,26 @@ impl Exe {
     fn running_inc_time(&mut self, time: i32, state: &State) {
         if self.is_running(state) {
             self.time += time;
+            self.dirty = true;
         }
     }
 }
 
 /// Scan processes and see which exes started running, which are not running
 /// anymore, and what new exes are around.
+///
+/// Exes reported by [`process_event`] since the last scan are taken into
+/// account too.
 pub(crate) fn scan(
     state: &mut State,
-    prefixes: Option<&[impl AsRef<Path>]>,
+    exerules: &Rules,
+    source: &(impl ProcessSource + ?Sized),
 ) -> Result<()> {
-    state.state_changed_exes.clear();
-    state.new_running_exes.clear();
-
     // mark each exe with fresh timestamp
     proc::proc_foreach(
         |pid, exe| state.running_process_callback(pid, exe),
-        prefixes,
+        exerules,
+        source,
     )?;
     state.last_running_timestamp = state.time;
 
@@ -154,20 +275,47 @@ pub(crate) fn scan(
             state.exe_already_running_callback(Rc::clone(exe));
         });
 
-    // update our running exes info
-    state.running_exes = state.new_running_exes.clone();
+    // update our running exes info. An exe that started again after exiting
+    // since the last scan is listed twice.
+    let mut seen = HashSet::new();
+    state.running_exes = std::mem::take(&mut state.new_running_exes)
+        .into_iter()
+        .filter(|exe| seen.insert(Rc::as_ptr(exe)))
+        .collect();
 
     Ok(())
 }
 
+/// Feeds a process event to the model as soon as it arrives, instead of
+/// waiting for the next scan. The `tracker` is updated along the way.
+///
+/// Unless `scanning`, only the `tracker` is updated, as no scan would take
+/// what is fed to the model.
+pub(crate) fn process_event(
+    state: &mut State,
+    event: ProcEvent,
+    tracker: &mut Tracker,
+    exerules: &Rules,
+    source: &(impl ProcessSource + ?Sized),
+    scanning: bool,
+) {
+    // exits are noticed by the next scan, as the exe is not listed anymore
+    if let Some(exe) = tracker.apply(event, source) {
+        if let (ProcEvent::Exec(pid), true) = (event, scanning) {
+            if exerules.accepts(&exe) {
+                state.running_process_callback(pid, &exe);
+            }
+        }
+    }
+}
+
 pub(crate) fn update_model(
     state: RcCell<State>,
-    mapprefix: &[impl AsRef<Path>],
+    rules: &PathRules,
     minsize: u64,
     cycle: u32,
+    source: &(impl ProcessSource + ?Sized),
 ) -> Result<()> {
-    let mut is_error = Ok(());
-
     // register new discovered exes
     let new_exes =
         std::mem::take(&mut state.borrow_mut().new_exes).into_iter();
@@ -176,19 +324,30 @@ pub(crate) fn update_model(
             Rc::clone(&state),
             &path,
             pid as libc::pid_t,
-            mapprefix,
+            rules,
             minsize,
             cycle,
+            source,
         )
-        .unwrap_or_else(|e| {
-            is_error = Err(e);
-            Default::default()
-        });
+        // the process may have exited since it was seen
+        .log_on_err(Level::Debug, format!("Failed to learn {:?}", path))
+        .ok();
     });
 
-    if is_error.is_err() {
-        return is_error;
-    }
+    // read the maps of exes that lost some again
+    let relearn_exes =
+        std::mem::take(&mut state.borrow_mut().relearn_exes).into_iter();
+    relearn_exes.for_each(|(path, pid)| {
+        State::relearn_exe_callback(
+            Rc::clone(&state),
+            &path,
+            pid,
+            rules,
+            source,
+        )
+        .log_on_err(Level::Debug, format!("Failed to relearn {:?}", path))
+        .ok();
+    });
 
     // adjust states for those changing
     let state_changed_exes =
@@ -212,3 +371,258 @@ pub(crate) fn update_model(
     }
     Ok(())
 }
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{
+        common::RcCellNew,
+        connector::EventSource,
+        model::{App, System},
+        proc::FakeSource,
+    };
+
+    const CYCLE: u32 = 20;
+    const MINSIZE: u64 = 5000;
+    const PREFIX: [&str; 1] = ["/usr/"];
+
+    /// Runs a whole cycle just like the event loop does: a scan and a model
+    /// update, each followed by half a cycle.
+    fn run_cycle(state: &RcCell<State>, source: &impl ProcessSource) {
+        run_cycle_with(state, &PathRules::from_prefixes(&PREFIX), source);
+    }
+
+    fn run_cycle_with(
+        state: &RcCell<State>,
+        rules: &PathRules,
+        source: &impl ProcessSource,
+    ) {
+        scan(&mut state.borrow_mut(), &rules.exes, source).unwrap();
+        state.borrow_mut().time += CYCLE as i32 / 2;
+        update_model(Rc::clone(state), rules, MINSIZE, CYCLE, source).unwrap();
+        state.borrow_mut().time += CYCLE as i32 / 2;
+    }
+
+    fn source() -> FakeSource {
+        let mut source = FakeSource::default();
+        source.spawn(
+            10,
+            "/usr/bin/a",
+            &[("/usr/lib/liba.so", 0, 8192), ("/usr/lib/libc.so", 0, 4096)],
+        );
+        source.spawn(
+            11,
+            "/usr/bin/b",
+            &[("/usr/lib/libb.so", 0, 4096), ("/usr/lib/libc.so", 0, 4096)],
+        );
+        source.spawn(12, "/usr/bin/tiny", &[("/usr/lib/libc.so", 0, 10)]);
+        source
+    }
+
+    #[test]
+    fn new_exes_are_registered() {
+        let state = RcCell::new_cell(State::default());
+        run_cycle(&state, &source());
+
+        let state = state.borrow();
+        assert_eq!(state.exes.len(), 2);
+        assert_eq!(state.running_exes.len(), 2);
+        assert!(state.bad_exes.contains_key(Path::new("/usr/bin/tiny")));
+
+        // maps are shared between the exes that use them
+        assert_eq!(state.maps.len(), 3);
+        let a = state.exes[Path::new("/usr/bin/a")].borrow();
+        assert_eq!(a.get_size(), 8192 + 4096);
+        assert_eq!(a.exemaps.len(), 2);
+        assert_eq!(a.markovs.len(), 1);
+    }
+
+    #[test]
+    fn markov_training() {
+        let state = RcCell::new_cell(State::default());
+        let mut source = source();
+
+        run_cycle(&state, &source);
+        run_cycle(&state, &source);
+        source.kill(11);
+        run_cycle(&state, &source);
+
+        let state = state.borrow();
+        let a = state.exes[Path::new("/usr/bin/a")].borrow();
+        let b = state.exes[Path::new("/usr/bin/b")].borrow();
+        assert!(a.is_running(&state));
+        assert!(!b.is_running(&state));
+        assert_eq!(a.time, 50);
+        assert_eq!(b.time, 30);
+
+        let markov = a.markovs.iter().next().unwrap().borrow();
+        // both were running, then only `a` is.
+        assert_eq!(markov.state, 1);
+        assert_eq!(markov.time, 30);
+        assert_eq!(markov.weight[3][3], 1);
+        assert_eq!(markov.weight[3][1], 1);
+        assert_eq!(*markov.time_to_leave[3], 40.0);
+    }
+
+    #[test]
+    fn process_events_are_fed_immediately() {
+        let state = RcCell::new_cell(State::default());
+        let tracker = Rc::new(RefCell::new(Tracker::default()));
+        let mut events = EventSource {
+            tracker: Rc::clone(&tracker),
+            inner: source(),
+        };
+        tracker.borrow_mut().sync(&events.inner).unwrap();
+        run_cycle(&state, &events);
+
+        // `b` exits, which the next scan notices
+        events.inner.kill(11);
+        process_event(
+            &mut state.borrow_mut(),
+            ProcEvent::Exit(11),
+            &mut tracker.borrow_mut(),
+            &Rules::from_prefixes(&PREFIX),
+            &events.inner,
+            true,
+        );
+        run_cycle(&state, &events);
+
+        let b = Rc::clone(&state.borrow().exes[Path::new("/usr/bin/b")]);
+        assert!(!b.borrow().is_running(&state.borrow()));
+
+        // `b` starts again, and is running before any scan
+        events.inner.spawn(13, "/usr/bin/b", &[]);
+        process_event(
+            &mut state.borrow_mut(),
+            ProcEvent::Exec(13),
+            &mut tracker.borrow_mut(),
+            &Rules::from_prefixes(&PREFIX),
+            &events.inner,
+            true,
+        );
+        assert!(b.borrow().is_running(&state.borrow()));
+        assert!(state
+            .borrow()
+            .new_running_exes
+            .iter()
+            .any(|exe| Rc::ptr_eq(exe, &b)));
+
+        // the scan sees what the events reported
+        assert!(events
+            .processes()
+            .unwrap()
+            .contains(&(13, "/usr/bin/b".into())));
+        run_cycle(&state, &events);
+        assert!(b.borrow().is_running(&state.borrow()));
+        assert_eq!(state.borrow().running_exes.len(), 2);
+    }
+
+    #[test]
+    fn process_events_are_dropped_unless_scanning() {
+        let state = RcCell::new_cell(State::default());
+        let tracker = Rc::new(RefCell::new(Tracker::default()));
+        let mut events = EventSource {
+            tracker: Rc::clone(&tracker),
+            inner: source(),
+        };
+        tracker.borrow_mut().sync(&events.inner).unwrap();
+        run_cycle(&state, &events);
+        events.inner.kill(11);
+        process_event(
+            &mut state.borrow_mut(),
+            ProcEvent::Exit(11),
+            &mut tracker.borrow_mut(),
+            &Rules::from_prefixes(&PREFIX),
+            &events.inner,
+            true,
+        );
+        run_cycle(&state, &events);
+
+        // `b` starts again while scanning is paused
+        events.inner.spawn(13, "/usr/bin/b", &[]);
+        process_event(
+            &mut state.borrow_mut(),
+            ProcEvent::Exec(13),
+            &mut tracker.borrow_mut(),
+            &Rules::from_prefixes(&PREFIX),
+            &events.inner,
+            false,
+        );
+
+        let state = state.borrow();
+        let b = state.exes[Path::new("/usr/bin/b")].borrow();
+        assert!(!b.is_running(&state));
+        assert!(state.new_running_exes.is_empty());
+        assert!(state.state_changed_exes.is_empty());
+
+        // the tracker still knows the process
+        assert!(events
+            .processes()
+            .unwrap()
+            .contains(&(13, "/usr/bin/b".into())));
+    }
+
+    #[test]
+    fn exes_are_known_by_identity() {
+        // a binary seen at two paths, as from two mount namespaces
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (host, guest) = (dir.join("a"), dir.join("guest-a"));
+        std::fs::write(&host, vec![0; 8192]).unwrap();
+        std::fs::hard_link(&host, &guest).unwrap();
+        let lib = dir.join("liba.so");
+        std::fs::write(&lib, vec![0; 8192]).unwrap();
+        let maps = [(lib.to_str().unwrap(), 0, 8192)];
+
+        let state = RcCell::new_cell(State::default());
+        let rules = PathRules::from_prefixes(&[dir]);
+        let mut source = FakeSource::default();
+        source.spawn(10, host.clone(), &maps);
+        run_cycle_with(&state, &rules, &source);
+        source.kill(10);
+        run_cycle_with(&state, &rules, &source);
+
+        source.spawn(20, guest, &maps);
+        run_cycle_with(&state, &rules, &source);
+
+        let state = state.borrow();
+        assert_eq!(state.exes.len(), 1);
+        assert!(state.exes[&host].borrow().is_running(&state));
+        assert_eq!(state.running_exes.len(), 1);
+    }
+
+    #[test]
+    fn apps_override_the_model() {
+        let system = System {
+            exeprefix: vec!["/usr/".into()],
+            mapprefix: vec!["/usr/".into()],
+            ..Default::default()
+        };
+        let apps = [
+            App {
+                exe: "/usr/bin/tiny".into(),
+                minsize: Some(0),
+                ..Default::default()
+            },
+            App {
+                exe: "basename:b".into(),
+                notrack: true,
+                ..Default::default()
+            },
+        ];
+        let rules = PathRules::compile(&system, &apps).unwrap();
+
+        let state = RcCell::new_cell(State::default());
+        run_cycle_with(&state, &rules, &source());
+
+        let state = state.borrow();
+        assert!(state.exes.contains_key(Path::new("/usr/bin/a")));
+        assert!(!state.exes.contains_key(Path::new("/usr/bin/b")));
+        assert!(state.exes.contains_key(Path::new("/usr/bin/tiny")));
+        assert!(state.bad_exes.is_empty());
+    }
+}
+// 1}}} //
@@ -8,9 +8,13 @@
 
 // use ndarray::{Array1, Array2};
 use crate::{
-    common::{DropperCell, LogResult, RcCell, RcCellNew, WeakCell},
-    proc::{self, MemInfo},
+    common::{FileId, LogResult, RcCell, RcCellNew, WeakCell},
+    devices::Devices,
+    extents::Extents,
+    proc::{self, MemInfo, ProcessSource},
+    rules::Rules,
     schema,
+    stats::{Counters, Stats},
 };
 use anyhow::{Context, Result};
 use clap::crate_version;
@@ -21,7 +25,7 @@ use ordered_float::OrderedFloat;
 use semver::Version;
 use std::{
     cmp::Ordering,
-    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashSet},
     ops::Deref,
     path::{Path, PathBuf},
     rc::Rc,
@@ -69,6 +73,10 @@ pub(crate) mod models {
             update_time: i32,
             time: i32,
             uri: String,
+            dev: Option<i64>,
+            ino: Option<i64>,
+            size: Option<i64>,
+            mtime: Option<i64>,
         },
         "exes",
         NewExe,
@@ -81,6 +89,10 @@ pub(crate) mod models {
             offset: i32,
             length: i64,
             uri: String,
+            dev: Option<i64>,
+            ino: Option<i64>,
+            size: Option<i64>,
+            mtime: Option<i64>,
         },
         "maps",
         NewMap,
@@ -97,6 +109,18 @@ pub(crate) mod models {
         "markovstates",
         NewMarkovState,
     }
+
+    table_creator! {
+        PrefetchStat {
+            day: i64,
+            exe_seq: i32,
+            hits: i32,
+            misses: i32,
+            waste: i32,
+        },
+        "prefetchstats",
+        NewPrefetchStat,
+    }
 } /* models */
 
 /// Represents an vector of `f64` with `N` elements. Since default values for
@@ -259,6 +283,43 @@ pub(crate) struct Map {
         Debug = "ignore"
     )]
     pub(crate) block: i64,
+
+    /// Identity of the mapped file when the map was learnt, if known.
+    #[derivative(
+        PartialEq = "ignore",
+        PartialOrd = "ignore",
+        Ord = "ignore",
+        Debug = "ignore"
+    )]
+    pub(crate) id: Option<FileId>,
+
+    /// Whether the map has not been written to the database yet.
+    #[derivative(
+        PartialEq = "ignore",
+        PartialOrd = "ignore",
+        Ord = "ignore",
+        Debug = "ignore"
+    )]
+    pub(crate) dirty: bool,
+
+    /// Last time a prediction prefetched the map, or found it in the page
+    /// cache already.
+    #[derivative(
+        PartialEq = "ignore",
+        PartialOrd = "ignore",
+        Ord = "ignore",
+        Debug = "ignore"
+    )]
+    pub(crate) prefetched: Option<i32>,
+
+    /// Last time an exe of the map started.
+    #[derivative(
+        PartialEq = "ignore",
+        PartialOrd = "ignore",
+        Ord = "ignore",
+        Debug = "ignore"
+    )]
+    pub(crate) used: Option<i32>,
 }
 
 impl Map {
@@ -281,7 +342,17 @@ impl Map {
                     db_map.length as usize,
                     Rc::downgrade(state),
                 );
-                map.borrow_mut().update_time = db_map.update_time;
+                {
+                    let mut map = map.borrow_mut();
+                    map.update_time = db_map.update_time;
+                    map.seq = db_map.seq;
+                    map.id = FileId::from_columns(
+                        db_map.dev,
+                        db_map.ino,
+                        db_map.size,
+                        db_map.mtime,
+                    );
+                }
 
                 if let Entry::Vacant(e) = map_seqs.entry(db_map.seq) {
                     e.insert(Rc::clone(&map));
@@ -305,33 +376,50 @@ impl Map {
         self.length
     }
 
+    /// Creates a map that is not registered with the `state` yet. Dropping it
+    /// leaves the registry alone, since a map equal to it may be registered:
+    /// the maps no exe uses anymore are unregistered by the janitor.
     pub(crate) fn new(
         path: impl Into<PathBuf>,
         offset: usize,
         length: usize,
         state: WeakCell<State>,
-    ) -> DropperCell<Self> {
-        DropperCell::new(
-            Self {
-                path: path.into(),
-                offset,
-                length,
-                state,
-                update_time: 0,
-                block: -1,
-                lnprob: 0.0.into(),
-                seq: 0,
-            },
-            Some(|v| {
-                let state = &v.borrow().state;
-                if let Some(state) = state.upgrade() {
-                    state.borrow_mut().unregister_map(v);
-                }
-            }),
-        )
+    ) -> RcCell<Self> {
+        Rc::new_cell(Self {
+            path: path.into(),
+            offset,
+            length,
+            state,
+            update_time: 0,
+            block: -1,
+            lnprob: 0.0.into(),
+            seq: 0,
+            id: None,
+            dirty: true,
+            prefetched: None,
+            used: None,
+        })
+    }
+
+    /// Whether the mapped file is still the one the map was learnt from. A
+    /// map whose identity is unknown is assumed to be current as long as the
+    /// file still reaches it. The end of a map is rounded up to a page, so
+    /// it may lie past the end of the file.
+    pub(crate) fn is_current(&self) -> bool {
+        match (self.id, FileId::of(&self.path)) {
+            (_, None) => false,
+            (Some(id), Some(current)) => id == current,
+            (None, Some(current)) => current.size > self.offset as u64,
+        }
+    }
+
+    /// Key of the map in [`State::map_ids`], if its identity is known.
+    fn id_key(&self) -> Option<(u64, u64, usize, usize)> {
+        self.id.map(|id| (id.dev, id.ino, self.offset, self.length))
     }
 
-    /// Writes [`Map`] info to the database.
+    /// Writes [`Map`] info to the database, replacing the rows with the same
+    /// seq.
     pub(crate) fn write_all(
         maps: &[&RcCell<Self>],
         conn: &SqliteConnection,
@@ -341,6 +429,7 @@ impl Map {
 
         for each in maps {
             let each = each.borrow();
+            let (dev, ino, size, mtime) = FileId::to_columns(each.id);
 
             db_maps.push(models::NewMap {
                 seq: each.seq,
@@ -350,10 +439,14 @@ impl Map {
                 uri: filename_to_uri(&each.path)
                     .log_on_err(Level::Error, "Failed to parse filepath")?
                     .to_string(),
+                dev,
+                ino,
+                size,
+                mtime,
             })
         }
 
-        diesel::insert_into(schema::maps::table)
+        diesel::replace_into(schema::maps::table)
             .values(&db_maps)
             .execute(conn)
             .log_on_err(Level::Error, "Failed to insert map into database")?;
@@ -369,7 +462,7 @@ pub(crate) struct ExeMap {
     pub(crate) map: RcCell<Map>,
 
     /// Probability that this map will be used when an exe is running.
-    prob: OrderedFloat<f64>,
+    pub(crate) prob: OrderedFloat<f64>,
 }
 
 impl ExeMap {
@@ -430,9 +523,12 @@ impl ExeMap {
         Ok(())
     }
 
-    /// Add new `map` using `Rc::clone(&map)`.
+    /// Add new `map` using `Rc::clone(&map)`. The map is registered with the
+    /// `state` unless it is already known.
     pub(crate) fn new(map: RcCell<Map>, state: &mut State) -> Result<Self> {
-        state.register_map(Rc::clone(&map))?;
+        if !state.maps.contains(&map) {
+            state.register_map(Rc::clone(&map))?;
+        }
         Ok(Self {
             map,
             prob: 1.0.into(),
@@ -511,7 +607,28 @@ pub(crate) struct Exe {
     pub(crate) lnprob: OrderedFloat<f64>,
 
     /// Unique exe sequence number.
-    seq: i32,
+    pub(crate) seq: i32,
+
+    /// Identity of the executable when the exe was learnt, if known.
+    #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
+    pub(crate) id: Option<FileId>,
+
+    /// Whether the persistent fields changed since the last save.
+    #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
+    pub(crate) dirty: bool,
+
+    /// Whether the set of exemaps changed since the last save.
+    #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
+    exemaps_dirty: bool,
+
+    /// Whether the janitor dropped maps of the exe, so that they are read
+    /// again the next time it starts.
+    #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
+    pub(crate) maps_dropped: bool,
+
+    /// How well prefetching worked for the exe.
+    #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
+    pub(crate) stats: Counters,
 }
 
 // ExeWrapper {{{1 //
@@ -566,6 +683,13 @@ impl Exe {
                     exe.change_timestamp = -1;
                     exe.update_time = db_exe.update_time;
                     exe.time = db_exe.time;
+                    exe.seq = db_exe.seq;
+                    exe.id = FileId::from_columns(
+                        db_exe.dev,
+                        db_exe.ino,
+                        db_exe.size,
+                        db_exe.mtime,
+                    );
                 }
 
                 // this solves our lookup in exemap!
@@ -584,6 +708,54 @@ impl Exe {
     /// Add an exemap state to the set of exemaps.
     pub(crate) fn add_exemap(&mut self, value: ExeMap) {
         self.exemaps.insert(value);
+        self.exemaps_dirty = true;
+    }
+
+    /// Keep only the exemaps for which `keep` returns `true`, and return the
+    /// number of exemaps removed.
+    pub(crate) fn retain_exemaps(
+        &mut self,
+        mut keep: impl FnMut(&ExeMap) -> bool,
+    ) -> usize {
+        let before = self.exemaps.len();
+        let mut size = 0;
+        self.exemaps.retain(|exemap| {
+            let kept = keep(exemap);
+            if kept {
+                size += exemap.map.borrow().get_size();
+            }
+            kept
+        });
+        self.size = size;
+
+        let removed = before - self.exemaps.len();
+        if removed > 0 {
+            self.exemaps_dirty = true;
+        }
+        removed
+    }
+
+    /// Adds the exemaps whose maps the exe does not use yet, and returns the
+    /// number of exemaps added.
+    pub(crate) fn merge_exemaps(
+        &mut self,
+        exemaps: impl IntoIterator<Item = ExeMap>,
+    ) -> usize {
+        let known = self
+            .exemaps
+            .iter()
+            .map(|exemap| Rc::as_ptr(&exemap.map))
+            .collect::<HashSet<_>>();
+
+        let mut added = 0;
+        for exemap in exemaps {
+            if !known.contains(&Rc::as_ptr(&exemap.map)) {
+                exemap.add_map_size(self);
+                self.add_exemap(exemap);
+                added += 1;
+            }
+        }
+        added
     }
 
     /// Add a markov state to the set of markovs.
@@ -597,6 +769,21 @@ impl Exe {
         self.running_timestamp >= state.last_running_timestamp
     }
 
+    /// Whether the executable is still the one the exe was learnt from. An
+    /// exe whose identity is unknown is assumed to be current.
+    pub(crate) fn is_current(&self) -> bool {
+        match (self.id, FileId::of(&self.path)) {
+            (_, None) => false,
+            (Some(id), Some(current)) => id == current,
+            (None, Some(_)) => true,
+        }
+    }
+
+    /// Returns the sum of the length of the maps of the [`Exe`] in bytes.
+    pub(crate) const fn get_size(&self) -> usize {
+        self.size
+    }
+
     pub(crate) fn new(
         path: impl Into<PathBuf>,
         is_running: bool,
@@ -637,10 +824,16 @@ impl Exe {
             lnprob: 0.0.into(),
             seq: 0,
             markovs: Default::default(),
+            id: None,
+            dirty: true,
+            exemaps_dirty: true,
+            maps_dropped: false,
+            stats: Counters::default(),
         })
     }
 
-    /// Write exes data into the database.
+    /// Write exes data into the database, replacing the rows with the same
+    /// seq.
     pub(crate) fn write_all(
         exes: &[&RcCell<Self>],
         conn: &SqliteConnection,
@@ -650,6 +843,7 @@ impl Exe {
 
         for each in exes {
             let each = each.borrow();
+            let (dev, ino, size, mtime) = FileId::to_columns(each.id);
 
             db_exes.push(models::NewExe {
                 seq: each.seq,
@@ -658,10 +852,14 @@ impl Exe {
                 uri: filename_to_uri(&each.path)
                     .log_on_err(Level::Error, "Failed to parse filepath")?
                     .to_string(),
+                dev,
+                ino,
+                size,
+                mtime,
             })
         }
 
-        diesel::insert_into(schema::exes::table)
+        diesel::replace_into(schema::exes::table)
             .values(&db_exes)
             .execute(conn)
             .log_on_err(Level::Error, "Failed to insert exe into database")?;
@@ -729,9 +927,13 @@ pub(crate) struct MarkovState {
     pub(crate) weight: ArrayNxN<4>,
 
     /// The time we entered the current state.
-    change_timestamp: i32,
+    pub(crate) change_timestamp: i32,
 
     pub(crate) cycle: u32,
+
+    /// Whether the persistent fields changed since the last save.
+    #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
+    pub(crate) dirty: bool,
 }
 
 impl MarkovState {
@@ -785,6 +987,7 @@ impl MarkovState {
                     rmp_serde::from_read_ref(&db_markov.weight)?;
 
                 let mut mut_markov = markov_state.borrow_mut();
+                mut_markov.time = db_markov.time;
                 mut_markov.time_to_leave = time_to_leave;
                 mut_markov.weight = weight;
             }
@@ -902,6 +1105,7 @@ impl MarkovState {
             time: 0,
             time_to_leave: Default::default(),
             weight: Default::default(),
+            dirty: true,
         });
 
         if initialize {
@@ -932,19 +1136,32 @@ impl MarkovState {
             return;
         }
 
+        self.transition(new_state as i32, state.time);
+    }
+
+    /// Moves the chain to `new_state` at `time`, learning from the time spent
+    /// in the current state. The time to leave a state is the mean of the
+    /// stays in it, so it is averaged over the number of times the state was
+    /// left, `weight[old][old]`.
+    pub(crate) fn transition(&mut self, new_state: i32, time: i32) {
+        let old_state = self.state as usize;
+        let new_state = new_state as usize;
+
         self.weight[old_state][old_state] += 1;
         // workaround: Reverse the subtraction as a workaround for no
         // `std::ops::Sub<OrderedFloat<T>>` for f64
         self.time_to_leave[old_state] += -(self.time_to_leave[old_state]
-            - (state.time - self.change_timestamp) as f64)
-            / self.weight[old_state][new_state] as f64;
+            - (time - self.change_timestamp) as f64)
+            / self.weight[old_state][old_state] as f64;
 
         self.weight[old_state][new_state] += 1;
         self.state = new_state as i32;
-        self.change_timestamp = state.time;
+        self.change_timestamp = time;
+        self.dirty = true;
     }
 
-    /// Write the markov data to the database.
+    /// Write the markov data to the database, replacing the rows with the
+    /// same exe seqs.
     pub(crate) fn write_all(
         markovs: &[&RcCell<Self>],
         conn: &SqliteConnection,
@@ -978,7 +1195,7 @@ impl MarkovState {
             })
         }
 
-        diesel::insert_into(schema::markovstates::table)
+        diesel::replace_into(schema::markovstates::table)
             .values(&db_markovs)
             .execute(conn)
             .log_on_err(
@@ -1018,6 +1235,14 @@ pub(crate) struct State {
     /// Set of maps used by known executables, indexed by `Map` structure.
     pub(crate) maps: BTreeSet<RcCell<Map>>,
 
+    /// Maps of known identity, indexed by the device and inode of their file
+    /// and by their offset and length.
+    map_ids: BTreeMap<(u64, u64, usize, usize), RcCell<Map>>,
+
+    /// Exes of known identity, indexed by the device and inode of their
+    /// binary.
+    exe_ids: BTreeMap<(u64, u64), RcCell<Exe>>,
+
     // runtime section:
     /// Set of exe structs currently running.
     pub(crate) running_exes: Vec<RcCell<Exe>>,
@@ -1032,6 +1257,12 @@ pub(crate) struct State {
     /// Increasing sequence of unique numbers to assign to exes.
     exe_seq: i32,
 
+    /// Seqs of the maps removed since the last save.
+    removed_maps: BTreeSet<i32>,
+
+    /// Seqs of the exes removed since the last save.
+    removed_exes: BTreeSet<i32>,
+
     /// Last time we checked for process' running.
     pub(crate) last_running_timestamp: i32,
 
@@ -1058,6 +1289,19 @@ pub(crate) struct State {
 
     /// Stores exes we've never seen before
     pub(crate) new_exes: BTreeMap<PathBuf, libc::pid_t>,
+
+    /// Stores exes that started running after the janitor dropped some of
+    /// their maps, see [`Exe::maps_dropped`].
+    pub(crate) relearn_exes: BTreeMap<PathBuf, libc::pid_t>,
+
+    /// How well prefetching worked.
+    pub(crate) stats: Stats,
+
+    /// Where the files of the maps are on disk.
+    pub(crate) extents: Extents,
+
+    /// How the I/O on each device is sorted.
+    pub(crate) devices: Devices,
 }
 
 impl State {
@@ -1112,44 +1356,127 @@ impl State {
         Ok(())
     }
 
+    /// Writes the changes to the state since the last save to the database,
+    /// in a single transaction. Only the dirty rows are written, and the rows
+    /// of removed maps and exes are deleted, so the file always holds one
+    /// consistent snapshot of the model. If anything fails, the database is
+    /// left untouched.
+    ///
+    /// The dirty flags are not cleared here. See [`Self::mark_clean`].
     pub(crate) fn write_state(&self, conn: &SqliteConnection) -> Result<()> {
-        // write my details first. If this fails, it means any further
-        // validation in the future won't be possible, hence it would be
-        // futile.
-        self.write_self(conn)?;
-
-        let mut is_error = Ok(());
-
-        let maps = self.maps.iter().collect::<Vec<_>>();
-        Map::write_all(&maps, conn).unwrap_or_else(|v| is_error = Err(v));
-
-        if is_error.is_ok() {
+        conn.transaction(|| {
+            // write my details first. If this fails, it means any further
+            // validation in the future won't be possible, hence it would be
+            // futile.
+            self.write_self(conn)?;
+
+            self.delete_removed(conn)?;
+
+            let maps = self
+                .maps
+                .iter()
+                .filter(|map| map.borrow().dirty)
+                .collect::<Vec<_>>();
+            Map::write_all(&maps, conn)?;
+
+            // badexes are few and cleared on every save anyway
+            diesel::delete(schema::badexes::table).execute(conn)?;
             let bad_exes_updtimes: Vec<_> = self.bad_exes.iter().collect();
-            ReadWriteBadExe::write_all(&bad_exes_updtimes, conn)
-                .unwrap_or_else(|e| is_error = Err(e));
-        }
+            ReadWriteBadExe::write_all(&bad_exes_updtimes, conn)?;
 
-        if is_error.is_ok() {
             // NOTE: Several things are happening to exes at a time.
-            let exes_to_write = self.exes.values().collect::<Vec<_>>();
-            Exe::write_all(&exes_to_write, conn)
-                .unwrap_or_else(|e| is_error = Err(e));
+            let exes_to_write = self
+                .exes
+                .values()
+                .filter(|exe| exe.borrow().dirty)
+                .collect::<Vec<_>>();
+            Exe::write_all(&exes_to_write, conn)?;
+            self.stats.write_all(conn)?;
 
-            self.exes.values().for_each(|exe| {
-                let exe = exe.borrow();
+            for exe in self.exes.values() {
+                let exe_ref = exe.borrow();
 
                 // `preload_exemap_foreach`
-                let exemaps: Vec<_> = exe.exemaps.iter().collect();
-                ExeMap::write_all(&exemaps, &exe, conn)
-                    .unwrap_or_else(|e| is_error = Err(e));
+                if exe_ref.exemaps_dirty {
+                    diesel::delete(
+                        schema::exemaps::table
+                            .filter(schema::exemaps::seq.eq(exe_ref.seq)),
+                    )
+                    .execute(conn)?;
+                    let exemaps: Vec<_> = exe_ref.exemaps.iter().collect();
+                    ExeMap::write_all(&exemaps, &exe_ref, conn)?;
+                }
 
-                let markovs = exe.markovs.iter().collect::<Vec<_>>();
-                MarkovState::write_all(&markovs, conn)
-                    .unwrap_or_else(|e| is_error = Err(e));
-            });
+                // every markov is shared by its two exes; write it only once
+                // from its `a` side, like `exe_markov_callback` does.
+                let markovs = exe_ref
+                    .markovs
+                    .iter()
+                    .filter(|markov| {
+                        let markov = markov.borrow();
+                        markov.dirty
+                            && markov
+                                .a
+                                .upgrade()
+                                .map_or(false, |a| Rc::ptr_eq(&a, exe))
+                    })
+                    .collect::<Vec<_>>();
+                MarkovState::write_all(&markovs, conn)?;
+            }
+
+            Ok(())
+        })
+        .log_on_err(Level::Error, "Failed to write state, rolled back")
+    }
+
+    /// Deletes the rows of the maps and exes removed since the last save,
+    /// along with the exemaps and markovs referring to them.
+    fn delete_removed(&self, conn: &SqliteConnection) -> Result<()> {
+        use schema::{exemaps, exes, maps, markovstates};
+
+        // SQLite limits the number of bound parameters in a statement
+        for seqs in self.removed_maps.iter().collect::<Vec<_>>().chunks(500) {
+            diesel::delete(maps::table.filter(maps::seq.eq_any(seqs)))
+                .execute(conn)?;
+            diesel::delete(
+                exemaps::table.filter(exemaps::map_seq.eq_any(seqs)),
+            )
+            .execute(conn)?;
+        }
+
+        for seqs in self.removed_exes.iter().collect::<Vec<_>>().chunks(500) {
+            diesel::delete(exes::table.filter(exes::seq.eq_any(seqs)))
+                .execute(conn)?;
+            diesel::delete(exemaps::table.filter(exemaps::seq.eq_any(seqs)))
+                .execute(conn)?;
+            diesel::delete(
+                markovstates::table.filter(
+                    markovstates::a_seq
+                        .eq_any(seqs)
+                        .or(markovstates::b_seq.eq_any(seqs)),
+                ),
+            )
+            .execute(conn)?;
         }
 
-        is_error
+        Ok(())
+    }
+
+    /// Clears the dirty flags of every object and forgets the removed ones,
+    /// once they have been written to the database.
+    pub(crate) fn mark_clean(&mut self) {
+        self.maps
+            .iter()
+            .for_each(|map| map.borrow_mut().dirty = false);
+        self.exes.values().for_each(|exe| {
+            let mut exe = exe.borrow_mut();
+            exe.dirty = false;
+            exe.exemaps_dirty = false;
+        });
+        self.markov_foreach(|markov| markov.dirty = false);
+        self.removed_maps.clear();
+        self.removed_exes.clear();
+        self.stats.mark_clean();
     }
 
     /// Logs various statistics about the state.
@@ -1164,31 +1491,42 @@ impl State {
                 num maps = {}
 
             Runtime state stats:
-                num running exes = {}"},
+                num running exes = {}
+
+            Prefetch stats:
+                {}"},
             self.time,
             self.exes.len(),
             self.bad_exes.len(),
             self.maps.len(),
-            self.running_exes.len()
+            self.running_exes.len(),
+            self.stats.total,
         );
+        for exe in self.exes.values() {
+            let exe = exe.borrow();
+            if exe.stats != Counters::default() {
+                log::debug!("Prefetch stats of {:?}: {}", exe.path, exe.stats);
+            }
+        }
         log::debug!("state dump log done!")
     }
 
     pub(crate) fn load(
         cycle: u32,
-        exeprefix: Option<&[impl AsRef<Path>]>,
+        exerules: &Rules,
         conn: &SqliteConnection,
+        source: &(impl ProcessSource + ?Sized),
     ) -> Result<RcCell<Self>> {
         // creation
         let this = RcCell::new_cell(Self::default());
 
         // TODO: how should the data be processed?
-        Self::read_state(&this, cycle, exeprefix, conn)?;
+        Self::read_state(&this, cycle, exerules, conn, source)?;
 
         // happens at last just before returning
         {
             let mut this = this.borrow_mut();
-            this.memstat.update()?;
+            this.memstat.update(source)?;
             this.memstat_timestamp = this.time;
         }
 
@@ -1230,11 +1568,65 @@ impl State {
         Ok(())
     }
 
+    /// Loads the persistent part of the state from the database without
+    /// looking at the running processes. This is meant for inspecting the
+    /// state file only, as the runtime information is left empty.
+    pub(crate) fn load_offline(
+        cycle: u32,
+        conn: &SqliteConnection,
+    ) -> Result<RcCell<Self>> {
+        let this = RcCell::new_cell(Self::default());
+        Self::read_model(&this, cycle, conn)?;
+        Ok(this)
+    }
+
     /// Read everything from the database and fill the [`State`] info.
     fn read_state(
         this: &RcCell<Self>,
         cycle: u32,
-        exeprefix: Option<&[impl AsRef<Path>]>,
+        exerules: &Rules,
+        conn: &SqliteConnection,
+        source: &(impl ProcessSource + ?Sized),
+    ) -> Result<()> {
+        Self::read_model(this, cycle, conn)?;
+
+        // forget what has been replaced or removed while we were not running
+        this.borrow_mut().janitor();
+
+        proc::proc_foreach(
+            |_, path| {
+                let mut this = this.borrow_mut();
+                let time = this.time;
+                this.set_running_process_callback(path, time)
+            },
+            exerules,
+            source,
+        )?;
+
+        {
+            let mut this = this.borrow_mut();
+            this.last_running_timestamp = this.time;
+        }
+
+        this.borrow().markov_foreach(|markov| {
+            let a = markov.a.upgrade().unwrap();
+            let b = markov.a.upgrade().unwrap();
+
+            // `set_markov_state_callback`
+            markov.state = MarkovState::get_markov_state(
+                &a.borrow(),
+                &b.borrow(),
+                &this.borrow(),
+            );
+        });
+
+        Ok(())
+    }
+
+    /// Read the persistent objects of the model from the database.
+    fn read_model(
+        this: &RcCell<Self>,
+        cycle: u32,
         conn: &SqliteConnection,
     ) -> Result<()> {
         this.borrow_mut().read_self(conn)?;
@@ -1253,6 +1645,11 @@ impl State {
         let exe_seqs = Exe::read_all(conn, &mut this.borrow_mut(), cycle)
             .log_on_err(Level::Error, "Failed to load exes from database")?;
 
+        Stats::read_all(conn, &mut this.borrow_mut(), &exe_seqs).log_on_err(
+            Level::Error,
+            "Failed to load prefetch stats from database",
+        )?;
+
         ExeMap::read_all(conn, &mut this.borrow_mut(), &exe_seqs, &map_seqs).log_on_err(
             Level::Error,
             "Failed to load exes from the database",
@@ -1264,31 +1661,8 @@ impl State {
                 "Failed to load markov states from database",
             )?;
 
-        proc::proc_foreach(
-            |_, path| {
-                let mut this = this.borrow_mut();
-                let time = this.time;
-                this.set_running_process_callback(path, time)
-            },
-            exeprefix,
-        )?;
-
-        {
-            let mut this = this.borrow_mut();
-            this.last_running_timestamp = this.time;
-        }
-
-        this.borrow().markov_foreach(|markov| {
-            let a = markov.a.upgrade().unwrap();
-            let b = markov.a.upgrade().unwrap();
-
-            // `set_markov_state_callback`
-            markov.state = MarkovState::get_markov_state(
-                &a.borrow(),
-                &b.borrow(),
-                &this.borrow(),
-            );
-        });
+        // everything matches the database now
+        this.borrow_mut().mark_clean();
 
         Ok(())
     }
@@ -1335,15 +1709,25 @@ impl State {
             });
         }
         self.exes.insert(exe.borrow().path.clone(), Rc::clone(&exe));
-        self.exe_seq += 1;
-        exe.borrow_mut().seq = self.exe_seq;
+        self.index_exe(&exe);
+
+        // exes loaded from the database keep their seq
+        let seq = exe.borrow().seq;
+        if seq == 0 {
+            self.exe_seq += 1;
+            exe.borrow_mut().seq = self.exe_seq;
+        } else {
+            self.exe_seq = self.exe_seq.max(seq);
+        }
 
         Ok(())
     }
 
     pub(crate) fn save(&mut self, conn: &SqliteConnection) -> Result<()> {
         log::debug!("Begin saving state.");
+        self.janitor();
         self.write_state(conn)?;
+        self.mark_clean();
         self.dirty = false;
         // clean once in a while
         self.bad_exes.clear();
@@ -1358,16 +1742,300 @@ impl State {
         // TODO: We can remove this bit.
         anyhow::ensure!(!self.maps.contains(&map), "Map is already present");
 
-        self.map_seq += 1;
         // updating the sequence is safe. The `seq` field does not contribute
-        // to comparison.
-        map.borrow_mut().seq += self.map_seq;
+        // to comparison. Maps loaded from the database keep their seq.
+        let seq = map.borrow().seq;
+        if seq == 0 {
+            self.map_seq += 1;
+            map.borrow_mut().seq = self.map_seq;
+        } else {
+            self.map_seq = self.map_seq.max(seq);
+        }
+        self.index_map(&map);
         self.maps.insert(map);
         Ok(())
     }
 
-    /// Removes the given [`Map`] from the registry of maps.
+    /// Removes the given [`Map`] from the registry of maps. Its row is
+    /// deleted from the database on the next save.
     pub(crate) fn unregister_map(&mut self, map: &RcCell<Map>) {
-        self.maps.remove(map);
+        if self.maps.remove(map) {
+            self.removed_maps.insert(map.borrow().seq);
+
+            let key = map.borrow().id_key();
+            if let Some(Entry::Occupied(e)) =
+                key.map(|k| self.map_ids.entry(k))
+            {
+                if Rc::ptr_eq(e.get(), map) {
+                    e.remove();
+                }
+            }
+        }
+    }
+
+    /// Makes a registered map of known identity reachable by
+    /// [`Self::map_by_id`]. The first map of an identity is kept.
+    pub(crate) fn index_map(&mut self, map: &RcCell<Map>) {
+        if let Some(key) = map.borrow().id_key() {
+            self.map_ids.entry(key).or_insert_with(|| Rc::clone(map));
+        }
+    }
+
+    /// Returns the registered map of the file with identity `id` at `offset`
+    /// with `length`, whatever path it is known under.
+    pub(crate) fn map_by_id(
+        &self,
+        id: FileId,
+        offset: usize,
+        length: usize,
+    ) -> Option<RcCell<Map>> {
+        self.map_ids
+            .get(&(id.dev, id.ino, offset, length))
+            .filter(|map| map.borrow().id == Some(id))
+            .cloned()
+    }
+
+    /// Makes a registered exe of known identity reachable by
+    /// [`Self::exe_by_id`]. The first exe of an identity is kept.
+    pub(crate) fn index_exe(&mut self, exe: &RcCell<Exe>) {
+        if let Some(id) = exe.borrow().id {
+            self.exe_ids
+                .entry((id.dev, id.ino))
+                .or_insert_with(|| Rc::clone(exe));
+        }
+    }
+
+    /// Returns the registered exe of the binary with identity `id`, whatever
+    /// path it is known under.
+    pub(crate) fn exe_by_id(&self, id: FileId) -> Option<RcCell<Exe>> {
+        self.exe_ids
+            .get(&(id.dev, id.ino))
+            .filter(|exe| exe.borrow().id == Some(id))
+            .cloned()
+    }
+
+    /// Removes the given [`Exe`] from the registry of exes. Its row, along
+    /// with its exemaps and markovs, is deleted from the database on the
+    /// next save.
+    pub(crate) fn unregister_exe(&mut self, exe: &RcCell<Exe>) {
+        if self.exes.remove(&exe.borrow().path).is_none() {
+            return;
+        }
+        self.removed_exes.insert(exe.borrow().seq);
+
+        if let Some(id) = exe.borrow().id {
+            if let Entry::Occupied(e) = self.exe_ids.entry((id.dev, id.ino)) {
+                if Rc::ptr_eq(e.get(), exe) {
+                    e.remove();
+                }
+            }
+        }
+
+        // the markovs with other exes go away along with this one
+        let markovs = std::mem::take(&mut exe.borrow_mut().markovs);
+        for markov in markovs {
+            let other = {
+                let markov = markov.borrow();
+                let a = markov.a.upgrade();
+                if a.as_ref().map_or(false, |a| Rc::ptr_eq(a, exe)) {
+                    markov.b.upgrade()
+                } else {
+                    a
+                }
+            };
+            if let Some(other) = other {
+                other
+                    .borrow_mut()
+                    .markovs
+                    .retain(|m| !Rc::ptr_eq(m, &markov));
+            }
+        }
     }
 }
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database, proc::FakeSource, rules::PathRules, spy};
+
+    const CYCLE: u32 = 20;
+
+    fn count_rows(conn: &SqliteConnection) -> [i64; 4] {
+        [
+            schema::maps::table.count().get_result(conn).unwrap(),
+            schema::exes::table.count().get_result(conn).unwrap(),
+            schema::exemaps::table.count().get_result(conn).unwrap(),
+            schema::markovstates::table
+                .count()
+                .get_result(conn)
+                .unwrap(),
+        ]
+    }
+
+    /// Returns a state that has seen `a` and `b` in `dir` running for two
+    /// cycles. The files are created in `dir`, because saving prunes the
+    /// ones that do not exist.
+    fn trained_state(dir: &Path) -> RcCell<State> {
+        for file in &["a", "b", "liba.so", "libb.so"] {
+            std::fs::write(dir.join(file), vec![0; 8192]).unwrap();
+        }
+
+        let mut source = FakeSource::default();
+        for (pid, exe) in &[(10, "a"), (11, "b")] {
+            let lib = dir.join(format!("lib{}.so", exe));
+            source.spawn(
+                *pid,
+                dir.join(exe),
+                &[(lib.to_str().unwrap(), 0, 8192)],
+            );
+        }
+
+        let rules = PathRules::from_prefixes(&[dir]);
+        let state = RcCell::new_cell(State::default());
+        for _ in 0..2 {
+            spy::scan(&mut state.borrow_mut(), &rules.exes, &source).unwrap();
+            spy::update_model(Rc::clone(&state), &rules, 0, CYCLE, &source)
+                .unwrap();
+            state.borrow_mut().time += CYCLE as i32;
+        }
+        state
+    }
+
+    #[test]
+    fn saving_replaces_previous_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = trained_state(dir.path());
+
+        let conn = database::conn_and_migrate(":memory:").unwrap();
+        state.borrow_mut().save(&conn).unwrap();
+        state.borrow_mut().save(&conn).unwrap();
+
+        // the markov between `a` and `b` is written once
+        assert_eq!(count_rows(&conn), [2, 2, 2, 1]);
+
+        let loaded = State::load_offline(CYCLE, &conn).unwrap();
+        let loaded = loaded.borrow();
+        assert_eq!(loaded.time, state.borrow().time);
+        assert_eq!(loaded.exes.len(), 2);
+        assert_eq!(loaded.maps.len(), 2);
+    }
+
+    #[test]
+    fn only_changes_are_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let conn = database::conn_and_migrate(":memory:").unwrap();
+        trained_state(dir).borrow_mut().save(&conn).unwrap();
+
+        let last_exe_id = || {
+            schema::exes::table
+                .select(diesel::dsl::max(schema::exes::id))
+                .first::<Option<i64>>(&conn)
+                .unwrap()
+        };
+        let before = last_exe_id();
+
+        // nothing changed since loading, so no row is replaced
+        let state = State::load_offline(CYCLE, &conn).unwrap();
+        state.borrow_mut().save(&conn).unwrap();
+        assert_eq!(last_exe_id(), before);
+
+        let b = Rc::clone(&state.borrow().exes[&dir.join("b")]);
+        state.borrow_mut().unregister_exe(&b);
+        state.borrow_mut().save(&conn).unwrap();
+
+        // the map, exemap and markov of `b` are gone
+        assert_eq!(count_rows(&conn), [1, 1, 1, 0]);
+        let a = &state.borrow().exes[&dir.join("a")];
+        assert!(a.borrow().markovs.is_empty());
+    }
+
+    #[test]
+    fn maps_shared_by_exes_are_registered_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        for file in &["a", "b", "libc.so"] {
+            std::fs::write(dir.join(file), vec![0; 8192]).unwrap();
+        }
+        let libc = dir.join("libc.so");
+        let mut source = FakeSource::default();
+        source.spawn(10, dir.join("a"), &[(libc.to_str().unwrap(), 0, 8192)]);
+        source.spawn(11, dir.join("b"), &[(libc.to_str().unwrap(), 0, 8192)]);
+
+        let rules = PathRules::from_prefixes(&[dir]);
+        let state = RcCell::new_cell(State::default());
+        spy::scan(&mut state.borrow_mut(), &rules.exes, &source).unwrap();
+        spy::update_model(Rc::clone(&state), &rules, 0, CYCLE, &source)
+            .unwrap();
+        assert_eq!(state.borrow().maps.len(), 1);
+
+        let conn = database::conn_and_migrate(":memory:").unwrap();
+        state.borrow_mut().save(&conn).unwrap();
+        let loaded = State::load_offline(CYCLE, &conn).unwrap();
+        let loaded = loaded.borrow();
+        assert_eq!(loaded.maps.len(), 1);
+        let map = |exe: &str| {
+            let exe = loaded.exes[&dir.join(exe)].borrow();
+            RcCell::clone(&exe.exemaps.iter().next().unwrap().map)
+        };
+        assert!(Rc::ptr_eq(&map("a"), &map("b")));
+    }
+
+    #[test]
+    fn markov_times_are_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = trained_state(dir.path());
+        let markov_time = |state: &State| {
+            let a = state.exes[&dir.path().join("a")].borrow();
+            let markov = a.markovs.iter().next().unwrap().borrow();
+            markov.time
+        };
+        assert!(markov_time(&state.borrow()) > 0);
+
+        let conn = database::conn_and_migrate(":memory:").unwrap();
+        state.borrow_mut().save(&conn).unwrap();
+        let loaded = State::load_offline(CYCLE, &conn).unwrap();
+        assert_eq!(
+            markov_time(&loaded.borrow()),
+            markov_time(&state.borrow())
+        );
+    }
+
+    #[test]
+    fn times_to_leave_are_running_means() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = trained_state(dir.path());
+        let state = state.borrow();
+        let a = state.exes[&dir.path().join("a")].borrow();
+        let mut markov = a.markovs.iter().next().unwrap().borrow_mut();
+        markov.state = 0;
+        markov.change_timestamp = 0;
+        markov.time_to_leave = Default::default();
+        markov.weight = Default::default();
+
+        // state 0 is left after 10, then after 30 more.
+        markov.transition(1, 10);
+        assert_eq!(*markov.time_to_leave[0], 10.0);
+        markov.transition(0, 20);
+        markov.transition(1, 50);
+        assert_eq!(*markov.time_to_leave[0], 20.0);
+        assert_eq!(markov.weight[0][0], 2);
+        assert_eq!(markov.weight[0][1], 2);
+    }
+
+    #[test]
+    fn dropping_a_map_keeps_the_registered_one() {
+        let state = RcCell::new_cell(State::default());
+        let map = Map::new("/usr/lib/libc.so", 0, 4096, Rc::downgrade(&state));
+        state.borrow_mut().register_map(Rc::clone(&map)).unwrap();
+
+        // a map seen again while scanning, before it is looked up
+        drop(Map::new("/usr/lib/libc.so", 0, 4096, Rc::downgrade(&state)));
+        assert!(state.borrow().maps.contains(&map));
+
+        state.borrow_mut().unregister_map(&map);
+        assert!(state.borrow().maps.is_empty());
+    }
+}
+// 1}}} //
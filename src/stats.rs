@@ -0,0 +1,350 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Measurement of how well prefetching works.
+//!
+//! A map is covered by a prediction that prefetched it, or found it in the
+//! page cache already. The backends that make their requests in the
+//! background drop those still waiting at the next prediction, so the maps
+//! given to them are only covered once a prediction finds them in the page
+//! cache. When an exe starts, each of its maps counts as:
+//!
+//! - a hit, if it was covered since the exe last ran,
+//! - a miss, if it was not covered, so that the exe had to read it cold. The
+//!   maps of the exes that were running at the last prediction are not
+//!   counted, since they were in memory anyway.
+//!
+//! By the time a scan notices an exe, it has faulted its maps in, so whether
+//! they were still in the page cache when it started cannot be told. Hits are
+//! thus told from the time of the covering alone, and a map evicted between
+//! the covering and the start counts as a hit too. Such maps are caught when
+//! a later prediction finds them evicted before any of their exes started,
+//! and then count as waste for each of their exes.
+//!
+//! The counts are kept for each exe and for all of them together, and are
+//! saved by day so that trends can be followed.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use diesel::prelude::*;
+
+use crate::{
+    common::RcCell,
+    schema,
+    state::{models, Exe, Map, State},
+};
+
+/// Counts of the maps of the exes that started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Counters {
+    pub(crate) hits: i32,
+    pub(crate) misses: i32,
+    pub(crate) waste: i32,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Self) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.waste += other.waste;
+    }
+
+    /// Percentage of the maps needed by the exes that were prefetched in
+    /// time.
+    pub(crate) fn hit_rate(&self) -> f64 {
+        let needed = self.hits + self.misses;
+        if needed == 0 {
+            return 0.0;
+        }
+        self.hits as f64 * 100.0 / needed as f64
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hits = {}, misses = {}, waste = {} ({:.1}% hit rate)",
+            self.hits,
+            self.misses,
+            self.waste,
+            self.hit_rate()
+        )
+    }
+}
+
+/// The prefetch counts of the [`State`].
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Stats {
+    /// Counts of all the exes together, since the beginning of the
+    /// persistent state.
+    pub(crate) total: Counters,
+
+    /// Counts of the day and of the days not saved yet, keyed by day since
+    /// the epoch and exe seq. Those of all the exes together have a seq of
+    /// 0.
+    days: BTreeMap<(i64, i32), Counters>,
+
+    /// Whether the counts changed since the last save.
+    dirty: bool,
+
+    /// Whether a prediction has covered maps since we started. Until then,
+    /// every map would count as a miss.
+    pub(crate) predicted: bool,
+}
+
+/// Day since the epoch, in UTC.
+fn today() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64 / 86400)
+}
+
+impl Stats {
+    fn count(&mut self, seq: i32, counters: &Counters) {
+        self.count_on(today(), seq, counters);
+    }
+
+    fn count_on(&mut self, day: i64, seq: i32, counters: &Counters) {
+        self.total.add(counters);
+        self.days.entry((day, 0)).or_default().add(counters);
+        self.days.entry((day, seq)).or_default().add(counters);
+        self.dirty = true;
+    }
+
+    /// Writes the counts kept into the database, replacing those written
+    /// before.
+    pub(crate) fn write_all(&self, conn: &SqliteConnection) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let rows = self
+            .days
+            .iter()
+            .map(|(&(day, exe_seq), counters)| models::NewPrefetchStat {
+                day,
+                exe_seq,
+                hits: counters.hits,
+                misses: counters.misses,
+                waste: counters.waste,
+            })
+            .collect::<Vec<_>>();
+
+        diesel::replace_into(schema::prefetchstats::table)
+            .values(rows)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Reads the counts from the database. Those of the exes that are gone
+    /// only count in the total.
+    pub(crate) fn read_all(
+        conn: &SqliteConnection,
+        state: &mut State,
+        exe_seqs: &BTreeMap<i32, RcCell<Exe>>,
+    ) -> Result<()> {
+        let rows =
+            schema::prefetchstats::table.load::<models::PrefetchStat>(conn)?;
+
+        let stats = &mut state.stats;
+        let day = today();
+        for row in rows {
+            let counters = Counters {
+                hits: row.hits,
+                misses: row.misses,
+                waste: row.waste,
+            };
+            if row.exe_seq == 0 {
+                stats.total.add(&counters);
+            } else if let Some(exe) = exe_seqs.get(&row.exe_seq) {
+                exe.borrow_mut().stats.add(&counters);
+            }
+            if row.day == day {
+                stats.days.insert((day, row.exe_seq), counters);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the counts as written to the database. Only those of the day
+    /// are kept, to be added to.
+    pub(crate) fn mark_clean(&mut self) {
+        let day = today();
+        self.days.retain(|&(d, _), _| d >= day);
+        self.dirty = false;
+    }
+}
+
+impl State {
+    /// Counts the maps of `exe`, which is starting after it last ran at
+    /// `since`.
+    pub(crate) fn count_prefetches(&mut self, exe: &RcCell<Exe>, since: i32) {
+        let mut counters = Counters::default();
+        for exemap in &exe.borrow().exemaps {
+            let mut map = exemap.map.borrow_mut();
+            match map.prefetched {
+                Some(time) if time > since => counters.hits += 1,
+                _ if *map.lnprob > 0.0 => (),
+                _ => counters.misses += 1,
+            }
+            map.used = Some(self.time);
+        }
+
+        // until the first prediction, every map would be a miss
+        if !self.stats.predicted {
+            return;
+        }
+
+        let mut exe = exe.borrow_mut();
+        exe.stats.add(&counters);
+        self.stats.count(exe.seq, &counters);
+    }
+
+    /// Counts the `wasted` maps, which were covered by a prediction but have
+    /// been evicted before any of their exes started, as waste for each of
+    /// their exes. They are no longer covered afterwards, so that they are
+    /// not counted again by the next prediction.
+    pub(crate) fn count_waste(&mut self, wasted: &[RcCell<Map>]) {
+        if wasted.is_empty() {
+            return;
+        }
+        for map in wasted {
+            map.borrow_mut().prefetched = None;
+        }
+        let wasted = wasted.iter().map(Rc::as_ptr).collect::<HashSet<_>>();
+
+        for exe in self.exes.values() {
+            let mut exe = exe.borrow_mut();
+            let waste = exe
+                .exemaps
+                .iter()
+                .filter(|exemap| wasted.contains(&Rc::as_ptr(&exemap.map)))
+                .count() as i32;
+            if waste > 0 {
+                let counters = Counters {
+                    waste,
+                    ..Default::default()
+                };
+                exe.stats.add(&counters);
+                self.stats.count(exe.seq, &counters);
+            }
+        }
+    }
+}
+
+// tests {{{1 //
+// exemaps are ordered by the path and range of their maps, which never change
+#[allow(clippy::mutable_key_type)]
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeSet,
+        rc::{Rc, Weak},
+    };
+
+    use super::*;
+    use crate::{
+        database,
+        state::{ExeMap, Map},
+    };
+
+    #[test]
+    fn starts_are_counted_and_saved() {
+        let mut state = State::default();
+        let mut exemaps = BTreeSet::new();
+        for (path, prefetched) in &[("hit.so", Some(10)), ("miss.so", None)] {
+            let map = Map::new(path, 0, 4096, Weak::new());
+            map.borrow_mut().prefetched = *prefetched;
+            exemaps.insert(ExeMap::new(map, &mut state).unwrap());
+        }
+        let exe = Exe::new("app", false, Some(exemaps), &state);
+        state.register_exe(Rc::clone(&exe), false, 20).unwrap();
+
+        // nothing is counted before the first prediction
+        state.count_prefetches(&exe, 0);
+        assert_eq!(state.stats.total, Counters::default());
+
+        state.stats.predicted = true;
+        state.count_prefetches(&exe, 0);
+        // the prefetch happened before the exe last ran
+        state.count_prefetches(&exe, 10);
+
+        let expected = Counters {
+            hits: 1,
+            misses: 3,
+            waste: 0,
+        };
+        assert_eq!(exe.borrow().stats, expected);
+        assert_eq!(state.stats.total, expected);
+        assert_eq!(expected.hit_rate(), 25.0);
+
+        let conn = database::conn_and_migrate(":memory:").unwrap();
+        state.stats.write_all(&conn).unwrap();
+        state.stats.write_all(&conn).unwrap();
+
+        let mut loaded = State::default();
+        let seq = exe.borrow().seq;
+        let exe_seqs = [(seq, Exe::new("app", false, None, &loaded))]
+            .iter()
+            .cloned()
+            .collect();
+        Stats::read_all(&conn, &mut loaded, &exe_seqs).unwrap();
+        assert_eq!(loaded.stats.total, expected);
+        assert_eq!(loaded.stats.days, state.stats.days);
+        assert_eq!(exe_seqs[&seq].borrow().stats, expected);
+    }
+
+    #[test]
+    fn maps_evicted_before_use_are_waste() {
+        let mut state = State::default();
+        state.stats.predicted = true;
+        let mut exemaps = BTreeSet::new();
+        let mut maps = vec![];
+        for path in &["used.so", "unused.so"] {
+            let map = Map::new(path, 0, 4096, Weak::new());
+            map.borrow_mut().prefetched = Some(10);
+            exemaps.insert(ExeMap::new(Rc::clone(&map), &mut state).unwrap());
+            maps.push(map);
+        }
+        let exe = Exe::new("app", false, Some(exemaps), &state);
+        state.register_exe(Rc::clone(&exe), false, 20).unwrap();
+
+        // the exe starts, then one of its maps is covered again
+        state.time = 20;
+        state.count_prefetches(&exe, 0);
+        maps[1].borrow_mut().prefetched = Some(30);
+        assert_eq!(maps[0].borrow().used, Some(20));
+
+        state.count_waste(&maps[1..]);
+        assert_eq!(maps[1].borrow().prefetched, None);
+        assert_eq!(exe.borrow().stats.waste, 1);
+        assert_eq!(state.stats.total.waste, 1);
+        assert_eq!(state.stats.total.hits, 2);
+    }
+
+    #[test]
+    fn past_days_are_kept_until_saved() {
+        let hit = Counters {
+            hits: 1,
+            ..Default::default()
+        };
+        let mut stats = Stats::default();
+        stats.count_on(1, 5, &hit);
+        stats.count_on(2, 5, &hit);
+
+        let conn = database::conn_and_migrate(":memory:").unwrap();
+        stats.write_all(&conn).unwrap();
+        stats.mark_clean();
+        assert!(stats.days.is_empty());
+
+        let mut loaded = State::default();
+        Stats::read_all(&conn, &mut loaded, &BTreeMap::new()).unwrap();
+        assert_eq!(loaded.stats.total.hits, 2);
+    }
+}
+// 1}}} //
@@ -0,0 +1,461 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Rate limits of prefetching.
+//!
+//! The bytes and the requests are each limited by a token bucket, that fills
+//! at the configured rate and holds up to a second worth of tokens. A request
+//! may take more tokens than the bucket holds, leaving it in debt, and the
+//! requests after it wait for the debt to be paid back. That way, a request
+//! larger than the bucket still goes through, and the average rate is kept.
+//!
+//! Waiting for the limits would block the event loop, so the requests are
+//! handed to a [`Pacer`], whose thread makes them and reports back through a
+//! channel of the event loop. The io_uring backend paces itself instead.
+
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use calloop::{
+    channel::{self, Sender},
+    LoopHandle,
+};
+use log::Level;
+
+use crate::{
+    common::LogResult,
+    event::SharedData,
+    readahead::{self, Prefetcher, Queue, Request, Workers},
+};
+
+/// A token bucket.
+#[derive(Debug)]
+struct Bucket {
+    /// Tokens added per second, which is also the most the bucket holds.
+    rate: f64,
+
+    /// Tokens in the bucket. Negative if it is in debt.
+    tokens: f64,
+
+    /// When the bucket was last filled.
+    last: Instant,
+}
+
+impl Bucket {
+    /// A full bucket.
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    /// Returns how long it takes from `now` for the bucket to be out of
+    /// debt.
+    fn wait(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// How much prefetching has been slowed down, since the daemon started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Counts {
+    /// Number of requests that went through the limits.
+    pub(crate) requests: u64,
+
+    /// Number of bytes they asked for.
+    pub(crate) bytes: u64,
+
+    /// Number of requests that had to wait for their turn.
+    pub(crate) throttled: u64,
+
+    /// Total time that they waited.
+    pub(crate) waited: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    bytes: Option<Bucket>,
+    requests: Option<Bucket>,
+    counts: Counts,
+}
+
+/// The limits on the bytes and the requests prefetched per second, shared
+/// by all the prefetching threads.
+#[derive(Debug, Default)]
+pub(crate) struct Limiter(Mutex<Buckets>);
+
+impl Limiter {
+    /// Limits prefetching to `bytes` and `requests` per second, where 0 means
+    /// no limit.
+    pub(crate) fn new(bytes: u64, requests: u64) -> Self {
+        let limiter = Self::default();
+        limiter.set_limits(bytes, requests);
+        limiter
+    }
+
+    /// Changes the limits. The buckets whose limit did not change are kept.
+    pub(crate) fn set_limits(&self, bytes: u64, requests: u64) {
+        let now = Instant::now();
+        let update = |bucket: &mut Option<Bucket>, rate: u64| {
+            if bucket.as_ref().map_or(0, |bucket| bucket.rate as u64) != rate {
+                *bucket = (rate != 0).then(|| Bucket::new(rate, now));
+            }
+        };
+
+        let mut buckets = self.0.lock().unwrap();
+        update(&mut buckets.bytes, bytes);
+        update(&mut buckets.requests, requests);
+    }
+
+    /// Takes the tokens of a request of `bytes` if the limits allow it right
+    /// now, or returns how long to wait before trying again.
+    pub(crate) fn try_acquire(&self, bytes: u64) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.0.lock().unwrap();
+        let Buckets {
+            bytes: bytes_bucket,
+            requests: requests_bucket,
+            ..
+        } = &mut *buckets;
+
+        let wait = [bytes_bucket.as_mut(), requests_bucket.as_mut()]
+            .iter_mut()
+            .flatten()
+            .map(|bucket| bucket.wait(now))
+            .max()
+            .unwrap_or_default();
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+
+        if let Some(bucket) = bytes_bucket {
+            bucket.tokens -= bytes as f64;
+        }
+        if let Some(bucket) = requests_bucket {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    /// Waits until the limits allow a request of `bytes`, and takes its
+    /// tokens.
+    pub(crate) fn acquire(&self, bytes: u64) {
+        let start = Instant::now();
+        let mut throttled = false;
+        while let Err(wait) = self.try_acquire(bytes) {
+            throttled = true;
+            thread::sleep(wait);
+        }
+        self.record(bytes, throttled.then(|| start.elapsed()));
+    }
+
+    /// Counts a request of `bytes` that went through, after having `waited`
+    /// if it was throttled.
+    pub(crate) fn record(&self, bytes: u64, waited: Option<Duration>) {
+        let counts = &mut self.0.lock().unwrap().counts;
+        counts.requests += 1;
+        counts.bytes += bytes;
+        if let Some(waited) = waited {
+            counts.throttled += 1;
+            counts.waited += waited;
+        }
+    }
+
+    pub(crate) fn counts(&self) -> Counts {
+        self.0.lock().unwrap().counts
+    }
+}
+
+/// A [`Prefetcher`] whose requests wait for the [`Limiter`] to allow them.
+/// It blocks, so it is only used on the thread of a [`Pacer`].
+struct Limited<'a> {
+    inner: &'a dyn Prefetcher,
+    limiter: &'a Limiter,
+}
+
+impl Prefetcher for Limited<'_> {
+    fn prefetch(&self, request: &Request) -> Result<()> {
+        self.limiter.acquire(request.length.max(0) as u64);
+        self.inner.prefetch(request)
+    }
+
+    fn prefetch_remote(&self, request: &Request) -> Result<()> {
+        self.limiter.acquire(request.length.max(0) as u64);
+        self.inner.prefetch_remote(request)
+    }
+}
+
+/// The requests given to a [`Pacer`] at once.
+struct Batch {
+    queues: Vec<Queue>,
+    processes: u32,
+    prefetcher: Arc<dyn Prefetcher>,
+}
+
+#[derive(Default)]
+struct Next {
+    /// The batch to make next, if any.
+    batch: Option<Batch>,
+
+    /// Number of batches given so far.
+    given: u64,
+}
+
+/// Makes the requests given to it on a thread of its own, at the pace the
+/// [`Limiter`] allows, and sends the number of them that succeeded back to
+/// the event loop. A batch replaces the requests of the one before it that
+/// are not made yet, since they were decided on an older prediction.
+#[derive(Clone)]
+pub(crate) struct Pacer {
+    next: Arc<(Mutex<Next>, Condvar)>,
+}
+
+impl Pacer {
+    /// Starts the thread of the pacer, which sends the outcome of each batch
+    /// to `done`, and stops once the receiving end is gone.
+    pub(crate) fn new(
+        limiter: Arc<Limiter>,
+        done: Sender<i32>,
+    ) -> Result<Self> {
+        let pacer = Self {
+            next: Arc::default(),
+        };
+        let next = Arc::clone(&pacer.next);
+
+        thread::Builder::new().name("rustload-pacer".into()).spawn(
+            move || {
+                let (lock, cvar) = &*next;
+                let mut workers = Workers::default();
+                loop {
+                    let mut guard = lock.lock().unwrap();
+                    let batch = loop {
+                        match guard.batch.take() {
+                            Some(batch) => break batch,
+                            None => guard = cvar.wait(guard).unwrap(),
+                        }
+                    };
+                    let number = guard.given;
+                    drop(guard);
+
+                    let limited = Limited {
+                        inner: &*batch.prefetcher,
+                        limiter: &limiter,
+                    };
+                    // a newer batch takes over
+                    let stop = || lock.lock().unwrap().given != number;
+                    let processed = readahead::schedule_until(
+                        batch.queues,
+                        batch.processes,
+                        &limited,
+                        &mut workers,
+                        &stop,
+                    )
+                    .log_on_err(Level::Warn, "Failed to prefetch")
+                    .unwrap_or(0);
+
+                    // the event loop is gone
+                    if done.send(processed).is_err() {
+                        break;
+                    }
+                }
+            },
+        )?;
+
+        Ok(pacer)
+    }
+
+    /// Makes the requests of `batch` in place of those still waiting.
+    fn give(&self, batch: Batch) {
+        let (lock, cvar) = &*self.next;
+        let mut next = lock.lock().unwrap();
+        next.batch = Some(batch);
+        next.given += 1;
+        cvar.notify_one();
+    }
+
+    /// Drops the requests that are not made yet.
+    pub(crate) fn finish(&self) {
+        let mut next = self.next.0.lock().unwrap();
+        next.batch = None;
+        next.given += 1;
+    }
+}
+
+/// A [`Prefetcher`] whose requests are made by a [`Pacer`], in the
+/// background.
+pub(crate) struct Paced {
+    pub(crate) inner: Arc<dyn Prefetcher>,
+    pub(crate) pacer: Pacer,
+}
+
+impl Prefetcher for Paced {
+    fn prefetch(&self, request: &Request) -> Result<()> {
+        let queue = Queue {
+            requests: vec![request.clone()],
+            ..Default::default()
+        };
+        self.prefetch_all(vec![queue], 0)?;
+        Ok(())
+    }
+
+    /// Gives the requests of all the `queues` to the pacer, in place of
+    /// those still waiting, and returns at once.
+    fn prefetch_all(&self, queues: Vec<Queue>, processes: u32) -> Result<i32> {
+        let given = queues
+            .iter()
+            .map(|queue| queue.requests.len())
+            .sum::<usize>();
+        self.pacer.give(Batch {
+            queues,
+            processes,
+            prefetcher: Arc::clone(&self.inner),
+        });
+        Ok(given as i32)
+    }
+
+    fn completes_later(&self) -> bool {
+        true
+    }
+}
+
+/// Starts a [`Pacer`], whose outcomes are logged by the event loop.
+pub(crate) fn listen(
+    handle: &LoopHandle<SharedData>,
+    limiter: Arc<Limiter>,
+) -> Result<Pacer> {
+    let (sender, channel) = channel::channel();
+    handle
+        .insert_source(channel, |event, _, _| {
+            if let channel::Event::Msg(processed) = event {
+                log::debug!(
+                    "Readahead {} files in the background.",
+                    processed
+                );
+            }
+        })
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    Pacer::new(limiter, sender)
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use calloop::EventLoop;
+
+    use super::*;
+    use crate::readahead::Recorder;
+
+    fn requests(prefix: &str, count: usize) -> Vec<Request> {
+        (0..count)
+            .map(|i| Request {
+                path: format!("/{}/{}", prefix, i).into(),
+                offset: 0,
+                length: 4096,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn buckets_go_into_debt_and_pay_it_back() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(1000, start);
+
+        assert_eq!(bucket.wait(start), Duration::ZERO);
+        bucket.tokens -= 2500.0;
+        assert_eq!(bucket.wait(start), Duration::from_millis(1500));
+        let later = start + Duration::from_millis(1000);
+        assert_eq!(bucket.wait(later), Duration::from_millis(500));
+
+        // no more than a second worth of tokens is saved up
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.wait(much_later), Duration::ZERO);
+        assert_eq!(bucket.tokens, 1000.0);
+    }
+
+    #[test]
+    fn requests_are_paced_across_threads() {
+        let limiter = Limiter::new(0, 20);
+        let limited = Limited {
+            inner: &Recorder::default(),
+            limiter: &limiter,
+        };
+        let queues = vec![Queue {
+            serial: false,
+            remote: false,
+            requests: requests("old", 30),
+        }];
+
+        let start = Instant::now();
+        assert_eq!(limited.prefetch_all(queues, 4).unwrap(), 30);
+        // a second worth of requests goes at once, then one every 50 ms
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        let counts = limiter.counts();
+        assert_eq!(counts.requests, 30);
+        assert_eq!(counts.bytes, 30 * 4096);
+        assert!(counts.throttled >= 5);
+    }
+
+    #[test]
+    fn pacers_make_the_requests_of_the_latest_batch_in_the_background() {
+        let mut event_loop = EventLoop::<Vec<i32>>::try_new().unwrap();
+        let (sender, channel) = channel::channel();
+        event_loop
+            .handle()
+            .insert_source(channel, |event, _, done| {
+                if let channel::Event::Msg(processed) = event {
+                    done.push(processed);
+                }
+            })
+            .unwrap();
+
+        let recorder = Arc::new(Recorder::default());
+        let paced = Paced {
+            inner: Arc::clone(&recorder) as Arc<dyn Prefetcher>,
+            pacer: Pacer::new(Arc::new(Limiter::new(0, 20)), sender).unwrap(),
+        };
+        let batch = |prefix: &str, count: usize| {
+            vec![Queue {
+                requests: requests(prefix, count),
+                ..Default::default()
+            }]
+        };
+
+        // it would take seconds to make them all
+        let start = Instant::now();
+        assert_eq!(paced.prefetch_all(batch("old", 60), 4).unwrap(), 60);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(paced.prefetch_all(batch("new", 5), 4).unwrap(), 5);
+
+        let mut done = vec![];
+        while done.len() < 2 {
+            event_loop
+                .dispatch(Some(Duration::from_secs(5)), &mut done)
+                .unwrap();
+        }
+        assert!(done[0] < 60);
+        assert_eq!(done[1], 5);
+
+        let recorded = recorder.requests.lock().unwrap();
+        let old = recorded
+            .iter()
+            .filter(|request| request.path.starts_with("/old"));
+        assert_eq!(old.count(), done[0] as usize);
+        assert_eq!(recorded.len(), done[0] as usize + 5);
+    }
+}
+// 1}}} //
@@ -0,0 +1,613 @@
+// vim:set et sw=4 ts=4 tw=79 fdm=marker:
+//! Asynchronous prefetching with io_uring.
+//!
+//! Each request goes through an `openat`, an `fadvise` and a `close`
+//! operation of the ring, each one submitted once the one before it has
+//! completed. The completions are signaled through an eventfd that is a
+//! source of the event loop, so that the loop never waits for I/O. Up to
+//! [`QUEUE_DEPTH`] requests are in flight at once, and the others wait for
+//! their turn, in the order of their [`Queue`]. A serial queue has a single
+//! request in flight at a time. The requests submitted replace those still
+//! waiting, which were decided on an older prediction, so that they do not
+//! pile up from cycle to cycle.
+//!
+//! When the [`Limiter`] holds the waiting requests back, a timeout operation
+//! completes once they may go on, so that the ring paces itself without
+//! blocking the event loop.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ffi::CString,
+    fs::File,
+    io::Read,
+    os::unix::{
+        ffi::OsStrExt,
+        prelude::{AsRawFd, FromRawFd, RawFd},
+    },
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use calloop::{generic::Generic, Interest, LoopHandle, Mode, PostAction};
+use io_uring::{opcode, squeue, types, IoUring, Probe};
+use log::Level;
+use nix::{
+    errno::Errno,
+    sys::eventfd::{eventfd, EfdFlags},
+};
+
+use crate::{
+    common::LogResult,
+    event::SharedData,
+    readahead::{Prefetcher, Queue, Request},
+    throttle::Limiter,
+};
+
+/// Maximum number of requests in flight at once.
+const QUEUE_DEPTH: u32 = 64;
+
+/// User data of the timeout operation that wakes the throttled requests up.
+const TIMER: u64 = u64::MAX;
+
+/// The operation of a request that is in flight.
+enum Stage {
+    /// The file is being opened. The path has to outlive the operation.
+    Opening(CString),
+
+    /// The readahead of the range of the open file is being started.
+    Advising(RawFd),
+
+    /// The file is being closed.
+    Closing,
+}
+
+struct Slot {
+    /// The key of the line the request comes from.
+    line: u64,
+    request: Request,
+    stage: Stage,
+}
+
+/// The requests of a [`Queue`] that wait for a free slot, in order.
+struct Line {
+    serial: bool,
+    requests: VecDeque<Request>,
+
+    /// Number of the requests of the queue in flight.
+    in_flight: usize,
+}
+
+impl Line {
+    /// Whether the next request may be put in flight.
+    fn is_ready(&self) -> bool {
+        !self.requests.is_empty() && (!self.serial || self.in_flight == 0)
+    }
+}
+
+struct Ring {
+    ring: IoUring,
+
+    /// The requests in flight, indexed by the user data of their operation.
+    slots: Vec<Option<Slot>>,
+
+    /// The requests waiting for a free slot, keyed in the order they were
+    /// queued.
+    lines: BTreeMap<u64, Line>,
+
+    /// The key of the next line.
+    next_line: u64,
+
+    limiter: Arc<Limiter>,
+
+    /// Since when the first waiting request has been held back by the
+    /// limiter.
+    throttled_since: Option<Instant>,
+
+    /// The duration of the timeout operation in flight, if any. It has to
+    /// outlive the operation.
+    timer: Option<Box<types::Timespec>>,
+}
+
+/// An io_uring that prefetches the requests given to it. Its completions
+/// are handled by the event loop, or by [`Uring::finish`].
+#[derive(Clone)]
+pub(crate) struct Uring {
+    ring: Arc<Mutex<Ring>>,
+
+    /// Signaled whenever an operation completes.
+    eventfd: Arc<File>,
+}
+
+impl Uring {
+    /// Sets up the ring, whose requests wait for `limiter` to allow them.
+    /// Error is returned if io_uring is not available, or does not support
+    /// the operations we need, like on kernels older than 5.6.
+    pub(crate) fn new(limiter: Arc<Limiter>) -> Result<Self> {
+        // room for an operation of each slot, and for the timer
+        let ring = IoUring::new(QUEUE_DEPTH + 1)
+            .with_context(|| "Failed to set up io_uring")?;
+
+        let mut probe = Probe::new();
+        ring.submitter()
+            .register_probe(&mut probe)
+            .with_context(|| "Failed to probe io_uring")?;
+        for code in [
+            opcode::OpenAt::CODE,
+            opcode::Fadvise::CODE,
+            opcode::Close::CODE,
+            opcode::Timeout::CODE,
+        ] {
+            if !probe.is_supported(code) {
+                anyhow::bail!("io_uring does not support opcode {}", code);
+            }
+        }
+
+        let fd = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)?;
+        // SAFETY: the eventfd was just created, and nothing else owns it.
+        let eventfd = unsafe { File::from_raw_fd(fd) };
+        ring.submitter().register_eventfd(fd)?;
+
+        Ok(Self {
+            ring: Arc::new(Mutex::new(Ring {
+                ring,
+                slots: (0..QUEUE_DEPTH).map(|_| None).collect(),
+                lines: BTreeMap::new(),
+                next_line: 0,
+                limiter,
+                throttled_since: None,
+                timer: None,
+            })),
+            eventfd: Arc::new(eventfd),
+        })
+    }
+
+    /// Queues the requests of `queues` in place of those still waiting, and
+    /// submits as many of them as there is room for.
+    pub(crate) fn submit(&self, queues: Vec<Queue>) -> Result<()> {
+        let mut ring = self.ring.lock().unwrap();
+        for line in ring.lines.values_mut() {
+            line.requests.clear();
+        }
+        for queue in queues {
+            let key = ring.next_line;
+            ring.next_line += 1;
+            ring.lines.insert(
+                key,
+                Line {
+                    serial: queue.serial,
+                    requests: queue.requests.into(),
+                    in_flight: 0,
+                },
+            );
+        }
+        ring.fill()?;
+        Ok(())
+    }
+
+    /// Handles the operations that completed, and submits the ones that
+    /// follow.
+    pub(crate) fn complete(&self) -> Result<()> {
+        // the eventfd counts the completions, which are all reaped below.
+        let mut count = [0; 8];
+        match (&*self.eventfd).read(&mut count) {
+            Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => {
+                return Err(e.into())
+            }
+            _ => (),
+        }
+
+        let mut ring = self.ring.lock().unwrap();
+        ring.reap()?;
+        ring.fill()?;
+        Ok(())
+    }
+
+    /// Number of requests that are in flight or waiting.
+    pub(crate) fn pending(&self) -> usize {
+        let ring = self.ring.lock().unwrap();
+        let waiting = ring.lines.values().map(|line| line.requests.len());
+        ring.slots.iter().flatten().count() + waiting.sum::<usize>()
+    }
+
+    /// Drops the waiting requests, and waits for those in flight to be done,
+    /// without the event loop.
+    pub(crate) fn finish(&self) -> Result<()> {
+        let mut ring = self.ring.lock().unwrap();
+        ring.lines.clear();
+        while ring.slots.iter().any(Option::is_some) {
+            ring.ring.submit_and_wait(1)?;
+            ring.reap()?;
+        }
+        Ok(())
+    }
+}
+
+impl Ring {
+    /// Moves the waiting requests to the free slots as the limiter allows,
+    /// and submits their first operation.
+    fn fill(&mut self) -> Result<()> {
+        let mut submitted = false;
+        while let Some(free) = self.slots.iter().position(Option::is_none) {
+            let (key, line) = match self
+                .lines
+                .iter_mut()
+                .find(|(_, line)| line.is_ready())
+            {
+                Some((&key, line)) => (key, line),
+                None => break,
+            };
+            let request = match line.requests.pop_front() {
+                Some(request) => request,
+                None => break,
+            };
+            let path = match CString::new(request.path.as_os_str().as_bytes())
+            {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+
+            let length = request.length.max(0) as u64;
+            if let Err(wait) = self.limiter.try_acquire(length) {
+                line.requests.push_front(request);
+                self.throttled_since.get_or_insert_with(Instant::now);
+                submitted |= self.arm(wait)?;
+                break;
+            }
+            let waited = self.throttled_since.take().map(|at| at.elapsed());
+            self.limiter.record(length, waited);
+            line.in_flight += 1;
+
+            let entry =
+                opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), path.as_ptr())
+                    .flags(
+                        libc::O_RDONLY
+                            | libc::O_NOCTTY
+                            | libc::O_NOATIME
+                            | libc::O_CLOEXEC,
+                    )
+                    .build();
+            self.slots[free] = Some(Slot {
+                line: key,
+                request,
+                stage: Stage::Opening(path),
+            });
+            self.push(free as u64, entry)?;
+            submitted = true;
+        }
+
+        self.lines
+            .retain(|_, line| !line.requests.is_empty() || line.in_flight > 0);
+        if submitted {
+            self.ring.submit()?;
+        }
+        Ok(())
+    }
+
+    /// Counts a request of the line `key` out of flight.
+    fn release(&mut self, key: u64) {
+        if let Some(line) = self.lines.get_mut(&key) {
+            line.in_flight -= 1;
+        }
+    }
+
+    /// Pushes an operation to the submission queue, with the index of the
+    /// slot of its request, or [`TIMER`], as user data.
+    fn push(&mut self, user_data: u64, entry: squeue::Entry) -> Result<()> {
+        let entry = entry.user_data(user_data);
+        // SAFETY: what the operation points to, the path to open or the
+        // duration of the timer, is kept until it completes. There is an
+        // entry for every slot and for the timer, so the queue cannot be
+        // full.
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| anyhow::anyhow!("io_uring submission queue is full"))
+    }
+
+    /// Pushes a timeout operation that completes after `wait`, unless one is
+    /// in flight already. Returns whether it was pushed.
+    fn arm(&mut self, wait: Duration) -> Result<bool> {
+        if self.timer.is_some() {
+            return Ok(false);
+        }
+        let timespec = Box::new(
+            types::Timespec::new()
+                .sec(wait.as_secs())
+                .nsec(wait.subsec_nanos()),
+        );
+        let entry = opcode::Timeout::new(&*timespec).build();
+        self.timer = Some(timespec);
+        self.push(TIMER, entry)?;
+        Ok(true)
+    }
+
+    /// Handles the operations that completed, by pushing the operations that
+    /// follow them.
+    fn reap(&mut self) -> Result<()> {
+        let completed = self
+            .ring
+            .completion()
+            .map(|entry| (entry.user_data(), entry.result()))
+            .collect::<Vec<_>>();
+
+        for (index, result) in completed {
+            if index == TIMER {
+                // the waiting requests are tried again by the caller
+                self.timer = None;
+                continue;
+            }
+            let index = index as usize;
+            let slot = match self.slots.get_mut(index).and_then(Option::take) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            // the result is the negated errno on failure.
+            let outcome = match result {
+                result if result < 0 => Err(Errno::from_i32(-result)),
+                fd => Ok(fd),
+            }
+            .log_on_err(
+                Level::Warn,
+                format!("Could not readahead file {:?}", slot.request.path),
+            );
+
+            let Slot {
+                line,
+                request,
+                stage,
+            } = slot;
+            let (stage, entry) = match (stage, outcome) {
+                (Stage::Opening(_), Ok(fd)) => (
+                    Stage::Advising(fd),
+                    opcode::Fadvise::new(
+                        types::Fd(fd),
+                        request.length,
+                        libc::POSIX_FADV_WILLNEED,
+                    )
+                    .offset(request.offset)
+                    .build(),
+                ),
+                (Stage::Advising(fd), _) => {
+                    (Stage::Closing, opcode::Close::new(types::Fd(fd)).build())
+                }
+                _ => {
+                    self.release(line);
+                    continue;
+                }
+            };
+            self.slots[index] = Some(Slot {
+                line,
+                request,
+                stage,
+            });
+            self.push(index as u64, entry)?;
+        }
+
+        self.ring.submit()?;
+        Ok(())
+    }
+}
+
+impl AsRawFd for Uring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.eventfd.as_raw_fd()
+    }
+}
+
+impl Prefetcher for Uring {
+    fn prefetch(&self, request: &Request) -> Result<()> {
+        self.submit(vec![Queue {
+            requests: vec![request.clone()],
+            ..Default::default()
+        }])
+    }
+
+    /// Submits the requests of all the `queues`, in order, and returns at
+    /// once. The requests of a serial queue are in flight one at a time.
+    fn prefetch_all(
+        &self,
+        queues: Vec<Queue>,
+        _processes: u32,
+    ) -> Result<i32> {
+        let submitted = queues
+            .iter()
+            .map(|queue| queue.requests.len())
+            .sum::<usize>();
+        self.submit(queues)?;
+        Ok(submitted as i32)
+    }
+
+    fn completes_later(&self) -> bool {
+        true
+    }
+}
+
+/// Sets up the ring, and registers it in the event loop so that its
+/// completions are handled there.
+pub(crate) fn listen(
+    handle: &LoopHandle<SharedData>,
+    limiter: Arc<Limiter>,
+) -> Result<Uring> {
+    let uring = Uring::new(limiter)?;
+
+    handle
+        .insert_source(
+            Generic::new(uring.clone(), Interest::READ, Mode::Level),
+            |_, uring, _| {
+                uring
+                    .complete()
+                    .log_on_err(Level::Error, "Failed to handle io_uring")
+                    .ok();
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    log::info!("Prefetching with io_uring.");
+    Ok(uring)
+}
+
+// tests {{{1 //
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use nix::poll::{poll, PollFd, PollFlags};
+
+    use super::*;
+
+    /// Handles the completions like the event loop does, until every request
+    /// is done.
+    fn drive(uring: &Uring) {
+        while uring.pending() > 0 {
+            let mut fds = [PollFd::new(uring.as_raw_fd(), PollFlags::POLLIN)];
+            assert_eq!(poll(&mut fds, 5000).unwrap(), 1);
+            uring.complete().unwrap();
+        }
+    }
+
+    /// Files of a page each, along with the requests to prefetch them.
+    fn requests(dir: &Path, count: u32) -> Vec<Request> {
+        std::fs::create_dir_all(dir).unwrap();
+        (0..count)
+            .map(|i| {
+                let path = dir.join(i.to_string());
+                std::fs::write(&path, [7; 4096]).unwrap();
+                Request {
+                    path,
+                    offset: 0,
+                    length: 4096,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn requests_are_prefetched_through_the_ring() {
+        let uring = match Uring::new(Arc::default()) {
+            Ok(uring) => uring,
+            // not every kernel lets us, seccomp filters for one.
+            Err(_) => return,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let mut requests = requests(dir, 2 * QUEUE_DEPTH);
+        // a file that is gone fails alone
+        requests.insert(
+            3,
+            Request {
+                path: dir.join("gone"),
+                offset: 0,
+                length: 4096,
+            },
+        );
+
+        let queues = vec![Queue {
+            serial: false,
+            remote: false,
+            requests,
+        }];
+        assert_eq!(
+            uring.prefetch_all(queues, 0).unwrap(),
+            2 * QUEUE_DEPTH as i32 + 1
+        );
+        assert!(uring.pending() > 0);
+        drive(&uring);
+    }
+
+    #[test]
+    fn serial_queues_have_a_request_in_flight_at_a_time() {
+        let uring = match Uring::new(Arc::default()) {
+            Ok(uring) => uring,
+            Err(_) => return,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let queues = vec![
+            Queue {
+                serial: true,
+                remote: false,
+                requests: requests(&dir.path().join("hdd"), 3),
+            },
+            Queue {
+                serial: false,
+                remote: false,
+                requests: requests(&dir.path().join("ssd"), 3),
+            },
+        ];
+        uring.submit(queues).unwrap();
+
+        // the number of requests in flight, and those of the serial queue
+        let in_flight = || {
+            let ring = uring.ring.lock().unwrap();
+            let slots = ring.slots.iter().flatten();
+            let hdd = slots
+                .clone()
+                .filter(|slot| {
+                    slot.request.path.starts_with(dir.path().join("hdd"))
+                })
+                .count();
+            (slots.count(), hdd)
+        };
+        assert_eq!(in_flight(), (4, 1));
+
+        // the next request of the serial queue waits for the one before it
+        while uring.pending() > 0 {
+            let mut fds = [PollFd::new(uring.as_raw_fd(), PollFlags::POLLIN)];
+            assert_eq!(poll(&mut fds, 5000).unwrap(), 1);
+            uring.complete().unwrap();
+            assert!(in_flight().1 <= 1);
+        }
+    }
+
+    #[test]
+    fn rings_pace_themselves() {
+        let limiter = Arc::new(Limiter::new(0, 100));
+        let uring = match Uring::new(Arc::clone(&limiter)) {
+            Ok(uring) => uring,
+            Err(_) => return,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let start = Instant::now();
+        let queues = vec![Queue {
+            requests: requests(dir.path(), 120),
+            ..Default::default()
+        }];
+        uring.submit(queues).unwrap();
+        drive(&uring);
+
+        // a second worth of requests goes at once, then one every 10 ms
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        let counts = limiter.counts();
+        assert_eq!(counts.requests, 120);
+        assert!(counts.throttled > 0);
+    }
+
+    #[test]
+    fn submitted_requests_replace_the_waiting_ones() {
+        let limiter = Arc::new(Limiter::new(0, 10));
+        let uring = match Uring::new(Arc::clone(&limiter)) {
+            Ok(uring) => uring,
+            Err(_) => return,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let batch = |name: &str, count| {
+            vec![Queue {
+                requests: requests(&dir.path().join(name), count),
+                ..Default::default()
+            }]
+        };
+        uring.submit(batch("old", 50)).unwrap();
+        // a second worth of them is in flight, and the others wait
+        let in_flight = limiter.counts().requests as usize;
+        assert!(in_flight < 50);
+        uring.submit(batch("new", 5)).unwrap();
+        assert_eq!(uring.pending(), in_flight + 5);
+
+        drive(&uring);
+        assert_eq!(limiter.counts().requests as usize, in_flight + 5);
+    }
+}
+// 1}}} //